@@ -0,0 +1,68 @@
+//! Streaming responses for actix-web.
+//!
+//! Requires the `with-actix` feature.
+use actix_web::body::{BoxBody, SizedStream};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use postgres::{GenericConnection, Result};
+use postgres::types::Oid;
+
+use stream::{LargeObjectStream, DEFAULT_CHUNK_SIZE};
+
+/// An actix-web `Responder` streaming the contents of a large object.
+///
+/// The response has a `Content-Length` of the object's size and a
+/// `Content-Type` of `application/octet-stream` unless otherwise specified.
+/// The transaction used to read the object is held open until the response
+/// has been fully written or the client disconnects.
+#[derive(Debug)]
+pub struct LargeObjectResponse {
+    stream: LargeObjectStream,
+    content_type: String,
+}
+
+impl LargeObjectResponse {
+    /// Opens the large object with the specified `Oid` for a response,
+    /// reading it in chunks of `DEFAULT_CHUNK_SIZE` bytes.
+    ///
+    /// See `LargeObjectStream::new` for details.
+    pub fn new<C>(conn: C, oid: Oid) -> Result<LargeObjectResponse>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        let stream = LargeObjectStream::new(conn, oid, DEFAULT_CHUNK_SIZE)?;
+        Ok(LargeObjectResponse::from_stream(stream))
+    }
+
+    /// Creates a response from an existing stream.
+    pub fn from_stream(stream: LargeObjectStream) -> LargeObjectResponse {
+        LargeObjectResponse {
+            stream: stream,
+            content_type: "application/octet-stream".to_owned(),
+        }
+    }
+
+    /// Sets the `Content-Type` of the response.
+    ///
+    /// If it is not a valid header value, a 500 response is sent instead.
+    pub fn content_type(mut self, content_type: &str) -> LargeObjectResponse {
+        self.content_type = content_type.to_owned();
+        self
+    }
+}
+
+impl Responder for LargeObjectResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse {
+        let size = self.stream.remaining();
+        let response = HttpResponse::Ok()
+            .insert_header((CONTENT_TYPE, self.content_type))
+            .message_body(SizedStream::new(size, self.stream));
+        match response {
+            Ok(response) => response.map_into_boxed_body(),
+            // the content type is the only part of the response which can be invalid
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        }
+    }
+}
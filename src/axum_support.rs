@@ -0,0 +1,80 @@
+//! HTTP response bodies for axum and hyper.
+//!
+//! Requires the `with-axum` feature.
+use axum::body::Body as AxumBody;
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use futures::Stream;
+use http_body::{Body, Frame, SizeHint};
+use postgres::{GenericConnection, Result};
+use postgres::types::Oid;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use stream::{LargeObjectStream, DEFAULT_CHUNK_SIZE};
+
+/// An HTTP body streaming the contents of a large object.
+///
+/// It implements `http_body::Body`, with an exact size hint so that a
+/// `Content-Length` header can be set, and can be returned directly from
+/// axum handlers.
+#[derive(Debug)]
+pub struct LargeObjectBody {
+    stream: LargeObjectStream,
+}
+
+impl LargeObjectBody {
+    /// Opens the large object with the specified `Oid` as a body, reading it
+    /// in chunks of `DEFAULT_CHUNK_SIZE` bytes.
+    ///
+    /// See `LargeObjectStream::new` for details.
+    pub fn new<C>(conn: C, oid: Oid) -> Result<LargeObjectBody>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        LargeObjectBody::with_chunk_size(conn, oid, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Like `new`, but reads the object in chunks of the specified size.
+    pub fn with_chunk_size<C>(conn: C, oid: Oid, chunk_size: usize) -> Result<LargeObjectBody>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        let stream = LargeObjectStream::new(conn, oid, chunk_size)?;
+        Ok(LargeObjectBody::from_stream(stream))
+    }
+
+    /// Creates a body from an existing stream.
+    pub fn from_stream(stream: LargeObjectStream) -> LargeObjectBody {
+        LargeObjectBody { stream: stream }
+    }
+}
+
+impl Body for LargeObjectBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Option<io::Result<Frame<Bytes>>>> {
+        Pin::new(&mut self.get_mut().stream)
+            .poll_next(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map(Frame::data)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.stream.remaining() == 0
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.stream.remaining())
+    }
+}
+
+impl IntoResponse for LargeObjectBody {
+    fn into_response(self) -> Response {
+        Response::new(AxumBody::new(self))
+    }
+}
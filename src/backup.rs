@@ -0,0 +1,235 @@
+//! Backup and restore of large objects as tar archives.
+//!
+//! Requires the `with-tar` feature.
+//!
+//! Each object is stored in an entry named by its `Oid`, followed by a
+//! manifest in the same format as the one written by
+//! `export::export_to_directory`. Objects are restored with their original
+//! `Oid`s, so references to them remain valid.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use tar::{Archive, Builder, Header};
+
+use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+use export::{read_manifest, ManifestEntry, MANIFEST_FILE};
+use progress::{Progress, ProgressStream};
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Writes the large objects selected by `filter` to a tar archive,
+/// returning the entries of its manifest.
+pub fn backup<W, F>(trans: &Transaction, writer: W, filter: F) -> Result<Vec<ManifestEntry>>
+where
+    W: Write,
+    F: FnMut(Oid) -> bool,
+{
+    backup_with_progress(trans, writer, filter, |_| {})
+}
+
+/// Like `backup`, but reports the total number of object bytes archived so
+/// far to `progress` as the backup proceeds.
+pub fn backup_with_progress<W, F, P>(
+    trans: &Transaction,
+    writer: W,
+    mut filter: F,
+    mut progress: P,
+) -> Result<Vec<ManifestEntry>>
+where
+    W: Write,
+    F: FnMut(Oid) -> bool,
+    P: Progress,
+{
+    let mut total = 0;
+    let mut builder = Builder::new(writer);
+
+    let mut entries = vec![];
+    for oid in trans.list_large_objects()? {
+        if !filter(oid) {
+            continue;
+        }
+
+        let mut lo = trans.open_large_object(oid, Mode::Read)?;
+        let size = lo.size()?;
+        let file_name = oid.to_string();
+
+        let mut header = Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        {
+            let mut data = ProgressStream::new(&mut lo, |n| progress.progress(n), total);
+            builder.append_data(&mut header, &file_name, &mut data)?;
+        }
+        total += size;
+        lo.finish()?;
+
+        entries.push(ManifestEntry {
+            oid: oid,
+            size: size,
+            file_name: file_name,
+        });
+    }
+
+    let mut manifest = vec![];
+    for entry in &entries {
+        writeln!(manifest, "{}\t{}\t{}", entry.oid, entry.size, entry.file_name)?;
+    }
+    let mut header = Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, MANIFEST_FILE, &manifest[..])?;
+
+    builder.into_inner()?;
+    Ok(entries)
+}
+
+/// Restores the large objects selected by `filter` from a tar archive
+/// written by `backup`, returning their manifest entries.
+///
+/// Fails if an object already exists, or if the restored objects do not
+/// match the archive's manifest.
+pub fn restore<R, F>(trans: &Transaction, reader: R, filter: F) -> Result<Vec<ManifestEntry>>
+where
+    R: Read,
+    F: FnMut(Oid) -> bool,
+{
+    restore_with_progress(trans, reader, filter, |_| {})
+}
+
+/// Like `restore`, but reports the total number of object bytes restored so
+/// far to `progress` as the restore proceeds.
+pub fn restore_with_progress<R, F, P>(
+    trans: &Transaction,
+    reader: R,
+    mut filter: F,
+    mut progress: P,
+) -> Result<Vec<ManifestEntry>>
+where
+    R: Read,
+    F: FnMut(Oid) -> bool,
+    P: Progress,
+{
+    let mut total = 0;
+    let mut archive = Archive::new(reader);
+
+    let mut entries = vec![];
+    let mut manifest = None;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let file_name = entry.path()?.to_string_lossy().into_owned();
+
+        if file_name == MANIFEST_FILE {
+            let mut buf = String::new();
+            entry.read_to_string(&mut buf)?;
+            manifest = Some(buf);
+            continue;
+        }
+
+        let oid = file_name
+            .parse::<Oid>()
+            .map_err(|_| invalid_data(format!("unexpected archive entry {:?}", file_name)))?;
+        if !filter(oid) {
+            continue;
+        }
+
+        trans.create_large_object_with_oid(oid)?;
+        let mut lo = trans.open_large_object(oid, Mode::Write)?;
+        let size = {
+            let mut data = ProgressStream::new(&mut entry, |n| progress.progress(n), total);
+            io::copy(&mut data, &mut lo)?
+        };
+        total += size;
+        lo.finish()?;
+
+        entries.push(ManifestEntry {
+            oid: oid,
+            size: size,
+            file_name: file_name,
+        });
+    }
+
+    let manifest = match manifest {
+        Some(manifest) => read_manifest(manifest.as_bytes())?
+            .into_iter()
+            .map(|e| (e.oid, e.size))
+            .collect::<HashMap<_, _>>(),
+        None => return Err(invalid_data("archive has no manifest".to_owned()).into()),
+    };
+    for entry in &entries {
+        if manifest.get(&entry.oid) != Some(&entry.size) {
+            return Err(invalid_data(format!(
+                "object {} does not match the archive's manifest",
+                entry.oid
+            )).into());
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use backup::{backup, backup_with_progress, restore, restore_with_progress};
+
+    #[test]
+    fn test_backup_restore() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.finish().unwrap();
+        let excluded = trans.create_large_object().unwrap();
+
+        let mut archive = vec![];
+        let entries = backup(&trans, &mut archive, |o| o == oid || o == excluded).unwrap();
+        assert_eq!(entries.len(), 2);
+
+        trans.delete_large_object(oid).unwrap();
+        trans.delete_large_object(excluded).unwrap();
+        let entries = restore(&trans, &archive[..], |o| o != excluded).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].oid, oid);
+        assert_eq!(entries[0].size, 14);
+
+        let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello world!!!");
+        assert!(!trans.list_large_objects().unwrap().contains(&excluded));
+    }
+
+    #[test]
+    fn test_backup_restore_progress() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let mut oids = vec![];
+        for data in &[&b"hello"[..], &b"world!!!"[..]] {
+            let oid = trans.create_large_object().unwrap();
+            let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+            lo.write_all(data).unwrap();
+            lo.finish().unwrap();
+            oids.push(oid);
+        }
+
+        let mut archive = vec![];
+        let mut total = 0;
+        backup_with_progress(&trans, &mut archive, |o| oids.contains(&o), |n| total = n).unwrap();
+        assert_eq!(total, 13);
+
+        for &oid in &oids {
+            trans.delete_large_object(oid).unwrap();
+        }
+        let mut total = 0;
+        restore_with_progress(&trans, &archive[..], |_| true, |n| total = n).unwrap();
+        assert_eq!(total, 13);
+    }
+}
@@ -0,0 +1,288 @@
+//! Load balancing of bulk reads across replicas.
+//!
+//! A single server's disks and network link cap how quickly a bulk export
+//! can read objects. A `ReplicaPool` spreads the work across a set of
+//! servers holding copies of the same database, handing objects out to
+//! them round-robin. Servers which fail with a transient error are marked
+//! unhealthy and skipped for a cooldown period, and the objects they were
+//! reading are retried elsewhere.
+use postgres::{Connection, Result};
+use postgres::types::Oid;
+use std::cmp;
+use std::io;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use {LargeObject, LargeObjectTransactionExt, Mode};
+use export::ExportError;
+use retry::is_transient;
+
+/// The state of a server in a `ReplicaPool`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicaStatus {
+    /// The server's connection string.
+    pub target: String,
+    /// The number of objects read from the server.
+    pub reads: usize,
+    /// The number of transient failures seen on the server.
+    pub failures: usize,
+    /// Whether the server is currently considered healthy.
+    pub healthy: bool,
+}
+
+#[derive(Debug)]
+struct Replica {
+    target: String,
+    reads: AtomicUsize,
+    failures: AtomicUsize,
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl Replica {
+    fn is_healthy(&self) -> bool {
+        match *self.unhealthy_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+}
+
+/// A set of servers across which reads are distributed.
+#[derive(Debug)]
+pub struct ReplicaPool {
+    replicas: Vec<Replica>,
+    next: AtomicUsize,
+    cooldown: Duration,
+}
+
+impl ReplicaPool {
+    /// Creates a pool of the servers identified by the specified connection
+    /// strings.
+    pub fn new<I>(targets: I) -> ReplicaPool
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let replicas = targets
+            .into_iter()
+            .map(|target| Replica {
+                target: target.into(),
+                reads: AtomicUsize::new(0),
+                failures: AtomicUsize::new(0),
+                unhealthy_until: Mutex::new(None),
+            })
+            .collect();
+        ReplicaPool {
+            replicas: replicas,
+            next: AtomicUsize::new(0),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+
+    /// Sets how long a server is skipped after a transient failure.
+    ///
+    /// Defaults to 30 seconds.
+    pub fn set_cooldown(&mut self, cooldown: Duration) {
+        self.cooldown = cooldown;
+    }
+
+    /// Returns the state of each server in the pool.
+    pub fn status(&self) -> Vec<ReplicaStatus> {
+        self.replicas
+            .iter()
+            .map(|r| ReplicaStatus {
+                target: r.target.clone(),
+                reads: r.reads.load(Ordering::SeqCst),
+                failures: r.failures.load(Ordering::SeqCst),
+                healthy: r.is_healthy(),
+            })
+            .collect()
+    }
+
+    /// Exports many objects concurrently, using `jobs` worker threads.
+    ///
+    /// Each object is read from the next healthy server in turn, in its own
+    /// transaction, and passed to `emit`. If a server fails with a transient
+    /// error the object is retried on another, so `emit` may be called more
+    /// than once for an object and should discard any output from earlier
+    /// calls. Other failures are collected and returned in ascending `Oid`
+    /// order, as with `export::export_parallel`.
+    ///
+    /// Workers connect to a server by passing its connection string to
+    /// `connect`, which is responsible for any TLS configuration.
+    pub fn export<F, E>(
+        &self,
+        connect: F,
+        oids: &[Oid],
+        jobs: usize,
+        emit: E,
+    ) -> Result<Vec<ExportError>>
+    where
+        F: Fn(&str) -> Result<Connection> + Sync,
+        E: Fn(Oid, &mut LargeObject) -> io::Result<()> + Sync,
+    {
+        if self.replicas.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no replicas provided").into());
+        }
+
+        let queue = Mutex::new(oids.iter());
+        let failures = Mutex::new(vec![]);
+
+        let panicked = thread::scope(|s| {
+            let workers = (0..cmp::max(jobs, 1))
+                .map(|_| s.spawn(|| self.export_worker(&connect, &queue, &failures, &emit)))
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .any(|worker| worker.join().is_err())
+        });
+        if panicked {
+            return Err(io::Error::new(io::ErrorKind::Other, "export thread panicked").into());
+        }
+
+        let mut failures = failures.into_inner().unwrap();
+        failures.sort_by_key(|f: &ExportError| f.oid);
+        Ok(failures)
+    }
+
+    fn export_worker<F, E>(
+        &self,
+        connect: &F,
+        queue: &Mutex<slice::Iter<Oid>>,
+        failures: &Mutex<Vec<ExportError>>,
+        emit: &E,
+    ) where
+        F: Fn(&str) -> Result<Connection>,
+        E: Fn(Oid, &mut LargeObject) -> io::Result<()>,
+    {
+        // each worker lazily opens its own connection to each server
+        let mut conns = self.replicas.iter().map(|_| None).collect::<Vec<_>>();
+        loop {
+            let oid = match queue.lock().unwrap().next() {
+                Some(&oid) => oid,
+                None => return,
+            };
+
+            let mut attempts = 0;
+            loop {
+                let i = self.pick();
+                let r = self.export_one(connect, &mut conns[i], &self.replicas[i], oid, emit);
+                match r {
+                    Ok(()) => {
+                        self.replicas[i].reads.fetch_add(1, Ordering::SeqCst);
+                        break;
+                    }
+                    Err(ref e) if is_transient(e) && attempts + 1 < self.replicas.len() => {
+                        self.mark_unhealthy(&self.replicas[i]);
+                        conns[i] = None;
+                        attempts += 1;
+                    }
+                    Err(e) => {
+                        if is_transient(&e) {
+                            self.mark_unhealthy(&self.replicas[i]);
+                            conns[i] = None;
+                        }
+                        failures.lock().unwrap().push(ExportError { oid: oid, error: e });
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn export_one<F, E>(
+        &self,
+        connect: &F,
+        conn: &mut Option<Connection>,
+        replica: &Replica,
+        oid: Oid,
+        emit: &E,
+    ) -> Result<()>
+    where
+        F: Fn(&str) -> Result<Connection>,
+        E: Fn(Oid, &mut LargeObject) -> io::Result<()>,
+    {
+        if conn.is_none() {
+            *conn = Some(connect(&replica.target)?);
+        }
+        let conn = conn.as_ref().unwrap();
+        let trans = conn.transaction()?;
+        let mut lo = trans.open_large_object(oid, Mode::Read)?;
+        emit(oid, &mut lo)?;
+        lo.finish()?;
+        trans.commit()
+    }
+
+    /// Returns the index of the next healthy server, or simply the next
+    /// server if none are healthy.
+    fn pick(&self) -> usize {
+        let len = self.replicas.len();
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+        (0..len)
+            .map(|i| (start + i) % len)
+            .find(|&i| self.replicas[i].is_healthy())
+            .unwrap_or(start % len)
+    }
+
+    fn mark_unhealthy(&self, replica: &Replica) {
+        replica.failures.fetch_add(1, Ordering::SeqCst);
+        *replica.unhealthy_until.lock().unwrap() = Some(Instant::now() + self.cooldown);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Write};
+    use std::sync::Mutex;
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use balance::ReplicaPool;
+
+    #[test]
+    fn test_replica_pool() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let mut oids = vec![];
+        for i in 0..6 {
+            let oid = conn.create_large_object().unwrap();
+            let trans = conn.transaction().unwrap();
+            let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+            write!(lo, "object {}", i).unwrap();
+            lo.finish().unwrap();
+            trans.commit().unwrap();
+            oids.push(oid);
+        }
+
+        let pool = ReplicaPool::new(vec![
+            "postgres://postgres@localhost",
+            "postgres://postgres@localhost:1",
+            "postgres://postgres@127.0.0.1",
+        ]);
+        let exported = Mutex::new(vec![]);
+        let connect = |target: &str| Connection::connect(target, TlsMode::None);
+        let failures = pool.export(connect, &oids, 2, |oid, lo| {
+            let mut out = String::new();
+            lo.read_to_string(&mut out)?;
+            exported.lock().unwrap().push((oid, out));
+            Ok(())
+        }).unwrap();
+        assert!(failures.is_empty());
+
+        let mut exported = exported.into_inner().unwrap();
+        exported.sort();
+        assert_eq!(exported.len(), 6);
+        for (i, &(oid, ref out)) in exported.iter().enumerate() {
+            assert_eq!(oid, oids[i]);
+            assert_eq!(*out, format!("object {}", i));
+            conn.delete_large_object(oid).unwrap();
+        }
+
+        let status = pool.status();
+        assert!(!status[1].healthy);
+        assert_eq!(status[1].reads, 0);
+        assert_eq!(status[0].reads + status[2].reads, 6);
+    }
+}
@@ -0,0 +1,166 @@
+//! A minimal HTTP server exposing large objects.
+//!
+//! Requires the `with-tiny-http` feature.
+//!
+//! Objects are addressed by `Oid`:
+//!
+//! * `GET /<oid>` returns the object's contents, honoring `Range` headers.
+//! * `PUT /` creates a new object from the request body, returning its `Oid`.
+//! * `PUT /<oid>` replaces the contents of an existing object.
+//! * `DELETE /<oid>` deletes an object.
+//!
+//! Requests are handled one at a time on a single connection.
+extern crate postgres;
+extern crate postgres_large_object;
+extern crate tiny_http;
+
+use postgres::{Connection, TlsMode};
+use postgres::error::UNDEFINED_OBJECT;
+use postgres::types::Oid;
+use postgres_large_object::{LargeObjectExt, LargeObjectTransactionExt, Mode};
+use postgres_large_object::range::RangeReader;
+use postgres_large_object::upload::Upload;
+use std::env;
+use std::io;
+use std::process;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+/// Evaluates a `postgres::Result`, responding to the request with an error
+/// and returning if it failed.
+macro_rules! try_respond {
+    ($request:expr, $e:expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(e) => {
+                let e = postgres::Error::from(e);
+                eprintln!("error handling request: {}", e);
+                return $request.respond(error_response(&e));
+            }
+        }
+    };
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: {} <connection url> [listen address]", args[0]);
+        process::exit(2);
+    }
+    let addr = args.get(2).map(|s| &**s).unwrap_or("127.0.0.1:8080");
+
+    let conn = match Connection::connect(&*args[1], TlsMode::None) {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("error connecting to database: {}", e);
+            process::exit(1);
+        }
+    };
+    let server = match Server::http(addr) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("error listening on {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+
+    for request in server.incoming_requests() {
+        if let Err(e) = handle(&conn, request) {
+            eprintln!("error sending response: {}", e);
+        }
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn text_response(status: u16, body: &str) -> Response<io::Cursor<Vec<u8>>> {
+    Response::from_string(body).with_status_code(StatusCode(status))
+}
+
+fn error_response(e: &postgres::Error) -> Response<io::Cursor<Vec<u8>>> {
+    if e.code() == Some(&UNDEFINED_OBJECT) {
+        text_response(404, "not found\n")
+    } else {
+        text_response(500, "internal server error\n")
+    }
+}
+
+fn handle(conn: &Connection, request: Request) -> io::Result<()> {
+    let oid = {
+        let path = request.url().split('?').next().unwrap_or("").trim_matches('/');
+        if path.is_empty() {
+            None
+        } else {
+            match path.parse::<Oid>() {
+                Ok(oid) => Some(oid),
+                Err(_) => return request.respond(text_response(404, "not found\n")),
+            }
+        }
+    };
+
+    match (request.method().clone(), oid) {
+        (Method::Get, Some(oid)) => get(conn, request, oid),
+        (Method::Put, None) => create(conn, request),
+        (Method::Put, Some(oid)) => replace(conn, request, oid),
+        (Method::Delete, Some(oid)) => delete(conn, request, oid),
+        _ => request.respond(text_response(405, "method not allowed\n")),
+    }
+}
+
+fn get(conn: &Connection, request: Request, oid: Oid) -> io::Result<()> {
+    let trans = try_respond!(request, conn.transaction());
+    let mut lo = try_respond!(request, trans.open_large_object(oid, Mode::Read));
+
+    let range = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Range"))
+        .map(|h| h.value.as_str().to_owned());
+    let reader = try_respond!(request, RangeReader::new(&mut lo, range.as_ref().map(|s| &**s)));
+
+    let mut headers = vec![
+        header("Accept-Ranges", "bytes"),
+        header("Content-Type", "application/octet-stream"),
+    ];
+    if let Some(content_range) = reader.content_range() {
+        headers.push(header("Content-Range", &content_range));
+    }
+    let status = StatusCode(reader.status());
+    let len = reader.content_length() as usize;
+    request.respond(Response::new(status, headers, reader, Some(len), None))
+}
+
+fn create(conn: &Connection, mut request: Request) -> io::Result<()> {
+    let trans = try_respond!(request, conn.transaction());
+    let upload = try_respond!(
+        request,
+        Upload::new().from_reader(&trans, request.as_reader(), |_| {})
+    );
+    try_respond!(request, trans.commit());
+
+    let response = text_response(201, &format!("{}\n", upload.oid))
+        .with_header(header("Location", &format!("/{}", upload.oid)));
+    request.respond(response)
+}
+
+fn replace(conn: &Connection, mut request: Request, oid: Oid) -> io::Result<()> {
+    let trans = try_respond!(request, conn.transaction());
+    {
+        let mut lo = try_respond!(request, trans.open_large_object(oid, Mode::Write));
+        try_respond!(request, lo.truncate(0));
+        try_respond!(request, io::copy(request.as_reader(), &mut lo));
+        try_respond!(request, lo.finish());
+    }
+    try_respond!(request, trans.commit());
+
+    request.respond(Response::empty(204))
+}
+
+fn delete(conn: &Connection, request: Request, oid: Oid) -> io::Result<()> {
+    let trans = try_respond!(request, conn.transaction());
+    try_respond!(request, trans.delete_large_object(oid));
+    try_respond!(request, trans.commit());
+
+    request.respond(Response::empty(204))
+}
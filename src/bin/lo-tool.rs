@@ -0,0 +1,622 @@
+//! A command line tool for managing large objects.
+//!
+//! Requires the `with-clap` feature.
+extern crate clap;
+extern crate indicatif;
+extern crate postgres;
+extern crate postgres_large_object;
+#[macro_use]
+extern crate serde_json;
+
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use postgres::{Connection, TlsMode};
+use postgres::types::Oid;
+use postgres_large_object::{LargeObjectExt, LargeObjectTransactionExt, Mode};
+use postgres_large_object::backup;
+use postgres_large_object::copy::copy_large_object;
+use postgres_large_object::export;
+use postgres_large_object::vacuum;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::vec;
+
+fn main() {
+    let matches = App::new("lo-tool")
+        .about("Manages Postgres large objects")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("database")
+                .short("d")
+                .long("database")
+                .value_name("URL")
+                .env("DATABASE_URL")
+                .help("The database to connect to"),
+        )
+        .subcommand(
+            SubCommand::with_name("put")
+                .about("Uploads a file into a new large object, printing its OID")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .help("The file to upload, or - to read from stdin"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Downloads a large object into a file")
+                .arg(Arg::with_name("OID").required(true))
+                .arg(Arg::with_name("FILE").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Writes the contents of a large object to stdout")
+                .arg(Arg::with_name("OID").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("Lists large objects with their sizes and owners")
+                .arg(json_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Deletes large objects")
+                .arg(Arg::with_name("OID").required(true).multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("vacuum")
+                .about("Finds and deletes large objects not referenced by any oid or lo column")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Only reports the orphaned objects"),
+                )
+                .arg(
+                    Arg::with_name("delete")
+                        .long("delete")
+                        .help("Deletes the orphaned objects"),
+                )
+                .group(
+                    ArgGroup::with_name("action")
+                        .args(&["dry-run", "delete"])
+                        .required(true),
+                )
+                .arg(json_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks that large objects are readable and match a manifest")
+                .arg(
+                    Arg::with_name("oid")
+                        .long("oid")
+                        .value_name("OID")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Verifies the specified objects"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .conflicts_with("oid")
+                        .help("Verifies every large object"),
+                )
+                .arg(
+                    Arg::with_name("manifest")
+                        .long("manifest")
+                        .value_name("FILE")
+                        .help("Compares objects against an export manifest, verifying its objects by default"),
+                )
+                .group(
+                    ArgGroup::with_name("objects")
+                        .args(&["oid", "all", "manifest"])
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(json_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Writes large objects to a tar archive")
+                .arg(Arg::with_name("ARCHIVE").required(true))
+                .args(&filter_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restores large objects from a tar archive written by backup")
+                .arg(Arg::with_name("ARCHIVE").required(true))
+                .args(&filter_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("cp")
+                .about("Copies large objects between databases, preserving their OIDs")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("URL")
+                        .required(true)
+                        .help("The database to copy from"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("URL")
+                        .required(true)
+                        .help("The database to copy to"),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .value_name("N")
+                        .default_value("1")
+                        .help("The number of objects to copy in parallel"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .conflicts_with("OID")
+                        .help("Copies every large object"),
+                )
+                .arg(
+                    Arg::with_name("OID")
+                        .multiple(true)
+                        .required_unless("all"),
+                ),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("cp", Some(sub)) => cp(sub),
+        (name, Some(sub)) => connect(matches.value_of("database")).and_then(|conn| match name {
+            "put" => put(&conn, sub),
+            "get" => get(&conn, sub),
+            "cat" => cat(&conn, sub),
+            "ls" => ls(&conn, sub),
+            "rm" => rm(&conn, sub),
+            "vacuum" => vacuum(&conn, sub),
+            "verify" => verify(&conn, sub),
+            "backup" => backup(&conn, sub),
+            "restore" => restore(&conn, sub),
+            _ => unreachable!(),
+        }),
+        _ => unreachable!(),
+    };
+
+    if let Err(e) = result {
+        fail(&e);
+    }
+}
+
+fn fail(e: &postgres::Error) -> ! {
+    eprintln!("error: {}", e);
+    process::exit(1);
+}
+
+fn json_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("json")
+        .long("json")
+        .help("Prints a JSON record for each object, one per line")
+}
+
+fn filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("include")
+            .long("include")
+            .value_name("OID")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only includes the specified objects"),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .value_name("OID")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Excludes the specified objects"),
+    ]
+}
+
+/// Returns a filter selecting the objects allowed by `--include` and
+/// `--exclude`.
+fn oid_filter(matches: &ArgMatches) -> postgres::Result<Box<dyn Fn(Oid) -> bool>> {
+    let parse = |name: &str| -> postgres::Result<Option<HashSet<Oid>>> {
+        match matches.values_of(name) {
+            Some(values) => values.map(parse_oid).collect::<postgres::Result<_>>().map(Some),
+            None => Ok(None),
+        }
+    };
+    let include = parse("include")?;
+    let exclude = parse("exclude")?.unwrap_or_else(HashSet::new);
+
+    Ok(Box::new(move |oid| {
+        include.as_ref().map_or(true, |i| i.contains(&oid)) && !exclude.contains(&oid)
+    }))
+}
+
+/// Returns a progress bar for a transfer of `len` bytes, or a spinner if the
+/// length is unknown.
+///
+/// Progress is drawn to stderr, and hidden if it is not a terminal.
+fn bytes_progress(len: Option<u64>) -> ProgressBar {
+    match len {
+        Some(len) => {
+            let progress = ProgressBar::new(len);
+            progress.set_style(
+                ProgressStyle::with_template(
+                    "{bar:40} {bytes}/{total_bytes} {bytes_per_sec} ETA {eta}",
+                ).unwrap(),
+            );
+            progress
+        }
+        None => {
+            let progress = ProgressBar::new_spinner();
+            progress.set_style(
+                ProgressStyle::with_template("{spinner} {bytes} {bytes_per_sec}").unwrap(),
+            );
+            progress
+        }
+    }
+}
+
+/// Returns a progress bar for an operation on `len` objects, or a spinner if
+/// the count is unknown.
+fn object_progress(len: Option<u64>) -> ProgressBar {
+    match len {
+        Some(len) => {
+            let progress = ProgressBar::new(len);
+            progress.set_style(
+                ProgressStyle::with_template("{bar:40} {pos}/{len} objects ETA {eta} {msg}").unwrap(),
+            );
+            progress
+        }
+        None => {
+            let progress = ProgressBar::new_spinner();
+            progress.set_style(ProgressStyle::with_template("{spinner} {pos} objects {msg}").unwrap());
+            progress
+        }
+    }
+}
+
+fn invalid_input(msg: String) -> postgres::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg).into()
+}
+
+fn connect(url: Option<&str>) -> postgres::Result<Connection> {
+    match url {
+        Some(url) => Connection::connect(url, TlsMode::None),
+        None => Err(invalid_input(
+            "no database specified; use --database or DATABASE_URL".to_owned(),
+        )),
+    }
+}
+
+fn parse_oid(s: &str) -> postgres::Result<Oid> {
+    s.parse().map_err(|_| invalid_input(format!("invalid OID `{}`", s)))
+}
+
+fn put(conn: &Connection, matches: &ArgMatches) -> postgres::Result<()> {
+    let (file, len): (Box<dyn Read>, _) = match matches.value_of("FILE").unwrap() {
+        "-" => (Box::new(io::stdin()), None),
+        path => {
+            let file = File::open(path)?;
+            let len = file.metadata()?.len();
+            (Box::new(file), Some(len))
+        }
+    };
+    let progress = bytes_progress(len);
+
+    let trans = conn.transaction()?;
+    let oid = trans.create_large_object()?;
+    {
+        let mut lo = trans.open_large_object(oid, Mode::Write)?;
+        io::copy(&mut progress.wrap_read(file), &mut lo)?;
+        lo.finish()?;
+    }
+    trans.commit()?;
+    progress.finish_and_clear();
+
+    println!("{}", oid);
+    Ok(())
+}
+
+fn get(conn: &Connection, matches: &ArgMatches) -> postgres::Result<()> {
+    let oid = parse_oid(matches.value_of("OID").unwrap())?;
+    let mut file = File::create(matches.value_of("FILE").unwrap())?;
+
+    let trans = conn.transaction()?;
+    let mut lo = trans.open_large_object(oid, Mode::Read)?;
+    let progress = bytes_progress(Some(lo.size()?));
+    io::copy(&mut lo, &mut progress.wrap_write(&mut file))?;
+    progress.finish_and_clear();
+    Ok(())
+}
+
+fn cat(conn: &Connection, matches: &ArgMatches) -> postgres::Result<()> {
+    let oid = parse_oid(matches.value_of("OID").unwrap())?;
+
+    let trans = conn.transaction()?;
+    let mut lo = trans.open_large_object(oid, Mode::Read)?;
+    let progress = bytes_progress(Some(lo.size()?));
+    let stdout = io::stdout();
+    let result = io::copy(&mut lo, &mut progress.wrap_write(stdout.lock()));
+    progress.finish_and_clear();
+    match result {
+        Ok(_) => Ok(()),
+        // the reader went away, as with `lo-tool cat 1234 | head`
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn ls(conn: &Connection, matches: &ArgMatches) -> postgres::Result<()> {
+    let json = matches.is_present("json");
+
+    let trans = conn.transaction()?;
+    let rows = trans.query(
+        "SELECT oid, pg_catalog.pg_get_userbyid(lomowner)
+         FROM pg_catalog.pg_largeobject_metadata
+         ORDER BY oid",
+        &[],
+    )?;
+
+    for row in &rows {
+        let oid: Oid = row.get(0);
+        let owner: String = row.get(1);
+        let size = trans.open_large_object(oid, Mode::Read)?.size()?;
+        if json {
+            println!("{}", json!({ "oid": oid, "size": size, "owner": owner }));
+        } else {
+            println!("{}\t{}\t{}", oid, size, owner);
+        }
+    }
+    Ok(())
+}
+
+fn rm(conn: &Connection, matches: &ArgMatches) -> postgres::Result<()> {
+    let trans = conn.transaction()?;
+    for s in matches.values_of("OID").unwrap() {
+        trans.delete_large_object(parse_oid(s)?)?;
+    }
+    trans.commit()
+}
+
+fn vacuum(conn: &Connection, matches: &ArgMatches) -> postgres::Result<()> {
+    let delete = matches.is_present("delete");
+
+    let trans = conn.transaction()?;
+    let orphans = if delete {
+        vacuum::delete_orphans(&trans)?
+    } else {
+        vacuum::find_orphans(&trans)?
+    };
+    trans.commit()?;
+
+    if matches.is_present("json") {
+        let action = if delete { "deleted" } else { "orphaned" };
+        for orphan in &orphans {
+            println!(
+                "{}",
+                json!({ "oid": orphan.oid, "size": orphan.size, "action": action })
+            );
+        }
+        return Ok(());
+    }
+
+    let action = if delete { "deleted" } else { "would delete" };
+    for orphan in &orphans {
+        println!("{} {} ({} bytes)", action, orphan.oid, orphan.size);
+    }
+    let bytes = orphans.iter().map(|o| o.size).sum::<u64>();
+    println!("{} {} orphaned objects ({} bytes)", action, orphans.len(), bytes);
+    Ok(())
+}
+
+fn verify(conn: &Connection, matches: &ArgMatches) -> postgres::Result<()> {
+    let json = matches.is_present("json");
+    let manifest = match matches.value_of("manifest") {
+        Some(path) => Some(
+            export::read_manifest(File::open(path)?)?
+                .into_iter()
+                .map(|e| (e.oid, e.size))
+                .collect::<BTreeMap<_, _>>(),
+        ),
+        None => None,
+    };
+
+    let oids = if matches.is_present("all") {
+        conn.list_large_objects()?
+    } else if let Some(values) = matches.values_of("oid") {
+        values.map(parse_oid).collect::<postgres::Result<Vec<_>>>()?
+    } else {
+        manifest.as_ref().unwrap().keys().cloned().collect()
+    };
+
+    let mut failures = 0;
+    for &oid in &oids {
+        let result = verify_object(conn, oid).and_then(|size| {
+            match manifest.as_ref().map(|m| m.get(&oid)) {
+                Some(None) => Err(invalid_input("not present in the manifest".to_owned())),
+                Some(Some(&expected)) if expected != size => Err(invalid_input(format!(
+                    "size {} does not match the manifest's {}",
+                    size, expected
+                ))),
+                _ => Ok(size),
+            }
+        });
+
+        if result.is_err() {
+            failures += 1;
+        }
+        match (result, json) {
+            (Ok(size), false) => println!("ok {} ({} bytes)", oid, size),
+            (Err(e), false) => println!("FAILED {}: {}", oid, e),
+            (Ok(size), true) => println!("{}", json!({ "oid": oid, "ok": true, "size": size })),
+            (Err(e), true) => {
+                println!("{}", json!({ "oid": oid, "ok": false, "error": e.to_string() }))
+            }
+        }
+    }
+
+    if !json {
+        println!("verified {} objects, {} failed", oids.len(), failures);
+    }
+    if failures > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Reads the entirety of an object, returning its size.
+fn verify_object(conn: &Connection, oid: Oid) -> postgres::Result<u64> {
+    // a failed read aborts the transaction, so each object gets its own
+    let trans = conn.transaction()?;
+    let mut lo = trans.open_large_object(oid, Mode::Read)?;
+    let size = io::copy(&mut lo, &mut io::sink())?;
+    Ok(size)
+}
+
+fn backup(conn: &Connection, matches: &ArgMatches) -> postgres::Result<()> {
+    let filter = oid_filter(matches)?;
+    let mut file = BufWriter::new(File::create(matches.value_of("ARCHIVE").unwrap())?);
+
+    let trans = conn.transaction()?;
+    let mut total = 0;
+    let mut total_bytes = 0;
+    for oid in trans.list_large_objects()? {
+        if filter(oid) {
+            total += 1;
+            total_bytes += trans.open_large_object(oid, Mode::Read)?.size()?;
+        }
+    }
+
+    let bars = MultiProgress::new();
+    let progress = bars.add(object_progress(Some(total)));
+    let byte_progress = bars.add(bytes_progress(Some(total_bytes)));
+    let entries = backup::backup_with_progress(
+        &trans,
+        &mut file,
+        |oid| {
+            let selected = filter(oid);
+            if selected {
+                progress.set_message(format!("backing up {}", oid));
+                progress.inc(1);
+            }
+            selected
+        },
+        |bytes| byte_progress.set_position(bytes),
+    )?;
+    file.flush()?;
+    progress.finish_and_clear();
+    byte_progress.finish_and_clear();
+    let bytes = entries.iter().map(|e| e.size).sum::<u64>();
+    println!("backed up {} objects ({} bytes)", entries.len(), bytes);
+    Ok(())
+}
+
+fn restore(conn: &Connection, matches: &ArgMatches) -> postgres::Result<()> {
+    let filter = oid_filter(matches)?;
+    let file = File::open(matches.value_of("ARCHIVE").unwrap())?;
+
+    let trans = conn.transaction()?;
+    // the manifest is at the end of the archive, so the totals aren't known
+    let bars = MultiProgress::new();
+    let progress = bars.add(object_progress(None));
+    let byte_progress = bars.add(bytes_progress(None));
+    let entries = backup::restore_with_progress(
+        &trans,
+        BufReader::new(file),
+        |oid| {
+            let selected = filter(oid);
+            if selected {
+                progress.set_message(format!("restoring {}", oid));
+                progress.inc(1);
+            }
+            selected
+        },
+        |bytes| byte_progress.set_position(bytes),
+    )?;
+    trans.commit()?;
+    progress.finish_and_clear();
+    byte_progress.finish_and_clear();
+    let bytes = entries.iter().map(|e| e.size).sum::<u64>();
+    println!("restored {} objects ({} bytes)", entries.len(), bytes);
+    Ok(())
+}
+
+fn cp(matches: &ArgMatches) -> postgres::Result<()> {
+    let from = matches.value_of("from").unwrap();
+    let to = matches.value_of("to").unwrap();
+    let jobs = match matches.value_of("jobs").unwrap().parse::<usize>() {
+        Ok(jobs) if jobs > 0 => jobs,
+        _ => return Err(invalid_input("--jobs must be a positive integer".to_owned())),
+    };
+
+    let oids = if matches.is_present("all") {
+        connect(Some(from))?.list_large_objects()?
+    } else {
+        matches
+            .values_of("OID")
+            .unwrap()
+            .map(parse_oid)
+            .collect::<postgres::Result<Vec<_>>>()?
+    };
+    let progress = object_progress(Some(oids.len() as u64));
+
+    let queue = Arc::new(Mutex::new(oids.into_iter()));
+    let copied = Arc::new(AtomicU64::new(0));
+    let workers = (0..jobs)
+        .map(|_| {
+            let from = from.to_owned();
+            let to = to.to_owned();
+            let queue = queue.clone();
+            let copied = copied.clone();
+            let progress = progress.clone();
+            thread::spawn(move || copy_worker(&from, &to, &queue, &copied, &progress))
+        })
+        .collect::<Vec<_>>();
+
+    let mut result = Ok(());
+    for worker in workers {
+        let worker_result = match worker.join() {
+            Ok(r) => r,
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "copy thread panicked").into()),
+        };
+        if result.is_ok() {
+            result = worker_result;
+        }
+    }
+    progress.finish_and_clear();
+    result
+}
+
+fn copy_worker(
+    from: &str,
+    to: &str,
+    queue: &Mutex<vec::IntoIter<Oid>>,
+    copied: &AtomicU64,
+    progress: &ProgressBar,
+) -> postgres::Result<()> {
+    let src = connect(Some(from))?;
+    let dst = connect(Some(to))?;
+
+    loop {
+        let oid = match queue.lock().unwrap().next() {
+            Some(oid) => oid,
+            None => return Ok(()),
+        };
+
+        let src_trans = src.transaction()?;
+        let dst_trans = dst.transaction()?;
+        let len = copy_large_object(&src_trans, &dst_trans, oid)?.bytes;
+        dst_trans.commit()?;
+
+        let copied = copied.fetch_add(len, Ordering::SeqCst) + len;
+        progress.println(format!("copied {} ({} bytes)", oid, len));
+        progress.set_message(format!("{} copied", HumanBytes(copied)));
+        progress.inc(1);
+    }
+}
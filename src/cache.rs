@@ -0,0 +1,298 @@
+//! Client side caching for random access reads.
+//!
+//! Workloads which repeatedly seek around the same regions of an object,
+//! such as reading a zip archive's central directory and then its members,
+//! would otherwise fetch the same bytes from the server over and over. A
+//! `CachedReader` keeps the most recently read blocks of the object in
+//! memory.
+//!
+//! Read-heavy front ends can also keep whole objects on local disk with a
+//! `DiskCache`, which serves an object from its local copy for as long as the
+//! object's contents are unchanged.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use {etag, read_full, seek_position, LargeObject, LargeObjectTransactionExt, Mode};
+
+/// The default size of the blocks fetched and cached by a `CachedReader`.
+pub const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+
+/// A reader which caches blocks of a large object, evicting the least
+/// recently used block once its capacity is reached.
+///
+/// The reader tracks its own position, so the position of the underlying
+/// handle is unspecified. Writes made to the object after a block has been
+/// cached, whether through this handle or any other, are not seen.
+#[derive(Debug)]
+pub struct CachedReader<'a> {
+    lo: LargeObject<'a>,
+    pos: u64,
+    size: Option<u64>,
+    block_size: usize,
+    capacity: usize,
+    blocks: HashMap<u64, Vec<u8>>,
+    // block indices, least recently used first
+    lru: VecDeque<u64>,
+}
+
+impl<'a> CachedReader<'a> {
+    /// Creates a reader caching up to `capacity` bytes of the object, in
+    /// blocks of `DEFAULT_BLOCK_SIZE` bytes.
+    ///
+    /// Reading starts at the current position of the handle.
+    pub fn new(lo: LargeObject<'a>, capacity: usize) -> io::Result<CachedReader<'a>> {
+        CachedReader::with_block_size(lo, capacity, DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Like `new`, but fetches and caches blocks of the specified size.
+    pub fn with_block_size(
+        mut lo: LargeObject<'a>,
+        capacity: usize,
+        block_size: usize,
+    ) -> io::Result<CachedReader<'a>> {
+        let pos = lo.seek(SeekFrom::Current(0))?;
+        let block_size = cmp::max(block_size, 1);
+        Ok(CachedReader {
+            lo: lo,
+            pos: pos,
+            size: None,
+            block_size: block_size,
+            capacity: cmp::max(capacity / block_size, 1),
+            blocks: HashMap::new(),
+            lru: VecDeque::new(),
+        })
+    }
+
+    /// Drops all cached data.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.lru.clear();
+        self.size = None;
+    }
+
+    /// Returns the underlying object.
+    pub fn into_inner(self) -> LargeObject<'a> {
+        self.lo
+    }
+
+    fn block(&mut self, index: u64) -> io::Result<&[u8]> {
+        if self.blocks.contains_key(&index) {
+            if let Some(i) = self.lru.iter().position(|&b| b == index) {
+                self.lru.remove(i);
+            }
+        } else {
+            let mut buf = vec![0; self.block_size];
+            self.lo.seek(SeekFrom::Start(index * self.block_size as u64))?;
+            let len = read_full(&mut self.lo, &mut buf)?;
+            buf.truncate(len);
+
+            if self.lru.len() >= self.capacity {
+                if let Some(evicted) = self.lru.pop_front() {
+                    self.blocks.remove(&evicted);
+                }
+            }
+            self.blocks.insert(index, buf);
+        }
+        self.lru.push_back(index);
+
+        Ok(&self.blocks[&index])
+    }
+}
+
+impl<'a> Read for CachedReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let block_size = self.block_size as u64;
+        let index = self.pos / block_size;
+        let offset = (self.pos % block_size) as usize;
+        let n = {
+            let block = self.block(index)?;
+            if offset >= block.len() {
+                // a short block marks the end of the object
+                return Ok(0);
+            }
+            let n = cmp::min(buf.len(), block.len() - offset);
+            buf[..n].copy_from_slice(&block[offset..offset + n]);
+            n
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a> Seek for CachedReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => {
+                self.pos = pos;
+                return Ok(pos);
+            }
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => {
+                let size = match self.size {
+                    Some(size) => size,
+                    None => {
+                        let size = self.lo.size()?;
+                        self.size = Some(size);
+                        size
+                    }
+                };
+                (size, offset)
+            }
+        };
+
+        self.pos = seek_position(base, offset)?;
+        Ok(self.pos)
+    }
+}
+
+/// A cache of large objects stored in a local directory.
+///
+/// Entries are keyed by `Oid` and the object's entity tag, as computed by
+/// `etag::etag`. Checking an entry costs a single query, in which the server
+/// hashes the object without transferring it.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    /// Creates a cache storing objects in the specified directory, creating
+    /// it if necessary.
+    pub fn new<P>(dir: P) -> io::Result<DiskCache>
+    where
+        P: AsRef<Path>,
+    {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(DiskCache {
+            dir: dir.as_ref().to_owned(),
+        })
+    }
+
+    /// Returns a file containing the current contents of the object with the
+    /// specified `Oid`.
+    ///
+    /// If the cached copy is missing or out of date, the object is downloaded
+    /// and any stale copies are removed.
+    pub fn open(&self, trans: &Transaction, oid: Oid) -> Result<File> {
+        let tag = etag::etag(trans, oid)?;
+        let name = format!("{}-{}", oid, tag.trim_matches('"'));
+        let path = self.dir.join(&name);
+
+        match File::open(&path) {
+            Ok(file) => return Ok(file),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // download to a hidden file so a partial copy is never served
+        let tmp = self.dir.join(format!(".{}.{}.tmp", name, process::id()));
+        let r = (|| -> Result<()> {
+            let mut lo = trans.open_large_object(oid, Mode::Read)?;
+            let mut file = File::create(&tmp)?;
+            lo.copy_to(&mut file)?;
+            file.sync_all()?;
+            lo.finish()?;
+            fs::rename(&tmp, &path)?;
+            Ok(())
+        })();
+        if let Err(e) = r {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+
+        self.remove_entries(oid, Some(&name))?;
+        File::open(&path).map_err(Into::into)
+    }
+
+    /// Removes all cached copies of the object with the specified `Oid`.
+    pub fn remove(&self, oid: Oid) -> io::Result<()> {
+        self.remove_entries(oid, None)
+    }
+
+    fn remove_entries(&self, oid: Oid, keep: Option<&str>) -> io::Result<()> {
+        let prefix = format!("{}-", oid);
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            if name.starts_with(&prefix) && Some(name) != keep {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use cache::{CachedReader, DiskCache};
+
+    #[test]
+    fn test_cached_reader() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut reader = CachedReader::with_block_size(lo, 8, 4).unwrap();
+        let mut buf = [0; 5];
+        reader.seek(SeekFrom::End(-8)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        reader.seek(SeekFrom::Current(1)).unwrap();
+        let mut out = vec![];
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"world!!!");
+    }
+
+    #[test]
+    fn test_disk_cache() {
+        use std::env;
+        use std::fs;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.finish().unwrap();
+
+        let dir = env::temp_dir().join(format!("postgres_large_object_cache_{}", oid));
+        let cache = DiskCache::new(&dir).unwrap();
+        let mut out = String::new();
+        cache.open(&trans, oid).unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello world!!!");
+
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"HELLO").unwrap();
+        lo.finish().unwrap();
+
+        out.clear();
+        cache.open(&trans, oid).unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, "HELLO world!!!");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        cache.remove(oid).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+//! Cancellation of long running transfers.
+//!
+//! A `CancellationToken` is shared between the code performing a transfer
+//! and whoever may want to stop it. Handles with a token attached (see
+//! `LargeObject::set_cancellation_token`) fail with an error between chunks
+//! once it has been cancelled. The query in flight at the time can also be
+//! cancelled server side with the backend cancellation protocol, so that a
+//! slow chunk doesn't have to run to completion first.
+use postgres::params::IntoConnectParams;
+use postgres::{self, CancelData, TlsMode};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A token used to cancel transfers.
+///
+/// Clones of a token share its state.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    on_cancel: Mutex<Vec<Box<dyn Fn() + Send>>>,
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl CancellationToken {
+    /// Creates a new token.
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancels the token, running any callbacks registered with `on_cancel`.
+    pub fn cancel(&self) {
+        if self.0.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // the lock is released first so callbacks can register others
+        let callbacks = mem::replace(&mut *self.0.on_cancel.lock().unwrap(), vec![]);
+        for f in callbacks {
+            f();
+        }
+    }
+
+    /// Determines if the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Returns an error if the token has been cancelled.
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::new(io::ErrorKind::Other, "operation cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Registers a callback to be run when the token is cancelled.
+    ///
+    /// If the token has already been cancelled, it is run immediately.
+    pub fn on_cancel<F>(&self, f: F)
+    where
+        F: Fn() + Send + 'static,
+    {
+        {
+            let mut callbacks = self.0.on_cancel.lock().unwrap();
+            if !self.is_cancelled() {
+                callbacks.push(Box::new(f));
+                return;
+            }
+        }
+        f();
+    }
+
+    /// Registers the connection identified by `cancel_data` to have its
+    /// in-flight query cancelled when the token is cancelled.
+    ///
+    /// `params` must identify the same server as the connection. The cancel
+    /// request is sent without TLS; use `on_cancel` with
+    /// `postgres::cancel_query` directly if TLS is required.
+    pub fn cancel_query_on<T>(&self, params: T, cancel_data: CancelData)
+    where
+        T: IntoConnectParams + Clone + Send + 'static,
+    {
+        self.on_cancel(move || {
+            let _ = postgres::cancel_query(params.clone(), TlsMode::None, &cancel_data);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::Write;
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use cancel::CancellationToken;
+
+    #[test]
+    fn test_cancel() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let token = CancellationToken::new();
+        token.cancel_query_on("postgres://postgres@localhost", conn.cancel_data());
+
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.set_cancellation_token(token.clone());
+        lo.write_all(b"hello").unwrap();
+        token.cancel();
+        assert!(token.is_cancelled());
+        assert!(lo.write_all(b" world").is_err());
+    }
+
+    #[test]
+    fn test_nested_on_cancel() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let token = CancellationToken::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        {
+            let token2 = token.clone();
+            let runs = runs.clone();
+            token.on_cancel(move || {
+                runs.fetch_add(1, Ordering::SeqCst);
+                let runs = runs.clone();
+                token2.on_cancel(move || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                });
+            });
+        }
+        token.cancel();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,394 @@
+//! Detection of the large object functions supported by a server.
+//!
+//! The 64 bit variants of the large object functions were added in Postgres
+//! 9.3, and `lo_get` and `lo_put` in 9.4. Older servers are still supported,
+//! with some limits:
+//!
+//! * Before 9.3, objects are limited to 2GB, and seeking or truncating past
+//!   that fails with an `Unsupported` error. Reading and writing through
+//!   the `Read` and `Write` implementations works as usual.
+//! * Before 9.0, there is no `pg_largeobject_metadata` catalog, so
+//!   `LargeObjectExt::list_large_objects` falls back to scanning
+//!   `pg_largeobject`, and the helper modules which consult the catalog
+//!   don't work.
+//! * Before 8.3, there is no `lo_truncate`, so `LargeObject::truncate` fails
+//!   with an `Unsupported` error.
+//!
+//! Rather than inferring which functions are present from the
+//! `server_version` reported by the server, which forks and connection
+//! proxies don't always report faithfully, the server's catalog is checked
+//! for the functions themselves.
+//!
+//! The first handle opened on a connection checks the catalog as part of the
+//! query which opens it, so this costs no extra round trips, and the result
+//! is remembered for later handles on the same connection. Capabilities can
+//! also be detected up front with `Capabilities::detect` and passed to
+//! `OpenOptions::capabilities`. Remembered capabilities don't notice changes
+//! to `lo_compat_privileges` made later in the session.
+//!
+//! Some servers speaking the Postgres protocol, like CockroachDB, don't
+//! support large objects at all. Operations on them fail with an
+//! `Unsupported` error, and `LargeObjectExt::supports_large_objects` can be
+//! used to check up front.
+//!
+//! The capabilities also report whether the server enforces privileges on
+//! individual objects, which depends on the `lo_compat_privileges` setting,
+//! so applications can tell whether granting access per object means
+//! anything.
+//!
+//! Capabilities passed to `OpenOptions::capabilities` can also be built or
+//! adjusted by hand, to force a handle onto the 32 bit functions, say, when
+//! middleware interferes with detection.
+use postgres::{Connection, GenericConnection, Result};
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+
+use {first_column, parse_version};
+
+/// An expression evaluating to the names of the relevant functions and
+/// relations present in the server's catalog, along with
+/// `lo_compat_privileges` if that setting is on.
+pub(crate) const NAMES: &str = "ARRAY(SELECT p.proname::TEXT \
+     FROM pg_catalog.pg_proc p \
+     JOIN pg_catalog.pg_namespace n ON n.oid = p.pronamespace \
+     WHERE n.nspname = 'pg_catalog' \
+     AND p.proname IN ('lo_open', 'lo_truncate', 'lo_lseek64', 'lo_truncate64', 'lo_get') \
+     UNION ALL SELECT c.relname::TEXT \
+     FROM pg_catalog.pg_class c \
+     JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace \
+     WHERE n.nspname = 'pg_catalog' \
+     AND c.relname = 'pg_largeobject_metadata' \
+     UNION ALL SELECT name::TEXT \
+     FROM pg_catalog.pg_settings \
+     WHERE name = 'lo_compat_privileges' AND setting = 'on')";
+
+/// The number of connections whose capabilities are remembered.
+const CACHE_SIZE: usize = 64;
+
+// capabilities detected on recently used connections, identified by their
+// backend process ID and cancellation key, most recently detected last
+static CACHE: Mutex<Vec<((i32, i32), Capabilities)>> = Mutex::new(Vec::new());
+
+fn connection_key(conn: &Connection) -> (i32, i32) {
+    let data = conn.cancel_data();
+    (data.process_id, data.secret_key)
+}
+
+/// Returns the capabilities previously detected on a connection, if any.
+pub(crate) fn cached(conn: &Connection) -> Option<Capabilities> {
+    let key = connection_key(conn);
+    let cache = CACHE.lock().unwrap();
+    cache.iter().find(|e| e.0 == key).map(|e| e.1)
+}
+
+/// Remembers the capabilities detected on a connection.
+pub(crate) fn remember(conn: &Connection, capabilities: Capabilities) {
+    let key = connection_key(conn);
+    let mut cache = CACHE.lock().unwrap();
+    cache.retain(|e| e.0 != key);
+    if cache.len() >= CACHE_SIZE {
+        cache.remove(0);
+    }
+    cache.push((key, capabilities));
+}
+
+/// The large object functionality supported by a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    has_large_objects: bool,
+    has_truncate: bool,
+    has_metadata: bool,
+    has_64: bool,
+    has_lo_get: bool,
+    compat_privileges: bool,
+}
+
+impl Capabilities {
+    /// Returns capabilities including all supported functionality, as on
+    /// Postgres 9.4 and newer.
+    pub fn all() -> Capabilities {
+        Capabilities {
+            has_large_objects: true,
+            has_truncate: true,
+            has_metadata: true,
+            has_64: true,
+            has_lo_get: true,
+            compat_privileges: false,
+        }
+    }
+
+    /// Determines the capabilities of a server by checking its catalog.
+    pub fn detect<C: GenericConnection>(conn: &C) -> Result<Capabilities> {
+        let rows = conn.query(&format!("SELECT {}", NAMES), &[])?;
+        let names: Vec<String> = first_column(&rows)?;
+        Ok(Capabilities::from_names(&names))
+    }
+
+    /// Like `detect`, but reuses the capabilities already detected on the
+    /// connection if there are any.
+    pub(crate) fn detect_cached(conn: &Connection) -> Result<Capabilities> {
+        if let Some(capabilities) = cached(conn) {
+            return Ok(capabilities);
+        }
+        let capabilities = Capabilities::detect(conn)?;
+        remember(conn, capabilities);
+        Ok(capabilities)
+    }
+
+    /// Infers the capabilities of a server from its `server_version`.
+    ///
+    /// This is only as reliable as the version string, so `detect` should be
+    /// preferred where possible. `lo_compat_privileges` is assumed to be off.
+    pub fn from_server_version(version: &str) -> io::Result<Capabilities> {
+        let (major, minor) = parse_version(version)?;
+        Ok(Capabilities {
+            has_large_objects: true,
+            has_truncate: major > 8 || (major == 8 && minor >= 3),
+            has_metadata: major >= 9,
+            has_64: major > 9 || (major == 9 && minor >= 3),
+            has_lo_get: major > 9 || (major == 9 && minor >= 4),
+            compat_privileges: false,
+        })
+    }
+
+    pub(crate) fn from_names(names: &[String]) -> Capabilities {
+        let has = |name: &str| names.iter().any(|n| n == name);
+        Capabilities {
+            has_large_objects: has("lo_open"),
+            has_truncate: has("lo_truncate"),
+            has_metadata: has("pg_largeobject_metadata"),
+            has_64: has("lo_lseek64") && has("lo_truncate64"),
+            has_lo_get: has("lo_get"),
+            compat_privileges: has("lo_compat_privileges"),
+        }
+    }
+
+    /// Determines if the server supports large objects at all.
+    pub fn supports_large_objects(&self) -> bool {
+        self.has_large_objects
+    }
+
+    /// Determines if the server supports truncating objects with
+    /// `lo_truncate`.
+    pub fn has_truncate(&self) -> bool {
+        self.has_truncate
+    }
+
+    /// Determines if the server has the `pg_largeobject_metadata` catalog.
+    pub fn has_metadata(&self) -> bool {
+        self.has_metadata
+    }
+
+    /// Determines if the server supports objects larger than 2GB, through
+    /// `lo_lseek64` and `lo_truncate64`.
+    pub fn has_64_bit(&self) -> bool {
+        self.has_64
+    }
+
+    /// Determines if the server supports reading ranges of objects in a
+    /// single query with `lo_get`.
+    pub fn has_lo_get(&self) -> bool {
+        self.has_lo_get
+    }
+
+    /// Determines if the `lo_compat_privileges` setting is on.
+    ///
+    /// With it on, the server skips permission checks on large objects, as
+    /// servers before Postgres 9.0 did.
+    pub fn compat_privileges(&self) -> bool {
+        self.compat_privileges
+    }
+
+    /// Determines if the server enforces the privileges granted on
+    /// individual objects with `GRANT ... ON LARGE OBJECT`.
+    ///
+    /// This requires Postgres 9.0 or newer, with `lo_compat_privileges` off.
+    pub fn has_object_privileges(&self) -> bool {
+        self.has_metadata && !self.compat_privileges
+    }
+
+    /// Overrides whether the 64 bit functions are used.
+    ///
+    /// Without them, objects are limited to 2GB.
+    pub fn set_64_bit(&mut self, has_64: bool) -> &mut Capabilities {
+        self.has_64 = has_64;
+        self
+    }
+
+    /// Overrides whether `lo_get` is used.
+    pub fn set_lo_get(&mut self, has_lo_get: bool) -> &mut Capabilities {
+        self.has_lo_get = has_lo_get;
+        self
+    }
+}
+
+/// The error returned when the server lacks the functionality required by
+/// an operation.
+///
+/// It's wrapped in an `io::Error`, from which it can be recovered with
+/// `Unsupported::from_error`.
+#[derive(Debug, Clone)]
+pub struct Unsupported {
+    feature: &'static str,
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "the server does not support {}", self.feature)
+    }
+}
+
+impl error::Error for Unsupported {}
+
+impl From<Unsupported> for io::Error {
+    fn from(e: Unsupported) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+}
+
+impl Unsupported {
+    pub(crate) fn new(feature: &'static str) -> Unsupported {
+        Unsupported { feature: feature }
+    }
+
+    /// Returns the `Unsupported` error wrapped by an error, if there is one.
+    pub fn from_error(e: &::postgres::Error) -> Option<&Unsupported> {
+        e.as_io()
+            .and_then(|e| e.get_ref())
+            .and_then(|e| e.downcast_ref::<Unsupported>())
+    }
+
+    /// Returns a description of the missing functionality.
+    pub fn feature(&self) -> &str {
+        self.feature
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode, OpenOptions};
+    use capabilities::{Capabilities, Unsupported};
+
+    #[test]
+    fn test_detect() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let caps = Capabilities::detect(&conn).unwrap();
+        let version = conn.parameter("server_version").unwrap();
+        assert_eq!(caps, Capabilities::from_server_version(&version).unwrap());
+        assert!(caps.supports_large_objects());
+        assert!(conn.supports_large_objects().unwrap());
+
+        let old = Capabilities::from_server_version("9.2.24").unwrap();
+        assert!(!old.has_64_bit());
+        assert!(!old.has_lo_get());
+        assert!(old.has_truncate());
+        assert!(old.has_metadata());
+
+        let ancient = Capabilities::from_server_version("8.2.23").unwrap();
+        assert!(!ancient.has_truncate());
+        assert!(!ancient.has_metadata());
+
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        assert_eq!(lo.capabilities(), caps);
+
+        let lo = OpenOptions::new()
+            .capabilities(old)
+            .open(&trans, oid, Mode::Read)
+            .unwrap();
+        assert_eq!(lo.capabilities(), old);
+    }
+
+    #[test]
+    fn test_compat_privileges() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let caps = Capabilities::detect(&conn).unwrap();
+        assert!(!caps.compat_privileges());
+        assert!(caps.has_object_privileges());
+
+        conn.batch_execute("SET lo_compat_privileges = on").unwrap();
+        let caps = Capabilities::detect(&conn).unwrap();
+        assert!(caps.compat_privileges());
+        assert!(!caps.has_object_privileges());
+    }
+
+    #[test]
+    fn test_cached() {
+        use capabilities;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        assert_eq!(capabilities::cached(&conn), None);
+
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        assert_eq!(capabilities::cached(&conn), Some(lo.capabilities()));
+        assert_eq!(Capabilities::detect_cached(&conn).unwrap(), lo.capabilities());
+
+        let other = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        assert_eq!(capabilities::cached(&other), None);
+    }
+
+    #[test]
+    fn test_override() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let mut caps = Capabilities::all();
+        caps.set_64_bit(false);
+        assert!(!caps.has_64_bit());
+        assert!(caps.has_lo_get());
+
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = OpenOptions::new()
+            .capabilities(caps)
+            .open(&trans, oid, Mode::Write)
+            .unwrap();
+        lo.truncate(10).unwrap();
+        let e = lo.truncate(1 << 32).unwrap_err();
+        assert!(Unsupported::from_error(&e).is_some());
+        assert_eq!(lo.size().unwrap(), 10);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let mut caps = Capabilities::all();
+        caps.has_large_objects = false;
+
+        let trans = conn.transaction().unwrap();
+        let e = OpenOptions::new()
+            .capabilities(caps)
+            .open(&trans, 0, Mode::Read)
+            .unwrap_err();
+        assert_eq!(Unsupported::from_error(&e).unwrap().feature(), "large objects");
+    }
+
+    #[test]
+    fn test_legacy() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let caps = Capabilities::from_server_version("8.2").unwrap();
+
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = OpenOptions::new()
+            .capabilities(caps)
+            .open(&trans, oid, Mode::ReadWrite)
+            .unwrap();
+        lo.write_all(b"hello world").unwrap();
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello world");
+
+        let e = lo.truncate(5).unwrap_err();
+        assert_eq!(Unsupported::from_error(&e).unwrap().feature(), "lo_truncate");
+        let e = lo.seek(SeekFrom::Start(1 << 32)).unwrap_err();
+        assert!(e.get_ref().unwrap().is::<Unsupported>());
+    }
+}
@@ -0,0 +1,178 @@
+//! Reading several large objects as one stream.
+//!
+//! Data uploaded in parts, such as a multi-part upload, is often stored as
+//! one object per part. A `ChainedReader` presents the parts as a single
+//! seekable stream, without first concatenating them into another object.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use {seek_position, LargeObject, Mode, OpenOptions};
+
+/// A reader over the concatenated contents of several large objects.
+///
+/// The sizes of the objects are read when the reader is created, and the
+/// objects must not change size while it is in use.
+#[derive(Debug)]
+pub struct ChainedReader<'a> {
+    parts: Vec<LargeObject<'a>>,
+    // the offset at which each part starts
+    starts: Vec<u64>,
+    len: u64,
+    pos: u64,
+    // the part whose own position corresponds to `pos`, if any
+    current: Option<usize>,
+}
+
+impl<'a> ChainedReader<'a> {
+    /// Opens the objects with the specified `Oid`s, in order, for reading.
+    pub fn open(trans: &'a Transaction, oids: &[Oid]) -> Result<ChainedReader<'a>> {
+        let mut parts = Vec::with_capacity(oids.len());
+        let mut starts = Vec::with_capacity(oids.len());
+        let mut len = 0;
+        for &oid in oids {
+            let mut lo = OpenOptions::new()
+                .track_position(true)
+                .open(trans, oid, Mode::READ)?;
+            starts.push(len);
+            len += lo.size()?;
+            parts.push(lo);
+        }
+
+        Ok(ChainedReader {
+            parts: parts,
+            starts: starts,
+            len: len,
+            pos: 0,
+            current: None,
+        })
+    }
+
+    /// Returns the total size of the objects.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Determines if the objects are all empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the offset in the stream at which each object starts.
+    pub fn offsets(&self) -> &[u64] {
+        &self.starts
+    }
+
+    /// Returns the `Oid` of the object containing the specified offset in
+    /// the stream, along with the corresponding offset within that object.
+    ///
+    /// Returns `None` if the offset is past the end of the stream.
+    pub fn locate(&self, offset: u64) -> Option<(Oid, u64)> {
+        self.part(offset).map(|i| (self.parts[i].oid(), offset - self.starts[i]))
+    }
+
+    /// Consumes the reader, closing the objects.
+    pub fn finish(self) -> Result<()> {
+        for lo in self.parts {
+            lo.finish()?;
+        }
+        Ok(())
+    }
+
+    fn part(&self, offset: u64) -> Option<usize> {
+        if offset >= self.len {
+            return None;
+        }
+        // the last part starting at or before the offset is non-empty, since
+        // the offset is before the end
+        self.starts.iter().rposition(|&start| start <= offset)
+    }
+}
+
+impl<'a> Read for ChainedReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let i = match self.part(self.pos) {
+            Some(i) => i,
+            None => return Ok(0),
+        };
+        let end = self.starts.get(i + 1).cloned().unwrap_or(self.len);
+
+        if self.current != Some(i) {
+            self.parts[i].seek(SeekFrom::Start(self.pos - self.starts[i]))?;
+            self.current = Some(i);
+        }
+        let len = cmp::min(buf.len() as u64, end - self.pos) as usize;
+        let n = self.parts[i].read(&mut buf[..len])?;
+        if n == 0 && len > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("large object {} shrank while being read", self.parts[i].oid()),
+            ));
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a> Seek for ChainedReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => (pos, 0),
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (self.len, offset),
+        };
+
+        let pos = seek_position(base, offset)?;
+        if pos != self.pos {
+            self.pos = pos;
+            self.current = None;
+        }
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use chain::ChainedReader;
+
+    #[test]
+    fn test_chained_reader() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let mut oids = vec![];
+        for part in &[&b"hello "[..], &b""[..], &b"world"[..], &b"!!!"[..]] {
+            let oid = trans.create_large_object().unwrap();
+            let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+            lo.write_all(part).unwrap();
+            lo.finish().unwrap();
+            oids.push(oid);
+        }
+
+        let mut reader = ChainedReader::open(&trans, &oids).unwrap();
+        assert_eq!(reader.len(), 14);
+        assert_eq!(reader.offsets(), &[0, 6, 6, 11]);
+        assert_eq!(reader.locate(6), Some((oids[2], 0)));
+        assert_eq!(reader.locate(13), Some((oids[3], 2)));
+        assert_eq!(reader.locate(14), None);
+
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello world!!!");
+
+        let mut buf = [0; 7];
+        reader.seek(SeekFrom::Start(4)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"o world");
+        reader.seek(SeekFrom::End(-2)).unwrap();
+        out.clear();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "!!");
+        reader.finish().unwrap();
+    }
+}
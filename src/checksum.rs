@@ -0,0 +1,189 @@
+//! Computing checksums of data as it is transferred.
+//!
+//! A `ChecksumStream` wraps a reader or writer, such as a `LargeObject`, and
+//! feeds the bytes passing through it to a `Checksum`. The digest is
+//! available once the transfer completes, so integrity metadata can be
+//! recorded without a second pass over the data.
+//!
+//! CRC-32 is supported with the `with-crc32fast` feature, SHA-256 with the
+//! `with-sha2` feature, and MD5 with the `with-md5` feature.
+#[cfg(feature = "with-crc32fast")]
+use crc32fast;
+#[cfg(feature = "with-md5")]
+use md5;
+#[cfg(feature = "with-sha2")]
+use sha2::{self, Digest};
+use std::io::{self, Read, Write};
+
+/// A checksum or hash computed incrementally.
+pub trait Checksum {
+    /// The final value of the checksum.
+    type Output;
+
+    /// Feeds data to the checksum.
+    fn update(&mut self, data: &[u8]);
+
+    /// Consumes the checksum, returning its value.
+    fn finish(self) -> Self::Output;
+}
+
+/// CRC-32, as used by zip and gzip, returned as an integer.
+#[cfg(feature = "with-crc32fast")]
+impl Checksum for crc32fast::Hasher {
+    type Output = u32;
+
+    fn update(&mut self, data: &[u8]) {
+        crc32fast::Hasher::update(self, data)
+    }
+
+    fn finish(self) -> u32 {
+        self.finalize()
+    }
+}
+
+/// SHA-256, returned as a lowercase hex string.
+#[cfg(feature = "with-sha2")]
+impl Checksum for sha2::Sha256 {
+    type Output = String;
+
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data)
+    }
+
+    fn finish(self) -> String {
+        format!("{:x}", self.finalize())
+    }
+}
+
+/// MD5, returned as a lowercase hex string.
+#[cfg(feature = "with-md5")]
+impl Checksum for md5::Context {
+    type Output = String;
+
+    fn update(&mut self, data: &[u8]) {
+        self.consume(data)
+    }
+
+    fn finish(self) -> String {
+        format!("{:x}", self.compute())
+    }
+}
+
+/// A reader or writer computing a checksum of the bytes passed through it.
+#[derive(Debug)]
+pub struct ChecksumStream<T, C> {
+    inner: T,
+    checksum: C,
+    total: u64,
+}
+
+impl<T, C> ChecksumStream<T, C>
+where
+    C: Checksum,
+{
+    /// Wraps a reader or writer, feeding the bytes passed through it to
+    /// `checksum`.
+    pub fn new(inner: T, checksum: C) -> ChecksumStream<T, C> {
+        ChecksumStream {
+            inner: inner,
+            checksum: checksum,
+            total: 0,
+        }
+    }
+
+    /// Returns the number of bytes passed through the stream.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the underlying reader or writer, along with the value of the
+    /// checksum.
+    pub fn finish(self) -> (T, C::Output) {
+        (self.inner, self.checksum.finish())
+    }
+}
+
+impl<T, C> Read for ChecksumStream<T, C>
+where
+    T: Read,
+    C: Checksum,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.checksum.update(&buf[..n]);
+        self.total += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T, C> Write for ChecksumStream<T, C>
+where
+    T: Write,
+    C: Checksum,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.checksum.update(&buf[..n]);
+        self.total += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(all(test, any(feature = "with-crc32fast", feature = "with-sha2")))]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{self, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use checksum::ChecksumStream;
+
+    #[test]
+    #[cfg(feature = "with-crc32fast")]
+    fn test_crc32() {
+        use crc32fast::Hasher;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        let mut writer = ChecksumStream::new(lo, Hasher::new());
+        writer.write_all(b"hello world").unwrap();
+        assert_eq!(writer.total(), 11);
+        let (lo, crc) = writer.finish();
+        lo.finish().unwrap();
+        assert_eq!(crc, 0x0d4a1185);
+
+        let lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        let mut reader = ChecksumStream::new(lo, Hasher::new());
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(reader.finish().1, crc);
+    }
+
+    #[test]
+    #[cfg(feature = "with-sha2")]
+    fn test_sha256() {
+        use sha2::Sha256;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        let mut writer = ChecksumStream::new(lo, Sha256::default());
+        writer.write_all(b"hello world").unwrap();
+        let (lo, digest) = writer.finish();
+        lo.finish().unwrap();
+        assert_eq!(
+            digest,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+
+        let lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        let mut reader = ChecksumStream::new(lo, Sha256::default());
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(reader.finish().1, digest);
+    }
+}
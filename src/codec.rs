@@ -0,0 +1,83 @@
+//! tokio-util adapters for large object streams and sinks.
+//!
+//! Requires the `with-tokio-util` feature.
+//!
+//! The readers and writers here implement tokio's `AsyncRead` and
+//! `AsyncWrite`, so they can be used with `FramedRead` and `FramedWrite` and
+//! any codec, such as `BytesCodec` for raw chunks or `LengthDelimitedCodec`
+//! for framed messages.
+use bytes::Bytes;
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+use tokio_util::io::{CopyToBytes, SinkWriter, StreamReader};
+
+use stream::{LargeObjectSink, LargeObjectStream};
+
+/// An `AsyncRead` over the contents of a large object.
+pub type LargeObjectReader = StreamReader<LargeObjectStream, Bytes>;
+
+/// An `AsyncWrite` into a large object.
+///
+/// The object is only committed once the writer has been shut down.
+pub type LargeObjectWriter = SinkWriter<CopyToBytes<LargeObjectSink>>;
+
+/// Wraps a stream in an `AsyncRead`.
+pub fn reader(stream: LargeObjectStream) -> LargeObjectReader {
+    StreamReader::new(stream)
+}
+
+/// Wraps a sink in an `AsyncWrite`.
+pub fn writer(sink: LargeObjectSink) -> LargeObjectWriter {
+    SinkWriter::new(CopyToBytes::new(sink))
+}
+
+/// Decodes frames from the contents of a large object.
+pub fn framed_read<D>(stream: LargeObjectStream, decoder: D) -> FramedRead<LargeObjectReader, D>
+where
+    D: Decoder,
+{
+    FramedRead::new(reader(stream), decoder)
+}
+
+/// Encodes frames into a large object.
+///
+/// The object is only committed once the sink has been closed.
+pub fn framed_write<E, I>(sink: LargeObjectSink, encoder: E) -> FramedWrite<LargeObjectWriter, E>
+where
+    E: Encoder<I>,
+{
+    FramedWrite::new(writer(sink), encoder)
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use futures::{executor, SinkExt};
+    use postgres::{Connection, TlsMode};
+    use tokio_util::codec::LengthDelimitedCodec;
+
+    use LargeObjectExt;
+    use codec::{framed_read, framed_write};
+    use stream::{LargeObjectSink, LargeObjectStream, DEFAULT_CHUNK_SIZE};
+
+    #[test]
+    fn test_length_delimited() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let oid = conn.create_large_object().unwrap();
+
+        let writer = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let sink = LargeObjectSink::new(writer, oid).unwrap();
+        let mut frames = framed_write(sink, LengthDelimitedCodec::new());
+        executor::block_on(frames.send(Bytes::from_static(b"hello"))).unwrap();
+        executor::block_on(frames.send(Bytes::from_static(b"world!!!"))).unwrap();
+        executor::block_on(frames.close()).unwrap();
+
+        let reader = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let stream = LargeObjectStream::new(reader, oid, DEFAULT_CHUNK_SIZE).unwrap();
+        let frames = executor::block_on_stream(framed_read(stream, LengthDelimitedCodec::new()))
+            .map(|f| f.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(frames, [&b"hello"[..], &b"world!!!"[..]]);
+
+        conn.delete_large_object(oid).unwrap();
+    }
+}
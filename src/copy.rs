@@ -0,0 +1,361 @@
+//! Copying of large objects between databases.
+use postgres::{Connection, Result};
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::collections::HashSet;
+
+use {first_column, quote_literal, LargeObjectExt, LargeObjectTransactionExt, Mode, TransferStats};
+use cancel::CancellationToken;
+use progress::{Progress, ProgressStream};
+use throttle::RateLimiter;
+
+/// The number of bytes transferred per round trip when copying objects.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Options controlling how large objects are copied between databases.
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    token: Option<CancellationToken>,
+    limiter: Option<RateLimiter>,
+}
+
+impl CopyOptions {
+    /// Creates a new set of options with the default configuration.
+    pub fn new() -> CopyOptions {
+        CopyOptions::default()
+    }
+
+    /// Stops the copy between chunks with an error once `token` has been
+    /// cancelled.
+    pub fn cancellation_token(&mut self, token: CancellationToken) -> &mut CopyOptions {
+        self.token = Some(token);
+        self
+    }
+
+    /// Limits the rate at which data is copied.
+    pub fn rate_limiter(&mut self, limiter: RateLimiter) -> &mut CopyOptions {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Copies the large object with the specified `Oid` from one database to
+    /// another, returning statistics about the transfer.
+    ///
+    /// See `copy_large_object` for details.
+    pub fn copy(&self, src: &Transaction, dst: &Transaction, oid: Oid) -> Result<TransferStats> {
+        self.copy_with_progress(src, dst, oid, |_: u64| {})
+    }
+
+    /// Like `copy`, but reports the number of bytes copied so far to
+    /// `progress` after each chunk.
+    pub fn copy_with_progress<P>(
+        &self,
+        src: &Transaction,
+        dst: &Transaction,
+        oid: Oid,
+        progress: P,
+    ) -> Result<TransferStats>
+    where
+        P: Progress,
+    {
+        let mut src_lo = src.open_large_object(oid, Mode::Read)?;
+        src_lo.set_chunk_size(CHUNK_SIZE);
+        if let Some(ref token) = self.token {
+            src_lo.set_cancellation_token(token.clone());
+        }
+        if let Some(ref limiter) = self.limiter {
+            src_lo.set_rate_limiter(limiter.clone());
+        }
+        dst.create_large_object_with_oid(oid)?;
+        let mut dst_lo = dst.open_large_object(oid, Mode::Write)?;
+        let start = dst_lo.start_transfer();
+        let mut stats = src_lo.copy_to(&mut ProgressStream::new(&mut dst_lo, progress, 0))?;
+        let dst_stats = dst_lo.transfer_stats(0, start);
+        stats.chunks += dst_stats.chunks;
+        stats.round_trips += dst_stats.round_trips;
+        dst_lo.finish()?;
+        src_lo.finish()?;
+        Ok(stats)
+    }
+}
+
+/// Copies the large object with the specified `Oid` from one database to
+/// another, returning statistics about the transfer.
+///
+/// The object is created in the destination database with the same `Oid`,
+/// so that references to it remain valid. Data is streamed in chunks of up
+/// to `CHUNK_SIZE` bytes, so the object is never buffered in memory in full.
+/// The returned chunk and round trip counts cover both databases.
+///
+/// Use `CopyOptions` to combine cancellation, rate limiting and progress
+/// reporting.
+pub fn copy_large_object(src: &Transaction, dst: &Transaction, oid: Oid) -> Result<TransferStats> {
+    CopyOptions::new().copy(src, dst, oid)
+}
+
+/// Like `copy_large_object`, but stops between chunks with an error once
+/// `token` has been cancelled.
+pub fn copy_large_object_cancellable(
+    src: &Transaction,
+    dst: &Transaction,
+    oid: Oid,
+    token: &CancellationToken,
+) -> Result<TransferStats> {
+    CopyOptions::new().cancellation_token(token.clone()).copy(src, dst, oid)
+}
+
+/// Like `copy_large_object`, but limits the rate at which data is copied.
+pub fn copy_large_object_throttled(
+    src: &Transaction,
+    dst: &Transaction,
+    oid: Oid,
+    limiter: &RateLimiter,
+) -> Result<TransferStats> {
+    CopyOptions::new().rate_limiter(limiter.clone()).copy(src, dst, oid)
+}
+
+/// Like `copy_large_object`, but reports the number of bytes copied so far
+/// to `progress` after each chunk.
+pub fn copy_large_object_with_progress<P>(
+    src: &Transaction,
+    dst: &Transaction,
+    oid: Oid,
+    progress: P,
+) -> Result<TransferStats>
+where
+    P: Progress,
+{
+    CopyOptions::new().copy_with_progress(src, dst, oid, progress)
+}
+
+/// Copies each of the large objects with the specified `Oid`s from one
+/// database to another, returning statistics about the transfers combined.
+///
+/// See `copy_large_object` for details.
+pub fn copy_large_objects(src: &Transaction, dst: &Transaction, oids: &[Oid]) -> Result<TransferStats> {
+    let mut stats = TransferStats::default();
+    for &oid in oids {
+        stats.add(&copy_large_object(src, dst, oid)?);
+    }
+    Ok(stats)
+}
+
+/// Atomically moves the large object with the specified `Oid` from one
+/// database to another, returning the number of bytes copied.
+///
+/// The object is copied to the destination and deleted from the source in
+/// separate transactions, which are coordinated with two-phase commit so
+/// that either both or neither take effect. Both servers must have
+/// `max_prepared_transactions` set to a nonzero value.
+///
+/// The prepared transactions are named `gid` followed by `.dst` and `.src`,
+/// so `gid` should be unique across both servers. If an error occurs after
+/// both transactions have been prepared, they are left in place and must be
+/// resolved with `COMMIT PREPARED` by an administrator.
+pub fn move_large_object(src: &Connection, dst: &Connection, oid: Oid, gid: &str) -> Result<u64> {
+    let src_gid = quote_literal(&format!("{}.src", gid));
+    let dst_gid = quote_literal(&format!("{}.dst", gid));
+
+    let src_trans = src.transaction()?;
+    let dst_trans = dst.transaction()?;
+    let len = copy_large_object(&src_trans, &dst_trans, oid)?.bytes;
+    src_trans.delete_large_object(oid)?;
+
+    // A failed PREPARE TRANSACTION rolls the transaction back, and once one
+    // has succeeded there is no longer a transaction open on the session, so
+    // in either case the trailing COMMIT issued by the Transaction is a no-op.
+    dst_trans.batch_execute(&format!("PREPARE TRANSACTION {}", dst_gid))?;
+    dst_trans.commit()?;
+    if let Err(e) = src_trans.batch_execute(&format!("PREPARE TRANSACTION {}", src_gid)) {
+        let _ = src_trans.commit();
+        dst.batch_execute(&format!("ROLLBACK PREPARED {}", dst_gid))?;
+        return Err(e);
+    }
+    src_trans.commit()?;
+
+    dst.batch_execute(&format!("COMMIT PREPARED {}", dst_gid))?;
+    src.batch_execute(&format!("COMMIT PREPARED {}", src_gid))?;
+    Ok(len)
+}
+
+/// A summary of the work performed by `sync_large_objects`.
+#[derive(Debug, Clone, Default)]
+pub struct SyncSummary {
+    /// The `Oid`s of objects which were missing from the destination.
+    pub copied: Vec<Oid>,
+    /// The `Oid`s of objects which differed between the source and
+    /// destination and were replaced.
+    pub replaced: Vec<Oid>,
+    /// The number of objects which were already identical.
+    pub unchanged: usize,
+    /// The total number of bytes copied.
+    pub bytes: u64,
+}
+
+/// Brings the large objects in the destination database up to date with
+/// those in the source database, copying only objects which are missing or
+/// differ.
+///
+/// Objects are first compared by size. If `compare_contents` is set, objects
+/// of equal size are additionally compared by MD5 digests of each chunk,
+/// computed server side so that the data itself is not transferred. This
+/// requires Postgres 9.4 or newer on both sides.
+///
+/// Objects which only exist in the destination database are left untouched.
+pub fn sync_large_objects(
+    src: &Transaction,
+    dst: &Transaction,
+    compare_contents: bool,
+) -> Result<SyncSummary> {
+    let dst_oids = dst.list_large_objects()?.into_iter().collect::<HashSet<_>>();
+
+    let mut summary = SyncSummary::default();
+    for oid in src.list_large_objects()? {
+        if !dst_oids.contains(&oid) {
+            summary.bytes += copy_large_object(src, dst, oid)?.bytes;
+            summary.copied.push(oid);
+            continue;
+        }
+
+        let size = src.open_large_object(oid, Mode::Read)?.size()?;
+        let mut same = size == dst.open_large_object(oid, Mode::Read)?.size()?;
+        if same && compare_contents {
+            same = contents_match(src, dst, oid, size)?;
+        }
+
+        if same {
+            summary.unchanged += 1;
+        } else {
+            dst.delete_large_object(oid)?;
+            summary.bytes += copy_large_object(src, dst, oid)?.bytes;
+            summary.replaced.push(oid);
+        }
+    }
+
+    Ok(summary)
+}
+
+fn contents_match(src: &Transaction, dst: &Transaction, oid: Oid, size: u64) -> Result<bool> {
+    let query = "SELECT pg_catalog.md5(pg_catalog.lo_get($1, $2, $3))";
+    let src_stmt = src.prepare_cached(query)?;
+    let dst_stmt = dst.prepare_cached(query)?;
+
+    let len = CHUNK_SIZE as i32;
+    let mut offset = 0;
+    while offset < size as i64 {
+        let src_digest: String = first_column(&src_stmt.query(&[&oid, &offset, &len])?)?;
+        let dst_digest: String = first_column(&dst_stmt.query(&[&oid, &offset, &len])?)?;
+        if src_digest != dst_digest {
+            return Ok(false);
+        }
+        offset += len as i64;
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use cancel::CancellationToken;
+    use copy::{copy_large_object, sync_large_objects, CopyOptions};
+    use throttle::RateLimiter;
+
+    #[test]
+    fn test_copy_large_object() {
+        let src_conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let dst_conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+
+        let trans = src_conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.finish().unwrap();
+        trans.commit().unwrap();
+
+        let src = src_conn.transaction().unwrap();
+        let dst = dst_conn.transaction().unwrap();
+        // both connections share a database, so free up the Oid on the destination side
+        dst.delete_large_object(oid).unwrap();
+        assert_eq!(copy_large_object(&src, &dst, oid).unwrap().bytes, 14);
+
+        let mut out = vec![];
+        let mut lo = dst.open_large_object(oid, Mode::Read).unwrap();
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello world!!!");
+        drop(lo);
+        drop(dst);
+        drop(src);
+
+        src_conn.delete_large_object(oid).unwrap();
+    }
+
+    #[test]
+    fn test_copy_options() {
+        let src_conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let dst_conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+
+        let trans = src_conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.finish().unwrap();
+        trans.commit().unwrap();
+
+        let src = src_conn.transaction().unwrap();
+        let dst = dst_conn.transaction().unwrap();
+        dst.delete_large_object(oid).unwrap();
+        let mut options = CopyOptions::new();
+        options
+            .cancellation_token(CancellationToken::new())
+            .rate_limiter(RateLimiter::new(1024 * 1024));
+        let mut copied = 0;
+        let stats = options.copy_with_progress(&src, &dst, oid, |n| copied = n).unwrap();
+        assert_eq!(stats.bytes, 14);
+        assert_eq!(copied, 14);
+        drop(dst);
+        drop(src);
+
+        src_conn.delete_large_object(oid).unwrap();
+    }
+
+    #[test]
+    fn test_sync_large_objects() {
+        let src_conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let dst_conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+
+        let trans = src_conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.finish().unwrap();
+        trans.commit().unwrap();
+
+        let src = src_conn.transaction().unwrap();
+        let dst = dst_conn.transaction().unwrap();
+        // both connections share a database, so make the destination copy differ
+        let mut lo = dst.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"HELLO").unwrap();
+        lo.finish().unwrap();
+
+        let summary = sync_large_objects(&src, &dst, true).unwrap();
+        assert!(summary.copied.is_empty());
+        assert_eq!(summary.replaced, [oid]);
+
+        let mut out = vec![];
+        let mut lo = dst.open_large_object(oid, Mode::Read).unwrap();
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello world!!!");
+        drop(lo);
+
+        let summary = sync_large_objects(&src, &dst, true).unwrap();
+        assert!(summary.replaced.is_empty());
+        drop(dst);
+        drop(src);
+
+        src_conn.delete_large_object(oid).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+//! Delta synchronization of local files into large objects.
+//!
+//! Files which are periodically re-uploaded, like VM images and SQLite
+//! databases, usually change in place in a few scattered blocks. Rather than
+//! uploading the whole file again, the server computes an MD5 digest of each
+//! block of the stored object, and only the blocks whose digests differ from
+//! those of the local file are sent.
+//!
+//! Blocks are compared at the same offsets in both copies, so data inserted
+//! or removed in the middle of the file causes everything after it to be
+//! sent. Requires Postgres 9.4 or newer.
+use md5;
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::cmp;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use {column, read_full, LargeObjectTransactionExt, Mode, OpenOptions};
+
+/// The default size of the blocks compared.
+pub const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Statistics about a delta synchronization.
+#[derive(Debug, Clone, Default)]
+pub struct DeltaStats {
+    /// The size of the synchronized object.
+    pub bytes: u64,
+    /// The number of blocks compared.
+    pub blocks: u64,
+    /// The number of blocks which differed and were uploaded.
+    pub changed_blocks: u64,
+    /// The number of bytes uploaded.
+    pub uploaded: u64,
+}
+
+/// Returns the hex-encoded MD5 digests of each `block_size` block of the
+/// object with the specified `Oid`, computed server side.
+///
+/// The last block may be shorter than `block_size`.
+pub fn block_digests(trans: &Transaction, oid: Oid, block_size: usize) -> Result<Vec<String>> {
+    let block_size = cmp::max(block_size, 1);
+    let size = trans.open_large_object(oid, Mode::Read)?.size()?;
+    let stmt = trans.prepare_cached(
+        "SELECT pg_catalog.md5(pg_catalog.lo_get($1, off, $3))
+         FROM pg_catalog.generate_series(0, $2::INT8 - 1, $4::INT8) off
+         ORDER BY off",
+    )?;
+    let rows = stmt.query(&[
+        &oid,
+        &(size as i64),
+        &(block_size as i32),
+        &(block_size as i64),
+    ])?;
+    rows.iter().map(|r| column(&r, 0)).collect()
+}
+
+/// Updates the object with the specified `Oid` to match the contents of a
+/// reader, uploading only the blocks which differ.
+///
+/// The object is truncated or extended to the reader's length.
+pub fn sync<R>(trans: &Transaction, oid: Oid, r: &mut R, block_size: usize) -> Result<DeltaStats>
+where
+    R: ?Sized + Read,
+{
+    let block_size = cmp::max(block_size, 1);
+    let digests = block_digests(trans, oid, block_size)?;
+    // seeks between consecutive changed blocks are then free
+    let mut lo = OpenOptions::new()
+        .track_position(true)
+        .open(trans, oid, Mode::Write)?;
+
+    let mut stats = DeltaStats::default();
+    let mut buf = vec![0; block_size];
+    loop {
+        let len = read_full(r, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+        let block = &buf[..len];
+
+        let digest = format!("{:x}", md5::compute(block));
+        let changed = digests.get(stats.blocks as usize) != Some(&digest);
+        if changed {
+            lo.seek(SeekFrom::Start(stats.bytes))?;
+            lo.write_all(block)?;
+            stats.changed_blocks += 1;
+            stats.uploaded += len as u64;
+        }
+        stats.blocks += 1;
+        stats.bytes += len as u64;
+        if len < block_size {
+            break;
+        }
+    }
+
+    if lo.size()? > stats.bytes {
+        lo.truncate(stats.bytes as i64)?;
+    }
+    lo.finish()?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Cursor, Read, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use delta::sync;
+
+    #[test]
+    fn test_sync() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut data = (0..10_000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(&data).unwrap();
+        lo.finish().unwrap();
+
+        let read = || {
+            let mut out = vec![];
+            let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+            lo.read_to_end(&mut out).unwrap();
+            out
+        };
+
+        data[1500] = 0xff;
+        data[8000] = 0xff;
+        let stats = sync(&trans, oid, &mut Cursor::new(&data), 1000).unwrap();
+        assert_eq!(stats.bytes, 10_000);
+        assert_eq!(stats.blocks, 10);
+        assert_eq!(stats.changed_blocks, 2);
+        assert_eq!(stats.uploaded, 2000);
+        assert_eq!(read(), data);
+
+        data.truncate(4500);
+        let stats = sync(&trans, oid, &mut Cursor::new(&data), 1000).unwrap();
+        assert_eq!(stats.changed_blocks, 1);
+        assert_eq!(read(), data);
+
+        data.extend_from_slice(&[1; 1000]);
+        let stats = sync(&trans, oid, &mut Cursor::new(&data), 1000).unwrap();
+        assert_eq!(stats.changed_blocks, 2);
+        assert_eq!(read(), data);
+    }
+}
@@ -0,0 +1,194 @@
+//! Large object support for Diesel.
+//!
+//! Requires the `with-diesel` feature.
+//!
+//! Diesel manages its own connections, so this module provides a separate
+//! `DieselLargeObject` handle which issues the large object functions through
+//! a `PgConnection`. As with the rust-postgres API, large objects can only be
+//! used inside of a transaction, so handles should be opened within a call
+//! to `Connection::transaction`.
+use diesel::{self, QueryResult, RunQueryDsl};
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::{Pg, PgConnection};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Oid;
+use std::fmt;
+use std::io::{self, Seek, Write};
+
+use Mode;
+
+mod functions {
+    use diesel::sql_types::{BigInt, Bytea, Integer, Oid};
+
+    sql_function!(fn lo_create(oid: Oid) -> Oid);
+    sql_function!(fn lo_unlink(oid: Oid) -> Integer);
+    sql_function!(fn lo_open(oid: Oid, mode: Integer) -> Integer);
+    sql_function!(fn lo_close(fd: Integer) -> Integer);
+    sql_function!(fn loread(fd: Integer, len: Integer) -> Bytea);
+    sql_function!(fn lowrite(fd: Integer, data: Bytea) -> Integer);
+    sql_function!(fn lo_lseek64(fd: Integer, offset: BigInt, whence: Integer) -> BigInt);
+    sql_function!(fn lo_truncate64(fd: Integer, len: BigInt) -> Integer);
+}
+
+/// The `Oid` of a large object, for use in Diesel schemas and queries.
+///
+/// It maps to the `Oid` SQL type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, AsExpression, FromSqlRow)]
+#[sql_type = "Oid"]
+pub struct LargeObjectId(pub u32);
+
+impl ToSql<Oid, Pg> for LargeObjectId {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        ToSql::<Oid, Pg>::to_sql(&self.0, out)
+    }
+}
+
+impl FromSql<Oid, Pg> for LargeObjectId {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<LargeObjectId> {
+        <u32 as FromSql<Oid, Pg>>::from_sql(bytes).map(LargeObjectId)
+    }
+}
+
+/// Creates a new large object, returning its ID.
+pub fn create_large_object(conn: &PgConnection) -> QueryResult<LargeObjectId> {
+    diesel::select(functions::lo_create(0)).get_result(conn).map(LargeObjectId)
+}
+
+/// Deletes the large object with the specified ID.
+pub fn delete_large_object(conn: &PgConnection, id: LargeObjectId) -> QueryResult<()> {
+    diesel::select(functions::lo_unlink(id.0)).execute(conn).map(|_| ())
+}
+
+/// Opens the large object with the specified ID in the specified `Mode`.
+pub fn open_large_object(conn: &PgConnection, id: LargeObjectId, mode: Mode) -> QueryResult<DieselLargeObject> {
+    let bits = (mode & (Mode::READ | Mode::WRITE)).bits();
+    let fd: i32 = diesel::select(functions::lo_open(id.0, bits)).get_result(conn)?;
+    let append = mode.contains(Mode::APPEND);
+    if append {
+        diesel::select(functions::lo_lseek64(fd, 0i64, 2)).execute(conn)?;
+    }
+    Ok(DieselLargeObject {
+        conn: conn,
+        fd: fd,
+        append: append,
+        append_pending: false,
+        finished: false,
+    })
+}
+
+fn io_error(e: diesel::result::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// An open large object accessed through a Diesel connection.
+pub struct DieselLargeObject<'a> {
+    conn: &'a PgConnection,
+    fd: i32,
+    append: bool,
+    // set when the handle may no longer be positioned at the end
+    append_pending: bool,
+    finished: bool,
+}
+
+impl<'a> fmt::Debug for DieselLargeObject<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("DieselLargeObject")
+            .field("fd", &self.fd)
+            .finish()
+    }
+}
+
+impl<'a> Drop for DieselLargeObject<'a> {
+    fn drop(&mut self) {
+        let _ = self.finish_inner();
+    }
+}
+
+impl<'a> DieselLargeObject<'a> {
+    /// Returns the file descriptor of the opened object.
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    /// Truncates the object to the specified size.
+    ///
+    /// If `len` is larger than the size of the object, it will be padded with
+    /// null bytes to the specified size.
+    pub fn truncate(&mut self, len: i64) -> QueryResult<()> {
+        diesel::select(functions::lo_truncate64(self.fd, len))
+            .execute(self.conn)
+            .map(|_| ())
+    }
+
+    fn finish_inner(&mut self) -> QueryResult<()> {
+        if self.finished {
+            return Ok(());
+        }
+
+        self.finished = true;
+        diesel::select(functions::lo_close(self.fd))
+            .execute(self.conn)
+            .map(|_| ())
+    }
+
+    /// Consumes the `DieselLargeObject`, cleaning up server side state.
+    ///
+    /// Functionally identical to the `Drop` implementation except that it
+    /// returns any errors to the caller.
+    pub fn finish(mut self) -> QueryResult<()> {
+        self.finish_inner()
+    }
+}
+
+impl<'a> io::Read for DieselLargeObject<'a> {
+    fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
+        let cap = buf.len().min(i32::max_value() as usize) as i32;
+        let data: Vec<u8> = diesel::select(functions::loread(self.fd, cap))
+            .get_result(self.conn)
+            .map_err(io_error)?;
+        buf.write(&data)
+    }
+}
+
+impl<'a> io::Write for DieselLargeObject<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.append_pending {
+            self.seek(io::SeekFrom::End(0))?;
+            self.append_pending = false;
+        }
+        let cap = buf.len().min(i32::max_value() as usize);
+        // lowrite reports how much was actually written
+        let n: i32 = diesel::select(functions::lowrite(self.fd, &buf[..cap]))
+            .get_result(self.conn)
+            .map_err(io_error)?;
+        Ok(n as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> io::Seek for DieselLargeObject<'a> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (kind, pos) = match pos {
+            io::SeekFrom::Start(pos) => {
+                if pos > i64::max_value() as u64 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "cannot seek more than 2^63 bytes",
+                    ));
+                }
+                (0, pos as i64)
+            }
+            io::SeekFrom::Current(pos) => (1, pos),
+            io::SeekFrom::End(pos) => (2, pos),
+        };
+
+        let pos: i64 = diesel::select(functions::lo_lseek64(self.fd, pos, kind))
+            .get_result(self.conn)
+            .map_err(io_error)?;
+        self.append_pending = self.append;
+        Ok(pos as u64)
+    }
+}
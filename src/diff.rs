@@ -0,0 +1,133 @@
+//! Comparison of large objects.
+//!
+//! `diff` compares two objects in the same database. The server compares
+//! them a chunk at a time, and only the chunks which differ are transferred
+//! to find the exact ranges, so auditing copies which are mostly identical is
+//! cheap. This requires Postgres 9.4 or newer. `diff_readers` compares any
+//! two readers, such as objects in different databases, by streaming both.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::cmp;
+use std::io::{self, Read};
+use std::ops::Range;
+
+use {column, push_range, read_full, LargeObjectTransactionExt, Mode};
+use copy::CHUNK_SIZE;
+
+/// Returns the ranges of bytes which differ between the objects with the
+/// specified `Oid`s, in ascending order.
+///
+/// If one object is longer than the other, its extra bytes form the last
+/// range.
+pub fn diff(trans: &Transaction, a: Oid, b: Oid) -> Result<Vec<Range<u64>>> {
+    let size_a = trans.open_large_object(a, Mode::Read)?.size()?;
+    let size_b = trans.open_large_object(b, Mode::Read)?.size()?;
+    let stmt = trans.prepare_cached(
+        "SELECT off, chunk_a, chunk_b
+         FROM (
+             SELECT off,
+                 pg_catalog.lo_get($1, off, $3) chunk_a,
+                 pg_catalog.lo_get($2, off, $3) chunk_b
+             FROM pg_catalog.generate_series(0, $4::INT8 - 1, $5::INT8) off
+         ) chunks
+         WHERE chunk_a IS DISTINCT FROM chunk_b
+         ORDER BY off",
+    )?;
+    let rows = stmt.query(&[
+        &a,
+        &b,
+        &(CHUNK_SIZE as i32),
+        &(cmp::max(size_a, size_b) as i64),
+        &(CHUNK_SIZE as i64),
+    ])?;
+
+    let mut ranges = vec![];
+    for row in rows.iter() {
+        let off: i64 = column(&row, 0)?;
+        let chunk_a: Vec<u8> = column(&row, 1)?;
+        let chunk_b: Vec<u8> = column(&row, 2)?;
+        diff_bytes(&chunk_a, &chunk_b, off as u64, &mut ranges);
+    }
+    Ok(ranges)
+}
+
+/// Returns the ranges of bytes which differ between the contents of two
+/// readers, in ascending order.
+///
+/// If one reader is longer than the other, its extra bytes form the last
+/// range.
+pub fn diff_readers<A, B>(a: &mut A, b: &mut B) -> io::Result<Vec<Range<u64>>>
+where
+    A: ?Sized + Read,
+    B: ?Sized + Read,
+{
+    let mut ranges = vec![];
+    let mut buf_a = vec![0; CHUNK_SIZE];
+    let mut buf_b = vec![0; CHUNK_SIZE];
+    let mut off = 0;
+    loop {
+        let len_a = read_full(a, &mut buf_a)?;
+        let len_b = read_full(b, &mut buf_b)?;
+        if len_a == 0 && len_b == 0 {
+            return Ok(ranges);
+        }
+        diff_bytes(&buf_a[..len_a], &buf_b[..len_b], off, &mut ranges);
+        off += CHUNK_SIZE as u64;
+    }
+}
+
+/// Adds the ranges which differ between two chunks starting at `off`,
+/// merging them with the previous range where they touch.
+fn diff_bytes(a: &[u8], b: &[u8], off: u64, ranges: &mut Vec<Range<u64>>) {
+    let mut i = 0;
+    let common = cmp::min(a.len(), b.len());
+    while i < common {
+        if a[i] == b[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < common && a[i] != b[i] {
+            i += 1;
+        }
+        push_range(ranges, off + start as u64..off + i as u64);
+    }
+    let len = cmp::max(a.len(), b.len());
+    if common < len {
+        push_range(ranges, off + common as u64..off + len as u64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Cursor, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use diff::{diff, diff_readers};
+
+    #[test]
+    fn test_diff() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let create = |data: &[u8]| {
+            let oid = trans.create_large_object().unwrap();
+            let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+            lo.write_all(data).unwrap();
+            lo.finish().unwrap();
+            oid
+        };
+        let a = create(b"hello world!!!");
+        let b = create(b"hello WOrld!!!??");
+
+        assert_eq!(diff(&trans, a, b).unwrap(), vec![6..8, 14..16]);
+        assert!(diff(&trans, a, a).unwrap().is_empty());
+
+        let ranges = diff_readers(
+            &mut Cursor::new(b"hello world!!!"),
+            &mut Cursor::new(b"hello WOrld!!!??"),
+        ).unwrap();
+        assert_eq!(ranges, vec![6..8, 14..16]);
+    }
+}
@@ -0,0 +1,36 @@
+//! Downloads of HTTP resources into large objects.
+//!
+//! Requires the `with-reqwest` feature.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use reqwest::blocking::Client;
+use std::io;
+
+use upload::Upload;
+
+fn http_error(e: reqwest::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Downloads the resource at the specified URL into a new large object,
+/// returning its `Oid`.
+///
+/// The response body is streamed directly into the object. Responses with
+/// an error status are rejected, and no object is left behind if the
+/// download fails.
+pub fn import_from_url(trans: &Transaction, url: &str) -> Result<Oid> {
+    import_from_url_with(trans, &Client::new(), url, &Upload::new())
+}
+
+/// Like `import_from_url`, but with a configured client and upload.
+///
+/// The upload's size limit applies to the response body.
+pub fn import_from_url_with(trans: &Transaction, client: &Client, url: &str, upload: &Upload) -> Result<Oid> {
+    let response = client
+        .get(url)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .map_err(http_error)?;
+    upload.from_reader(trans, response, |_| {}).map(|u| u.oid)
+}
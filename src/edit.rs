@@ -0,0 +1,274 @@
+//! Server side editing of large objects.
+//!
+//! Where the server supports `lo_get` and `lo_put` (Postgres 9.4 or newer),
+//! data is copied between objects by the server itself in a single
+//! statement, without being transferred to the client. Older servers fall
+//! back to copying the data through the client.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+use capabilities::Capabilities;
+use copy::CHUNK_SIZE;
+
+/// Creates a new object containing `len` bytes of the object with the
+/// specified `Oid`, starting at `offset`, returning the new object's `Oid`.
+///
+/// The range is cut short if it extends past the end of the object.
+pub fn extract_range(trans: &Transaction, oid: Oid, offset: u64, len: u64) -> Result<Oid> {
+    let new = trans.create_large_object()?;
+    let size = trans.open_large_object(oid, Mode::Read)?.size()?;
+    let len = cmp::min(len, size.saturating_sub(offset));
+    copy_range(trans, oid, offset, new, len)?;
+    Ok(new)
+}
+
+/// Splits the object with the specified `Oid` into new objects of
+/// `part_size` bytes each, returning their `Oid`s in order.
+///
+/// The last part holds whatever remains, and may be shorter. An empty object
+/// has no parts. The original object is left in place.
+pub fn split(trans: &Transaction, oid: Oid, part_size: u64) -> Result<Vec<Oid>> {
+    if part_size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "part size must be nonzero").into());
+    }
+
+    let size = trans.open_large_object(oid, Mode::Read)?.size()?;
+    let mut parts = vec![];
+    let mut offset = 0;
+    while offset < size {
+        let len = cmp::min(part_size, size - offset);
+        let part = trans.create_large_object()?;
+        copy_range(trans, oid, offset, part, len)?;
+        parts.push(part);
+        offset += len;
+    }
+    Ok(parts)
+}
+
+/// Discards the first `n` bytes of the object with the specified `Oid`,
+/// shifting the rest of its contents to the start.
+///
+/// The object keeps its `Oid`, so this suits logs kept in a single object
+/// which are trimmed from the front. Discarding more bytes than the object
+/// holds empties it.
+pub fn truncate_front(trans: &Transaction, oid: Oid, n: u64) -> Result<()> {
+    let mut lo = trans.open_large_object(oid, Mode::ReadWrite)?;
+    let size = lo.size()?;
+    if n == 0 {
+        return lo.finish();
+    }
+    let len = size.saturating_sub(n);
+
+    // data only ever moves towards the start, so each chunk is read before
+    // anything is written over it
+    if len > 0 && lo.capabilities().has_lo_get() {
+        let stmt = trans.prepare_cached(
+            "SELECT pg_catalog.lo_put($1, off - $2, pg_catalog.lo_get($1, off, $3))
+             FROM pg_catalog.generate_series($2::INT8, $4::INT8 - 1, $5::INT8) off",
+        )?;
+        stmt.execute(&[
+            &oid,
+            &(n as i64),
+            &(CHUNK_SIZE as i32),
+            &(size as i64),
+            &(CHUNK_SIZE as i64),
+        ])?;
+    } else {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut off = 0;
+        while off < len {
+            let chunk = cmp::min(len - off, CHUNK_SIZE as u64) as usize;
+            lo.seek(SeekFrom::Start(off + n))?;
+            lo.read_exact(&mut buf[..chunk])?;
+            lo.seek(SeekFrom::Start(off))?;
+            lo.write_all(&buf[..chunk])?;
+            off += chunk as u64;
+        }
+    }
+
+    lo.truncate(len as i64)?;
+    lo.finish()
+}
+
+/// Writes each of a list of `(offset, data)` patches into the object with
+/// the specified `Oid`.
+///
+/// Patches are applied in order, so later patches win where they overlap.
+/// Writing past the end of the object extends it, filling any gap with null
+/// bytes. On Postgres 9.4 or newer, many patches are sent in each statement.
+pub fn apply_patches<B>(trans: &Transaction, oid: Oid, patches: &[(u64, B)]) -> Result<()>
+where
+    B: AsRef<[u8]>,
+{
+    if Capabilities::detect_cached(trans.connection())?.has_lo_get() {
+        let stmt = trans.prepare_cached(
+            "SELECT pg_catalog.lo_put($1, p.off, p.data)
+             FROM pg_catalog.unnest($2::INT8[], $3::BYTEA[]) WITH ORDINALITY AS p(off, data, n)
+             ORDER BY p.n",
+        )?;
+        let mut rest = patches;
+        while !rest.is_empty() {
+            // batch up patches until they add up to a chunk
+            let mut bytes = 0;
+            let mut n = 0;
+            while n < rest.len() && (n == 0 || bytes + rest[n].1.as_ref().len() <= CHUNK_SIZE) {
+                bytes += rest[n].1.as_ref().len();
+                n += 1;
+            }
+            let offsets = rest[..n].iter().map(|p| p.0 as i64).collect::<Vec<_>>();
+            let data = rest[..n].iter().map(|p| p.1.as_ref()).collect::<Vec<_>>();
+            stmt.execute(&[&oid, &offsets, &data])?;
+            rest = &rest[n..];
+        }
+        return Ok(());
+    }
+
+    let mut lo = trans.open_large_object(oid, Mode::Write)?;
+    for &(offset, ref data) in patches {
+        lo.seek(SeekFrom::Start(offset))?;
+        lo.write_all(data.as_ref())?;
+    }
+    lo.finish()
+}
+
+/// Copies `len` bytes from `src` starting at `offset` to the start of `dst`.
+///
+/// The range must lie within `src`.
+fn copy_range(trans: &Transaction, src: Oid, offset: u64, dst: Oid, len: u64) -> Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    if Capabilities::detect_cached(trans.connection())?.has_lo_get() {
+        let stmt = trans.prepare_cached(
+            "SELECT pg_catalog.lo_put($3, off - $2,
+                 pg_catalog.lo_get($1, off, LEAST($4, $2 + $5 - off)::INT4))
+             FROM pg_catalog.generate_series($2::INT8, $2::INT8 + $5::INT8 - 1, $4::INT8) off",
+        )?;
+        stmt.execute(&[
+            &src,
+            &(offset as i64),
+            &dst,
+            &(CHUNK_SIZE as i64),
+            &(len as i64),
+        ])?;
+        return Ok(());
+    }
+
+    let mut src = trans.open_large_object(src, Mode::Read)?;
+    src.seek(SeekFrom::Start(offset))?;
+    let mut dst = trans.open_large_object(dst, Mode::Write)?;
+    io::copy(&mut (&mut src).take(len), &mut dst)?;
+    dst.finish()?;
+    src.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use edit::{apply_patches, extract_range, split, truncate_front};
+
+    #[test]
+    fn test_extract_range() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.finish().unwrap();
+
+        let read = |oid| {
+            let mut out = String::new();
+            let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+            lo.read_to_string(&mut out).unwrap();
+            out
+        };
+
+        let part = extract_range(&trans, oid, 6, 5).unwrap();
+        assert_eq!(read(part), "world");
+        let part = extract_range(&trans, oid, 11, 100).unwrap();
+        assert_eq!(read(part), "!!!");
+        let part = extract_range(&trans, oid, 100, 5).unwrap();
+        assert_eq!(read(part), "");
+    }
+
+    #[test]
+    fn test_split() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        assert!(split(&trans, oid, 5).unwrap().is_empty());
+
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.finish().unwrap();
+
+        let parts = split(&trans, oid, 5)
+            .unwrap()
+            .into_iter()
+            .map(|oid| {
+                let mut out = String::new();
+                let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+                lo.read_to_string(&mut out).unwrap();
+                out
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(parts, ["hello", " worl", "d!!!"]);
+        assert!(split(&trans, oid, 0).is_err());
+    }
+
+    #[test]
+    fn test_apply_patches() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.finish().unwrap();
+
+        let patches = [
+            (0, &b"HELLO"[..]),
+            (6, &b"WORLD"[..]),
+            (7, &b"o"[..]),
+            (16, &b"?"[..]),
+        ];
+        apply_patches(&trans, oid, &patches).unwrap();
+
+        let mut out = vec![];
+        let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"HELLO WoRLD!!!\0\0?");
+    }
+
+    #[test]
+    fn test_truncate_front() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let data = (0..1_000_000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(&data).unwrap();
+        lo.finish().unwrap();
+
+        let read = || {
+            let mut out = vec![];
+            let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+            lo.read_to_end(&mut out).unwrap();
+            out
+        };
+
+        truncate_front(&trans, oid, 1000).unwrap();
+        assert_eq!(read(), &data[1000..]);
+        truncate_front(&trans, oid, 0).unwrap();
+        assert_eq!(read(), &data[1000..]);
+        truncate_front(&trans, oid, 2_000_000).unwrap();
+        assert!(read().is_empty());
+    }
+}
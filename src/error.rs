@@ -0,0 +1,192 @@
+//! A classified error type.
+//!
+//! The crate reports errors as `postgres::Error`s, or as `io::Error`s from
+//! the `Read`, `Write` and `Seek` implementations, and the cause of a failure
+//! can end up buried several layers deep in either. Converting one into an
+//! `Error` sorts it into a variant which can be matched on:
+//!
+//! ```rust,no_run
+//! # extern crate postgres;
+//! # extern crate postgres_large_object;
+//! # use postgres::{Connection, TlsMode};
+//! # use postgres_large_object::{LargeObjectTransactionExt, Mode};
+//! use postgres_large_object::error::Error;
+//!
+//! # fn main() {
+//! # let conn = Connection::connect("", TlsMode::None).unwrap();
+//! # let trans = conn.transaction().unwrap();
+//! match trans.open_large_object(1234, Mode::Read).map_err(Error::from) {
+//!     Ok(lo) => {}
+//!     Err(Error::Unsupported(e)) => println!("giving up: {}", e),
+//!     Err(e) => panic!("{}", e),
+//! }
+//! # }
+//! ```
+//!
+//! `Error` converts back into both `postgres::Error` and `io::Error`.
+use postgres;
+use postgres::error::SqlState;
+use std::error;
+use std::fmt;
+use std::io;
+
+use capabilities::Unsupported;
+
+/// The possible causes of a failed large object operation.
+#[derive(Debug)]
+pub enum Error {
+    /// An error reported by the server, or by the `postgres` crate.
+    Sql(postgres::Error),
+    /// The server lacks the functionality required by the operation.
+    Unsupported(Unsupported),
+    /// An argument to the operation was invalid.
+    InvalidArgument(String),
+    /// An I/O error, including errors communicating with the server.
+    Io(io::Error),
+}
+
+impl Error {
+    /// Returns the SQLSTATE of an error reported by the server.
+    pub fn code(&self) -> Option<&SqlState> {
+        match *self {
+            Error::Sql(ref e) => e.code(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Sql(ref e) => fmt::Display::fmt(e, fmt),
+            Error::Unsupported(ref e) => fmt::Display::fmt(e, fmt),
+            Error::InvalidArgument(ref s) => write!(fmt, "invalid argument: {}", s),
+            Error::Io(ref e) => fmt::Display::fmt(e, fmt),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Sql(ref e) => Some(e),
+            Error::Unsupported(ref e) => Some(e),
+            Error::InvalidArgument(_) => None,
+            Error::Io(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<postgres::Error> for Error {
+    fn from(e: postgres::Error) -> Error {
+        if e.as_io().is_some() {
+            Error::from(io::Error::from(e))
+        } else {
+            Error::Sql(e)
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        if e.get_ref().is_none() {
+            return Error::Io(e);
+        }
+
+        let kind = e.kind();
+        let inner = e.into_inner().unwrap();
+        let inner = match inner.downcast::<Unsupported>() {
+            Ok(e) => return Error::Unsupported(*e),
+            Err(inner) => inner,
+        };
+        // postgres errors are wrapped when returned through the io traits
+        let inner = match inner.downcast::<postgres::Error>() {
+            Ok(e) => return Error::from(*e),
+            Err(inner) => inner,
+        };
+        if kind == io::ErrorKind::InvalidInput {
+            Error::InvalidArgument(inner.to_string())
+        } else {
+            Error::Io(io::Error::new(kind, inner))
+        }
+    }
+}
+
+impl From<Unsupported> for Error {
+    fn from(e: Unsupported) -> Error {
+        Error::Unsupported(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Sql(e) => io::Error::from(e),
+            Error::Unsupported(e) => io::Error::from(e),
+            Error::InvalidArgument(s) => io::Error::new(io::ErrorKind::InvalidInput, s),
+            Error::Io(e) => e,
+        }
+    }
+}
+
+impl From<Error> for postgres::Error {
+    fn from(e: Error) -> postgres::Error {
+        match e {
+            Error::Sql(e) => e,
+            e => io::Error::from(e).into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::error::UNDEFINED_OBJECT;
+    use postgres::{Connection, TlsMode};
+    use std::io::{self, Read};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode, OpenOptions};
+    use capabilities::Capabilities;
+    use error::Error;
+
+    #[test]
+    fn test_classify() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        trans.delete_large_object(oid).unwrap();
+        let e = Error::from(trans.open_large_object(oid, Mode::Read).unwrap_err());
+        assert_eq!(e.code(), Some(&UNDEFINED_OBJECT));
+        drop(trans);
+
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        let e = Error::from(lo.read(&mut [0; 10]).unwrap_err());
+        assert!(e.code().is_some());
+        drop(lo);
+        drop(trans);
+
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut caps = Capabilities::all();
+        caps.set_64_bit(false);
+        let mut lo = OpenOptions::new()
+            .capabilities(caps)
+            .open(&trans, oid, Mode::Write)
+            .unwrap();
+        match Error::from(lo.truncate(1 << 32).unwrap_err()) {
+            Error::Unsupported(_) => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let e = io::Error::new(io::ErrorKind::InvalidInput, "bad offset");
+        match Error::from(e) {
+            Error::InvalidArgument(ref s) if s == "bad offset" => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        match Error::from(io::Error::from(io::ErrorKind::UnexpectedEof)) {
+            Error::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Entity tags for large objects.
+//!
+//! Tags are computed on demand from MD5 digests of the object's contents.
+//! The digests are computed server side in chunks, so the object does not
+//! need to be transferred to answer a conditional request. This requires
+//! Postgres 9.4 or newer.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+
+use {first_column, LargeObjectTransactionExt, Mode};
+use copy::CHUNK_SIZE;
+
+/// Returns a strong entity tag for the contents of the large object with the
+/// specified `Oid`.
+///
+/// The tag is quoted, ready for use as the value of an `ETag` header.
+pub fn etag(trans: &Transaction, oid: Oid) -> Result<String> {
+    let size = trans.open_large_object(oid, Mode::Read)?.size()? as i64;
+    let stmt = trans.prepare_cached(
+        "SELECT pg_catalog.md5(COALESCE(pg_catalog.string_agg(
+             pg_catalog.md5(pg_catalog.lo_get($1, off, $3)), '' ORDER BY off), ''))
+         FROM pg_catalog.generate_series(0, $2::INT8 - 1, $4::INT8) off",
+    )?;
+    let len = CHUNK_SIZE as i32;
+    let rows = stmt.query(&[&oid, &size, &len, &(len as i64)])?;
+    let digest: String = first_column(&rows)?;
+    Ok(format!("\"{:x}-{}\"", size, digest))
+}
+
+/// Determines if the large object with the specified `Oid` has changed
+/// relative to the value of an `If-None-Match` header.
+///
+/// The header may contain a list of tags, or `*`. Weak tags are compared
+/// weakly, as required for `If-None-Match`. If this returns `false`, a 304
+/// response can be sent in place of the object.
+pub fn changed_since(trans: &Transaction, oid: Oid, if_none_match: &str) -> Result<bool> {
+    let if_none_match = if_none_match.trim();
+    if if_none_match == "*" {
+        return Ok(false);
+    }
+
+    let current = etag(trans, oid)?;
+    let matched = if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .map(|tag| if tag.starts_with("W/") { &tag[2..] } else { tag })
+        .any(|tag| tag == current);
+    Ok(!matched)
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::Write;
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use etag::{changed_since, etag};
+
+    #[test]
+    fn test_etag() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let empty = etag(&trans, oid).unwrap();
+
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        let tag = etag(&trans, oid).unwrap();
+        assert!(tag != empty);
+        assert_eq!(tag, etag(&trans, oid).unwrap());
+
+        assert!(!changed_since(&trans, oid, &tag).unwrap());
+        assert!(!changed_since(&trans, oid, &format!("\"foo\", W/{}", tag)).unwrap());
+        assert!(!changed_since(&trans, oid, "*").unwrap());
+        assert!(changed_since(&trans, oid, &empty).unwrap());
+    }
+}
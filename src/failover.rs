@@ -0,0 +1,242 @@
+//! Reads which survive the loss of a server.
+//!
+//! A `FailoverReader` is given a list of servers holding copies of the same
+//! database, such as a set of streaming replicas, and reads from the first
+//! one which is available. If its connection is lost partway through, it
+//! transparently reconnects to the next server and carries on from the same
+//! position.
+//!
+//! Reads are made with `lo_get` outside of an explicit transaction, so there
+//! is no handle to reopen after a failover, but Postgres 9.4 or newer is
+//! required. Each read sees the latest committed state of the object on the
+//! server it is sent to, so objects should not be modified while they are
+//! being read.
+use postgres::{Connection, Result};
+use postgres::types::Oid;
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use {bytes_column, first_row, seek_position, LargeObjectTransactionExt, Mode};
+use retry::is_transient;
+
+/// The default maximum number of bytes fetched per read.
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// A reader which fails over between servers.
+pub struct FailoverReader<F> {
+    connect: F,
+    targets: Vec<String>,
+    conn: Option<Connection>,
+    // the index of the current or next target
+    target: usize,
+    oid: Oid,
+    pos: u64,
+    size: u64,
+    chunk_size: usize,
+}
+
+impl<F> fmt::Debug for FailoverReader<F> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("FailoverReader")
+            .field("targets", &self.targets)
+            .field("conn", &self.conn)
+            .field("target", &self.target)
+            .field("oid", &self.oid)
+            .field("pos", &self.pos)
+            .field("size", &self.size)
+            .field("chunk_size", &self.chunk_size)
+            .finish()
+    }
+}
+
+impl<F> FailoverReader<F>
+where
+    F: Fn(&str) -> Result<Connection>,
+{
+    /// Opens the large object with the specified `Oid` on the first available
+    /// server.
+    ///
+    /// `targets` are connection strings, in order of preference, and
+    /// `connect` is called with one of them whenever the reader needs a new
+    /// connection, so it decides how TLS is negotiated. Fails if none of the
+    /// targets can be connected to.
+    pub fn new<I>(targets: I, oid: Oid, connect: F) -> Result<FailoverReader<F>>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let targets = targets.into_iter().map(Into::into).collect::<Vec<_>>();
+        if targets.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no targets provided").into());
+        }
+
+        let mut reader = FailoverReader {
+            connect: connect,
+            targets: targets,
+            conn: None,
+            target: 0,
+            oid: oid,
+            pos: 0,
+            size: 0,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        };
+        let (conn, size) = reader.connect_any(None)?;
+        reader.conn = Some(conn);
+        reader.size = size;
+        Ok(reader)
+    }
+
+    /// Sets the maximum number of bytes fetched per read.
+    ///
+    /// Defaults to `DEFAULT_CHUNK_SIZE`.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = cmp::max(chunk_size, 1);
+    }
+
+    /// Returns the connection string of the server currently being read
+    /// from, if connected.
+    pub fn current_target(&self) -> Option<&str> {
+        match self.conn {
+            Some(_) => Some(&self.targets[self.target]),
+            None => None,
+        }
+    }
+
+    /// Returns the size of the object in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Connects to the first available target, starting from the current
+    /// one, returning the connection and the size of the object there.
+    ///
+    /// If `size` is provided, servers on which the object has a different
+    /// size, such as a lagging replica, are skipped.
+    fn connect_any(&mut self, size: Option<u64>) -> Result<(Connection, u64)> {
+        let mut last_error = None;
+        for _ in 0..self.targets.len() {
+            match self.try_connect(size) {
+                Ok(r) => return Ok(r),
+                Err(e) => last_error = Some(e),
+            }
+            self.target = (self.target + 1) % self.targets.len();
+        }
+        Err(last_error.unwrap())
+    }
+
+    fn try_connect(&self, size: Option<u64>) -> Result<(Connection, u64)> {
+        let conn = (self.connect)(&self.targets[self.target])?;
+        let actual = {
+            let trans = conn.transaction()?;
+            let mut lo = trans.open_large_object(self.oid, Mode::Read)?;
+            lo.size()?
+        };
+        match size {
+            Some(size) if size != actual => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "large object {} has a different size on {}",
+                    self.oid,
+                    self.targets[self.target]
+                ),
+            ).into()),
+            _ => Ok((conn, actual)),
+        }
+    }
+
+    fn read_chunk(&mut self, len: usize) -> Result<Vec<u8>> {
+        // one attempt per target, plus one on the server we started with
+        for attempt in 0..self.targets.len() + 1 {
+            if self.conn.is_none() {
+                let size = Some(self.size);
+                let (conn, _) = self.connect_any(size)?;
+                self.conn = Some(conn);
+            }
+
+            let r = {
+                let conn = self.conn.as_ref().unwrap();
+                conn.prepare_cached("SELECT pg_catalog.lo_get($1, $2, $3)")
+                    .and_then(|stmt| {
+                        stmt.query(&[&self.oid, &(self.pos as i64), &(len as i32)])
+                    })
+                    .and_then(|rows| Ok(bytes_column(&first_row(&rows)?, 0)?.to_vec()))
+            };
+            match r {
+                Err(ref e) if is_transient(e) && attempt < self.targets.len() => {
+                    self.conn = None;
+                    self.target = (self.target + 1) % self.targets.len();
+                }
+                r => return r,
+            }
+        }
+        unreachable!()
+    }
+}
+
+impl<F> Read for FailoverReader<F>
+where
+    F: Fn(&str) -> Result<Connection>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.size {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len(), self.chunk_size);
+        let data = self.read_chunk(len)?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+impl<F> Seek for FailoverReader<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => {
+                self.pos = pos;
+                return Ok(pos);
+            }
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (self.size, offset),
+        };
+
+        self.pos = seek_position(base, offset)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use failover::FailoverReader;
+
+    #[test]
+    fn test_failover_reader() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let oid = conn.create_large_object().unwrap();
+        {
+            let trans = conn.transaction().unwrap();
+            let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+            lo.write_all(b"hello world!!!").unwrap();
+            lo.finish().unwrap();
+            trans.commit().unwrap();
+        }
+
+        let targets = ["postgres://postgres@localhost:1", "postgres://postgres@localhost"];
+        let connect = |target: &str| Connection::connect(target, TlsMode::None);
+        let mut reader = FailoverReader::new(targets.to_vec(), oid, connect).unwrap();
+        assert_eq!(reader.current_target(), Some(targets[1]));
+        assert_eq!(reader.size(), 14);
+        reader.set_chunk_size(4);
+        reader.seek(SeekFrom::Start(6)).unwrap();
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "world!!!");
+
+        conn.delete_large_object(oid).unwrap();
+    }
+}
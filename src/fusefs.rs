@@ -0,0 +1,276 @@
+//! A FUSE filesystem exposing large objects as files.
+//!
+//! Requires the `with-fuse` feature.
+//!
+//! The root directory of the mounted filesystem contains one regular file per
+//! large object, named by the decimal representation of its `Oid`. Files can
+//! be read, written, truncated, and removed, and new objects can be created by
+//! creating a file whose name is an unused `Oid`. Each operation runs in its
+//! own transaction.
+use fuse::{self, FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData,
+           ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyWrite, Request};
+use libc;
+use postgres::{Connection, Error, Result};
+use postgres::error::UNDEFINED_OBJECT;
+use postgres::types::Oid;
+use std::ffi::OsStr;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use time::Timespec;
+
+use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+
+const ROOT_INO: u64 = 1;
+
+const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
+fn oid_to_ino(oid: Oid) -> u64 {
+    oid as u64 + 1
+}
+
+fn ino_to_oid(ino: u64) -> Option<Oid> {
+    if ino > ROOT_INO && ino - 1 <= Oid::max_value() as u64 {
+        Some((ino - 1) as Oid)
+    } else {
+        None
+    }
+}
+
+fn errno(e: &Error) -> libc::c_int {
+    if e.code() == Some(&UNDEFINED_OBJECT) {
+        libc::ENOENT
+    } else {
+        libc::EIO
+    }
+}
+
+fn attr(ino: u64, kind: FileType, size: u64) -> FileAttr {
+    let epoch = Timespec { sec: 0, nsec: 0 };
+    FileAttr {
+        ino: ino,
+        size: size,
+        blocks: (size + 511) / 512,
+        atime: epoch,
+        mtime: epoch,
+        ctime: epoch,
+        crtime: epoch,
+        kind: kind,
+        perm: if kind == FileType::Directory { 0o755 } else { 0o644 },
+        nlink: if kind == FileType::Directory { 2 } else { 1 },
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+/// A FUSE filesystem backed by the large objects of a database.
+#[derive(Debug)]
+pub struct LargeObjectFs {
+    conn: Connection,
+}
+
+impl LargeObjectFs {
+    /// Creates a new filesystem which accesses large objects through the
+    /// specified connection.
+    pub fn new(conn: Connection) -> LargeObjectFs {
+        LargeObjectFs { conn: conn }
+    }
+
+    /// Mounts the filesystem at the specified path, blocking until it is
+    /// unmounted.
+    pub fn mount<P>(self, mountpoint: P) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        fuse::mount(self, &mountpoint, &[])
+    }
+
+    fn size(&self, oid: Oid) -> Result<u64> {
+        let trans = self.conn.transaction()?;
+        let size = trans.open_large_object(oid, Mode::Read)?.size()?;
+        Ok(size)
+    }
+
+    fn read_at(&self, oid: Oid, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let trans = self.conn.transaction()?;
+        let mut lo = trans.open_large_object(oid, Mode::Read)?;
+        lo.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![];
+        (&mut lo).take(len as u64).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_at(&self, oid: Oid, offset: u64, data: &[u8]) -> Result<()> {
+        let trans = self.conn.transaction()?;
+        {
+            let mut lo = trans.open_large_object(oid, Mode::Write)?;
+            lo.seek(SeekFrom::Start(offset))?;
+            lo.write_all(data)?;
+            lo.finish()?;
+        }
+        trans.commit()
+    }
+
+    fn truncate(&self, oid: Oid, len: u64) -> Result<()> {
+        let trans = self.conn.transaction()?;
+        {
+            let mut lo = trans.open_large_object(oid, Mode::Write)?;
+            lo.truncate(len as i64)?;
+            lo.finish()?;
+        }
+        trans.commit()
+    }
+}
+
+impl Filesystem for LargeObjectFs {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let oid = match name.to_str().and_then(|s| s.parse::<Oid>().ok()) {
+            Some(oid) if parent == ROOT_INO => oid,
+            _ => return reply.error(libc::ENOENT),
+        };
+
+        match self.size(oid) {
+            Ok(size) => reply.entry(&TTL, &attr(oid_to_ino(oid), FileType::RegularFile, size), 0),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        if ino == ROOT_INO {
+            return reply.attr(&TTL, &attr(ROOT_INO, FileType::Directory, 0));
+        }
+
+        let oid = match ino_to_oid(ino) {
+            Some(oid) => oid,
+            None => return reply.error(libc::ENOENT),
+        };
+        match self.size(oid) {
+            Ok(size) => reply.attr(&TTL, &attr(ino, FileType::RegularFile, size)),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<Timespec>,
+        _mtime: Option<Timespec>,
+        _fh: Option<u64>,
+        _crtime: Option<Timespec>,
+        _chgtime: Option<Timespec>,
+        _bkuptime: Option<Timespec>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let oid = match ino_to_oid(ino) {
+            Some(oid) => oid,
+            None => return reply.error(libc::EPERM),
+        };
+
+        let result = match size {
+            Some(size) => self.truncate(oid, size).and_then(|()| self.size(oid)),
+            None => self.size(oid),
+        };
+        match result {
+            Ok(size) => reply.attr(&TTL, &attr(ino, FileType::RegularFile, size)),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        if ino != ROOT_INO {
+            return reply.error(libc::ENOTDIR);
+        }
+
+        let oids = match self.conn.list_large_objects() {
+            Ok(oids) => oids,
+            Err(e) => return reply.error(errno(&e)),
+        };
+
+        let entries = [
+            (ROOT_INO, FileType::Directory, ".".to_owned()),
+            (ROOT_INO, FileType::Directory, "..".to_owned()),
+        ];
+        let entries = entries
+            .iter()
+            .cloned()
+            .chain(oids.iter().map(|&oid| (oid_to_ino(oid), FileType::RegularFile, oid.to_string())));
+        for (i, (ino, kind, name)) in entries.enumerate().skip(offset as usize) {
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        let oid = match ino_to_oid(ino) {
+            Some(oid) => oid,
+            None => return reply.error(libc::EISDIR),
+        };
+
+        match self.read_at(oid, offset as u64, size as usize) {
+            Ok(buf) => reply.data(&buf),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let oid = match ino_to_oid(ino) {
+            Some(oid) => oid,
+            None => return reply.error(libc::EISDIR),
+        };
+
+        match self.write_at(oid, offset as u64, data) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _flags: u32,
+        reply: ReplyCreate,
+    ) {
+        let oid = match name.to_str().and_then(|s| s.parse::<Oid>().ok()) {
+            Some(oid) if parent == ROOT_INO && oid != 0 => oid,
+            _ => return reply.error(libc::EINVAL),
+        };
+
+        match self.conn.create_large_object_with_oid(oid) {
+            Ok(()) => reply.created(&TTL, &attr(oid_to_ino(oid), FileType::RegularFile, 0), 0, 0, 0),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let oid = match name.to_str().and_then(|s| s.parse::<Oid>().ok()) {
+            Some(oid) if parent == ROOT_INO => oid,
+            _ => return reply.error(libc::ENOENT),
+        };
+
+        match self.conn.delete_large_object(oid) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Storage of payloads inline or in large objects depending on their size.
+//!
+//! Every large object carries some overhead in the catalogs, which dominates
+//! for very small payloads. A `HybridStore` keeps payloads at or below a size
+//! threshold in a `bytea` column, and only uses large objects for larger
+//! ones, while presenting a single interface for both.
+use postgres::{GenericConnection, Result};
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::io::{self, Read, Seek, Write};
+
+use {column, first_column, first_row, quote_identifier, LargeObject, LargeObjectExt,
+     LargeObjectTransactionExt, Mode};
+
+/// A store of payloads in a table, holding small payloads inline and larger
+/// ones as large objects.
+#[derive(Debug, Clone)]
+pub struct HybridStore {
+    table: String,
+    threshold: usize,
+}
+
+impl HybridStore {
+    /// Creates a new store backed by the specified table.
+    ///
+    /// The name is quoted, so it must match the case of the table exactly.
+    pub fn new(table: &str) -> HybridStore {
+        HybridStore {
+            table: table.to_owned(),
+            threshold: 64 * 1024,
+        }
+    }
+
+    /// Sets the size in bytes of the largest payload which will be stored
+    /// inline.
+    ///
+    /// Defaults to 64 KiB.
+    pub fn threshold(&mut self, threshold: usize) -> &mut HybridStore {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Creates the store's table if it does not already exist.
+    pub fn install<C: GenericConnection>(&self, conn: &C) -> Result<()> {
+        conn.batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                 id BIGSERIAL PRIMARY KEY,
+                 data BYTEA,
+                 oid OID,
+                 CHECK ((data IS NULL) <> (oid IS NULL))
+             )",
+            quote_identifier(&self.table)
+        ))
+    }
+
+    /// Stores the contents of a reader, returning the payload's ID.
+    pub fn put<R>(&self, trans: &Transaction, data: &mut R) -> Result<i64>
+    where
+        R: Read,
+    {
+        let mut buf = vec![];
+        data.by_ref().take(self.threshold as u64 + 1).read_to_end(&mut buf)?;
+
+        if buf.len() <= self.threshold {
+            let stmt = trans.prepare_cached(&format!(
+                "INSERT INTO {} (data) VALUES ($1) RETURNING id",
+                quote_identifier(&self.table)
+            ))?;
+            let rows = stmt.query(&[&buf])?;
+            return first_column(&rows);
+        }
+
+        let oid = trans.create_large_object()?;
+        {
+            let mut lo = trans.open_large_object(oid, Mode::Write)?;
+            lo.write_all(&buf)?;
+            io::copy(data, &mut lo)?;
+            lo.finish()?;
+        }
+
+        let stmt = trans.prepare_cached(&format!(
+            "INSERT INTO {} (oid) VALUES ($1) RETURNING id",
+            quote_identifier(&self.table)
+        ))?;
+        let rows = stmt.query(&[&oid])?;
+        first_column(&rows)
+    }
+
+    /// Opens the payload with the specified ID for reading.
+    pub fn get<'a>(&self, trans: &'a Transaction, id: i64) -> Result<HybridObject<'a>> {
+        let stmt = trans.prepare_cached(&format!(
+            "SELECT data, oid FROM {} WHERE id = $1",
+            quote_identifier(&self.table)
+        ))?;
+        let rows = stmt.query(&[&id])?;
+        if rows.is_empty() {
+            return Err(not_found(id).into());
+        }
+
+        let row = first_row(&rows)?;
+        match column::<Option<Vec<u8>>>(&row, 0)? {
+            Some(data) => Ok(HybridObject::Inline(io::Cursor::new(data))),
+            None => {
+                let oid: Oid = column(&row, 1)?;
+                trans.open_large_object(oid, Mode::Read).map(HybridObject::Large)
+            }
+        }
+    }
+
+    /// Deletes the payload with the specified ID, along with its large
+    /// object if it has one.
+    pub fn delete(&self, trans: &Transaction, id: i64) -> Result<()> {
+        let stmt = trans.prepare_cached(&format!(
+            "DELETE FROM {} WHERE id = $1 RETURNING oid",
+            quote_identifier(&self.table)
+        ))?;
+        let rows = stmt.query(&[&id])?;
+        if rows.is_empty() {
+            return Err(not_found(id).into());
+        }
+
+        match first_column::<Option<Oid>>(&rows)? {
+            Some(oid) => trans.delete_large_object(oid),
+            None => Ok(()),
+        }
+    }
+}
+
+fn not_found(id: i64) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no payload with ID {}", id))
+}
+
+/// A payload opened from a `HybridStore`.
+#[derive(Debug)]
+pub enum HybridObject<'a> {
+    /// A payload stored inline.
+    Inline(io::Cursor<Vec<u8>>),
+    /// A payload stored in a large object.
+    Large(LargeObject<'a>),
+}
+
+impl<'a> Read for HybridObject<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            HybridObject::Inline(ref mut c) => c.read(buf),
+            HybridObject::Large(ref mut lo) => lo.read(buf),
+        }
+    }
+}
+
+impl<'a> Seek for HybridObject<'a> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match *self {
+            HybridObject::Inline(ref mut c) => c.seek(pos),
+            HybridObject::Large(ref mut lo) => lo.seek(pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::Read;
+
+    use hybrid::{HybridObject, HybridStore};
+
+    #[test]
+    fn test_put_get_delete() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let mut store = HybridStore::new("hybrid_store_test");
+        store.threshold(5);
+        store.install(&trans).unwrap();
+
+        let small = store.put(&trans, &mut &b"hello"[..]).unwrap();
+        let large = store.put(&trans, &mut &b"hello world!!!"[..]).unwrap();
+
+        let mut out = vec![];
+        let mut obj = store.get(&trans, small).unwrap();
+        match obj {
+            HybridObject::Inline(_) => {}
+            HybridObject::Large(_) => panic!("expected an inline payload"),
+        }
+        obj.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello");
+
+        out.clear();
+        let mut obj = store.get(&trans, large).unwrap();
+        match obj {
+            HybridObject::Inline(_) => panic!("expected a large object payload"),
+            HybridObject::Large(_) => {}
+        }
+        obj.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello world!!!");
+        drop(obj);
+
+        store.delete(&trans, large).unwrap();
+        assert!(store.get(&trans, large).is_err());
+    }
+}
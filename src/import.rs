@@ -0,0 +1,83 @@
+//! Bulk import of files as large objects.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+
+/// Imports every file in the specified directory and its subdirectories as a
+/// new large object.
+///
+/// Each file's contents are streamed into the new object. A map from each
+/// file's path (the directory path joined with the file's location inside
+/// of it) to the `Oid` of its object is returned.
+pub fn import_directory<P>(trans: &Transaction, path: P) -> Result<HashMap<PathBuf, Oid>>
+where
+    P: AsRef<Path>,
+{
+    let mut oids = HashMap::new();
+    import_directory_inner(trans, path.as_ref(), &mut oids)?;
+    Ok(oids)
+}
+
+fn import_directory_inner(
+    trans: &Transaction,
+    path: &Path,
+    oids: &mut HashMap<PathBuf, Oid>,
+) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            import_directory_inner(trans, &path, oids)?;
+            continue;
+        }
+
+        let mut file = File::open(&path)?;
+        let oid = trans.create_large_object()?;
+        let mut lo = trans.open_large_object(oid, Mode::Write)?;
+        io::copy(&mut file, &mut lo)?;
+        lo.finish()?;
+        oids.insert(path, oid);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+
+    use {LargeObjectTransactionExt, Mode};
+    use import::import_directory;
+
+    #[test]
+    fn test_import_directory() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+
+        let dir = env::temp_dir().join("postgres_large_object_import");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        File::create(dir.join("a")).unwrap().write_all(b"hello").unwrap();
+        File::create(dir.join("nested").join("b")).unwrap().write_all(b"world").unwrap();
+
+        let oids = import_directory(&trans, &dir).unwrap();
+        assert_eq!(oids.len(), 2);
+
+        let mut out = vec![];
+        let oid = oids[&dir.join("nested").join("b")];
+        let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"world");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+//! Chunk-level integrity records for large objects.
+//!
+//! Verifying a multi-gigabyte object against a single digest only says that
+//! something is wrong, not where. This module records an MD5 digest of each
+//! chunk of an object in a side table, `large_object_chunk_digests`, along
+//! with a Merkle root of those digests in `large_object_digests`. A later
+//! check identifies the byte ranges of the chunks which no longer match, so
+//! only those need to be re-transferred.
+//!
+//! The digests detect corruption, not tampering. Requires Postgres 9.5 or
+//! newer.
+use md5;
+use postgres::{GenericConnection, Result};
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::cmp;
+use std::io::{self, Read};
+use std::ops::Range;
+
+use {push_range, read_full, LargeObjectTransactionExt, Mode};
+use delta::block_digests;
+
+/// The default size of the chunks digested.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Creates the integrity tables if they do not already exist.
+pub fn install<C: GenericConnection>(conn: &C) -> Result<()> {
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS large_object_digests (
+             oid OID PRIMARY KEY,
+             chunk_size INT4 NOT NULL,
+             size INT8 NOT NULL,
+             root TEXT NOT NULL
+         );
+         CREATE TABLE IF NOT EXISTS large_object_chunk_digests (
+             oid OID NOT NULL,
+             chunk INT8 NOT NULL,
+             digest TEXT NOT NULL,
+             PRIMARY KEY (oid, chunk)
+         )",
+    )
+}
+
+/// Digests the object with the specified `Oid` server side in chunks of
+/// `chunk_size` bytes, replacing any existing record for it.
+///
+/// Returns the Merkle root of the chunk digests.
+pub fn record(trans: &Transaction, oid: Oid, chunk_size: usize) -> Result<String> {
+    let chunk_size = cmp::max(chunk_size, 1);
+    let size = trans.open_large_object(oid, Mode::Read)?.size()?;
+    let digests = block_digests(trans, oid, chunk_size)?;
+    let root = merkle_root(&digests);
+
+    let stmt = trans.prepare_cached("DELETE FROM large_object_chunk_digests WHERE oid = $1")?;
+    stmt.execute(&[&oid])?;
+    let stmt = trans.prepare_cached(
+        "INSERT INTO large_object_chunk_digests (oid, chunk, digest)
+         SELECT $1, n - 1, d FROM pg_catalog.unnest($2::TEXT[]) WITH ORDINALITY AS t (d, n)",
+    )?;
+    stmt.execute(&[&oid, &digests])?;
+
+    let stmt = trans.prepare_cached(
+        "INSERT INTO large_object_digests (oid, chunk_size, size, root)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT (oid) DO UPDATE
+         SET chunk_size = EXCLUDED.chunk_size, size = EXCLUDED.size, root = EXCLUDED.root",
+    )?;
+    stmt.execute(&[&oid, &(chunk_size as i32), &(size as i64), &root])?;
+    Ok(root)
+}
+
+/// Returns the recorded Merkle root of the object with the specified `Oid`,
+/// if one has been recorded.
+pub fn root<C: GenericConnection>(conn: &C, oid: Oid) -> Result<Option<String>> {
+    let stmt = conn.prepare_cached("SELECT root FROM large_object_digests WHERE oid = $1")?;
+    let rows = stmt.query(&[&oid])?;
+    Ok(rows.iter().next().map(|r| r.get(0)))
+}
+
+/// Removes the record of the object with the specified `Oid`.
+pub fn forget<C: GenericConnection>(conn: &C, oid: Oid) -> Result<()> {
+    let stmt = conn.prepare_cached("DELETE FROM large_object_chunk_digests WHERE oid = $1")?;
+    stmt.execute(&[&oid])?;
+    let stmt = conn.prepare_cached("DELETE FROM large_object_digests WHERE oid = $1")?;
+    stmt.execute(&[&oid]).map(|_| ())
+}
+
+/// Checks the object with the specified `Oid` against its record, returning
+/// the byte ranges of the chunks which no longer match.
+///
+/// The digests of the object's current contents are computed server side.
+/// If the object's size has changed, the ranges cover the difference.
+pub fn verify(trans: &Transaction, oid: Oid) -> Result<Vec<Range<u64>>> {
+    let (chunk_size, size, recorded) = load(trans, oid)?;
+    let current_size = trans.open_large_object(oid, Mode::Read)?.size()?;
+    let current = block_digests(trans, oid, chunk_size)?;
+    Ok(mismatches(&recorded, &current, chunk_size, cmp::max(size, current_size)))
+}
+
+/// Checks a local copy of the object with the specified `Oid`, such as a
+/// download, against the object's record, returning the byte ranges of the
+/// chunks which don't match.
+pub fn verify_reader<R>(trans: &Transaction, oid: Oid, r: &mut R) -> Result<Vec<Range<u64>>>
+where
+    R: ?Sized + Read,
+{
+    let (chunk_size, size, recorded) = load(trans, oid)?;
+    let mut current = vec![];
+    let mut current_size = 0;
+    let mut buf = vec![0; chunk_size];
+    loop {
+        let len = read_full(r, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+        current.push(format!("{:x}", md5::compute(&buf[..len])));
+        current_size += len as u64;
+        if len < chunk_size {
+            break;
+        }
+    }
+    Ok(mismatches(&recorded, &current, chunk_size, cmp::max(size, current_size)))
+}
+
+/// Computes the Merkle root of a list of hex-encoded digests.
+///
+/// Each level of the tree hashes the concatenation of pairs of digests from
+/// the level below, carrying an unpaired last digest up unchanged. The root
+/// of an empty list is the digest of no data.
+pub fn merkle_root(digests: &[String]) -> String {
+    if digests.is_empty() {
+        return format!("{:x}", md5::compute(b""));
+    }
+
+    let mut level = digests.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair.len() {
+                2 => format!("{:x}", md5::compute(format!("{}{}", pair[0], pair[1]))),
+                _ => pair[0].clone(),
+            })
+            .collect();
+    }
+    level.pop().unwrap()
+}
+
+fn load(trans: &Transaction, oid: Oid) -> Result<(usize, u64, Vec<String>)> {
+    let stmt =
+        trans.prepare_cached("SELECT chunk_size, size FROM large_object_digests WHERE oid = $1")?;
+    let rows = stmt.query(&[&oid])?;
+    let (chunk_size, size) = match rows.iter().next() {
+        Some(row) => (row.get::<_, i32>(0) as usize, row.get::<_, i64>(1) as u64),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no digests are recorded for large object {}", oid),
+            ).into())
+        }
+    };
+
+    let stmt = trans.prepare_cached(
+        "SELECT digest FROM large_object_chunk_digests WHERE oid = $1 ORDER BY chunk",
+    )?;
+    let rows = stmt.query(&[&oid])?;
+    let digests = rows.iter().map(|r| r.get(0)).collect();
+    Ok((chunk_size, size, digests))
+}
+
+fn mismatches(a: &[String], b: &[String], chunk_size: usize, size: u64) -> Vec<Range<u64>> {
+    let chunk_size = chunk_size as u64;
+    let mut ranges = vec![];
+    for i in 0..cmp::max(a.len(), b.len()) {
+        if a.get(i) == b.get(i) {
+            continue;
+        }
+        let start = i as u64 * chunk_size;
+        push_range(&mut ranges, start..cmp::min(start + chunk_size, size));
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{self, Seek, SeekFrom, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use integrity::{forget, install, merkle_root, record, root, verify, verify_reader};
+
+    #[test]
+    fn test_integrity() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        install(&trans).unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut data = (0..10_000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(&data).unwrap();
+        lo.finish().unwrap();
+
+        let tree = record(&trans, oid, 1000).unwrap();
+        assert_eq!(root(&trans, oid).unwrap(), Some(tree));
+        assert!(verify(&trans, oid).unwrap().is_empty());
+        assert!(verify_reader(&trans, oid, &mut &data[..]).unwrap().is_empty());
+
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.seek(SeekFrom::Start(2500)).unwrap();
+        lo.write_all(&[0; 1000]).unwrap();
+        lo.seek(SeekFrom::Start(10_000)).unwrap();
+        lo.write_all(b"more").unwrap();
+        lo.finish().unwrap();
+        assert_eq!(verify(&trans, oid).unwrap(), vec![2000..4000, 10_000..10_004]);
+
+        data[9999] = 0;
+        assert_eq!(verify_reader(&trans, oid, &mut &data[..]).unwrap(), vec![9000..10_000]);
+
+        forget(&trans, oid).unwrap();
+        assert_eq!(root(&trans, oid).unwrap(), None);
+        let e = verify(&trans, oid).unwrap_err();
+        assert_eq!(e.as_io().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let digests = ["a", "b", "c"].iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let ab = format!("{:x}", ::md5::compute("ab"));
+        let abc = format!("{:x}", ::md5::compute(format!("{}c", ab)));
+        assert_eq!(merkle_root(&digests), abc);
+        assert_eq!(merkle_root(&digests[..1]), "a");
+    }
+}
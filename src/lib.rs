@@ -32,59 +32,278 @@
 //! ```
 #![doc(html_root_url = "https://docs.rs/postgres_large_object/0.7")]
 
+#[cfg(feature = "with-actix")]
+extern crate actix_web;
+#[cfg(feature = "with-axum")]
+extern crate axum;
+#[cfg(feature = "with-futures")]
+extern crate bytes;
+#[cfg(feature = "with-crc32fast")]
+extern crate crc32fast;
+#[cfg(feature = "with-diesel")]
+#[macro_use]
+extern crate diesel;
+#[cfg(feature = "with-fuse")]
+extern crate fuse;
+#[cfg(feature = "with-futures")]
+extern crate futures;
+#[cfg(feature = "with-axum")]
+extern crate http_body;
+#[cfg(feature = "with-fuse")]
+extern crate libc;
+#[cfg(feature = "with-md5")]
+extern crate md5;
+#[macro_use]
 extern crate postgres;
+#[cfg(feature = "with-reqwest")]
+extern crate reqwest;
+#[cfg(feature = "with-rusoto")]
+extern crate rusoto_s3;
+#[cfg(feature = "with-sha2")]
+extern crate sha2;
+#[cfg(feature = "with-tar")]
+extern crate tar;
+extern crate tempfile;
+#[cfg(feature = "with-fuse")]
+extern crate time;
+#[cfg(feature = "with-warp")]
+extern crate tokio;
+#[cfg(feature = "with-tokio-util")]
+extern crate tokio_util;
+#[cfg(feature = "with-tonic")]
+extern crate tonic;
+#[cfg(feature = "with-warp")]
+extern crate warp;
 
-use postgres::{GenericConnection, Result};
+use postgres::error::{SqlState, ADMIN_SHUTDOWN, CRASH_SHUTDOWN, INSUFFICIENT_PRIVILEGE,
+                      INVALID_PARAMETER_VALUE, LOCK_NOT_AVAILABLE, PROGRAM_LIMIT_EXCEEDED,
+                      QUERY_CANCELED, UNDEFINED_FUNCTION, UNDEFINED_OBJECT};
+use postgres::{Connection, GenericConnection, Result};
 use postgres::transaction::Transaction;
-use postgres::types::Oid;
+use postgres::rows::{Row, Rows};
+use postgres::types::{FromSql, Oid, ToSql};
 use std::cmp;
+use std::error;
 use std::fmt;
 use std::i32;
-use std::io::{self, Write};
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::ops;
+use std::ptr;
+use std::result;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cancel::CancellationToken;
+use capabilities::{Capabilities, Unsupported};
+use statement_cache::StatementCache;
+use throttle::RateLimiter;
+use window::Window;
+
+#[cfg(feature = "with-actix")]
+pub mod actix_support;
+#[cfg(feature = "with-axum")]
+pub mod axum_support;
+#[cfg(feature = "with-tar")]
+pub mod backup;
+pub mod balance;
+pub mod cache;
+pub mod cancel;
+pub mod capabilities;
+pub mod chain;
+pub mod checksum;
+#[cfg(feature = "with-tokio-util")]
+pub mod codec;
+pub mod copy;
+#[cfg(feature = "with-md5")]
+pub mod delta;
+#[cfg(feature = "with-diesel")]
+pub mod diesel_support;
+pub mod diff;
+#[cfg(feature = "with-reqwest")]
+pub mod download;
+pub mod edit;
+pub mod error;
+pub mod etag;
+pub mod export;
+pub mod failover;
+#[cfg(feature = "with-fuse")]
+pub mod fusefs;
+pub mod hybrid;
+pub mod import;
+#[cfg(feature = "with-md5")]
+pub mod integrity;
+pub mod lo;
+pub mod migrate;
+pub mod parallel;
+pub mod prefetch;
+pub mod progress;
+pub mod range;
+pub mod record_log;
+pub mod resume;
+pub mod retry;
+#[cfg(feature = "with-rusoto")]
+pub mod s3;
+pub mod sparse;
+pub mod spool;
+pub mod standby;
+pub mod statement_cache;
+pub mod store;
+#[cfg(feature = "with-futures")]
+pub mod stream;
+pub mod tee;
+pub mod throttle;
+pub mod timeout;
+#[cfg(feature = "with-tonic")]
+pub mod tonic_support;
+pub mod track;
+pub mod upload;
+pub mod vacuum;
+#[cfg(any(feature = "with-md5", feature = "with-sha2"))]
+pub mod verify;
+#[cfg(feature = "with-warp")]
+pub mod warp_support;
+pub mod window;
 
 /// An extension trait adding functionality to create and delete large objects.
 pub trait LargeObjectExt {
     /// Creates a new large object, returning its `Oid`.
     fn create_large_object(&self) -> Result<Oid>;
 
+    /// Creates a new large object with the specified `Oid`.
+    ///
+    /// Fails if an object with that `Oid` already exists.
+    fn create_large_object_with_oid(&self, oid: Oid) -> Result<()>;
+
     /// Deletes the large object with the specified `Oid`.
     fn delete_large_object(&self, oid: Oid) -> Result<()>;
+
+    /// Returns the `Oid`s of all large objects in the database, in ascending
+    /// order.
+    ///
+    /// On servers older than Postgres 9.0, empty objects are not listed.
+    fn list_large_objects(&self) -> Result<Vec<Oid>>;
+
+    /// Determines if the server supports large objects.
+    ///
+    /// Servers which speak the Postgres protocol without implementing large
+    /// objects, like CockroachDB, fail every other operation with an
+    /// `Unsupported` error.
+    fn supports_large_objects(&self) -> Result<bool>;
 }
 
+// These are run rarely enough that caching their statements isn't worth the
+// trouble it causes behind transaction pooling proxies like PgBouncer, so they
+// use the unnamed statement.
 impl<T: GenericConnection> LargeObjectExt for T {
     fn create_large_object(&self) -> Result<Oid> {
-        let stmt = self.prepare_cached("SELECT pg_catalog.lo_create(0)")?;
-        let r = stmt.query(&[]).map(|r| r.iter().next().unwrap().get(0));
-        r
+        let rows = check_supported(self.query("SELECT pg_catalog.lo_create(0)", &[]))?;
+        first_column(&rows)
+    }
+
+    fn create_large_object_with_oid(&self, oid: Oid) -> Result<()> {
+        check_supported(self.execute("SELECT pg_catalog.lo_create($1)", &[&oid])).map(|_| ())
     }
 
     fn delete_large_object(&self, oid: Oid) -> Result<()> {
-        let stmt = self.prepare_cached("SELECT pg_catalog.lo_unlink($1)")?;
-        stmt.execute(&[&oid]).map(|_| ())
+        check_supported(self.execute("SELECT pg_catalog.lo_unlink($1)", &[&oid])).map(|_| ())
+    }
+
+    fn list_large_objects(&self) -> Result<Vec<Oid>> {
+        let capabilities = Capabilities::detect(self)?;
+        if !capabilities.supports_large_objects() {
+            return Err(io::Error::from(Unsupported::new("large objects")).into());
+        }
+        // servers before 9.0 have no metadata catalog, and only list objects
+        // in pg_largeobject once something has been written to them
+        let sql = if capabilities.has_metadata() {
+            "SELECT oid FROM pg_catalog.pg_largeobject_metadata ORDER BY oid"
+        } else {
+            "SELECT DISTINCT loid FROM pg_catalog.pg_largeobject ORDER BY loid"
+        };
+        let rows = self.query(sql, &[])?;
+        rows.iter().map(|r| column(&r, 0)).collect()
+    }
+
+    fn supports_large_objects(&self) -> Result<bool> {
+        Ok(Capabilities::detect(self)?.supports_large_objects())
     }
 }
 
 /// Large object access modes.
 ///
+/// Modes are sets of flags, combined with `|`. The `Read`, `Write` and
+/// `ReadWrite` constants are kept from when `Mode` was an enum.
+///
 /// Note that Postgres currently does not make any distinction between the
-/// `Write` and `ReadWrite` modes.
-#[derive(Debug)]
-pub enum Mode {
+/// `WRITE` and `READ | WRITE` modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Mode(i32);
+
+#[allow(non_upper_case_globals)]
+impl Mode {
+    /// An object opened in this mode may be read from.
+    pub const READ: Mode = Mode(0x00040000);
+    /// An object opened in this mode may be written to.
+    pub const WRITE: Mode = Mode(0x00020000);
+    /// An object opened in this mode may be written to, with writes always
+    /// going to the end of the object.
+    ///
+    /// The handle seeks to the end of the object when it's opened, and again
+    /// before writing after it has been seeked or truncated, so reads may
+    /// still be made anywhere. Unlike `O_APPEND`, this does not guard against
+    /// concurrent writers extending the object. `read_at` and `write_all_at`
+    /// are unaffected.
+    pub const APPEND: Mode = Mode(0x00020000 | 0x00000001);
+
     /// An object opened in this mode may only be read from.
-    Read,
+    pub const Read: Mode = Mode::READ;
     /// An object opened in this mode may be written to.
-    Write,
+    pub const Write: Mode = Mode::WRITE;
     /// An object opened in this mode may be read from or written to.
-    ReadWrite,
+    pub const ReadWrite: Mode = Mode(0x00040000 | 0x00020000);
+
+    /// Returns a mode with no flags set.
+    pub fn empty() -> Mode {
+        Mode(0)
+    }
+
+    /// Returns the raw flags.
+    pub fn bits(&self) -> i32 {
+        self.0
+    }
+
+    /// Determines if all of the flags in `other` are set.
+    pub fn contains(&self, other: Mode) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Determines if no flags are set.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
 }
 
-impl Mode {
-    fn to_i32(&self) -> i32 {
-        match *self {
-            Mode::Read => 0x00040000,
-            Mode::Write => 0x00020000,
-            Mode::ReadWrite => 0x00040000 | 0x00020000,
-        }
+impl ops::BitOr for Mode {
+    type Output = Mode;
+
+    fn bitor(self, other: Mode) -> Mode {
+        Mode(self.0 | other.0)
+    }
+}
+
+impl ops::BitOrAssign for Mode {
+    fn bitor_assign(&mut self, other: Mode) {
+        self.0 |= other.0;
+    }
+}
+
+impl ops::BitAnd for Mode {
+    type Output = Mode;
+
+    fn bitand(self, other: Mode) -> Mode {
+        Mode(self.0 & other.0)
     }
 }
 
@@ -96,36 +315,224 @@ pub trait LargeObjectTransactionExt {
 
 impl<'conn> LargeObjectTransactionExt for Transaction<'conn> {
     fn open_large_object<'a>(&'a self, oid: Oid, mode: Mode) -> Result<LargeObject<'a>> {
-        let version = self.connection().parameter("server_version").unwrap();
-        let (major, minor) = parse_version(&version);
-        let has_64 = major > 9 || (major == 9 && minor >= 3);
-
-        let stmt = self.prepare_cached("SELECT pg_catalog.lo_open($1, $2)")?;
-        let fd = stmt.query(&[&oid, &mode.to_i32()])?
-            .iter()
-            .next()
-            .unwrap()
-            .get(0);
-        Ok(LargeObject {
-            trans: self,
+        OpenOptions::new().open(self, oid, mode)
+    }
+}
+
+/// Options controlling how large objects are opened.
+#[derive(Debug, Clone)]
+pub struct OpenOptions<'a> {
+    statements: Statements<'a>,
+    capabilities: Option<Capabilities>,
+    track_position: bool,
+}
+
+impl<'a> Default for OpenOptions<'a> {
+    fn default() -> OpenOptions<'a> {
+        OpenOptions {
+            statements: Statements::Cached,
+            capabilities: None,
+            track_position: false,
+        }
+    }
+}
+
+impl<'a> OpenOptions<'a> {
+    /// Creates a new set of options with the default values.
+    pub fn new() -> OpenOptions<'a> {
+        OpenOptions::default()
+    }
+
+    /// Determines if the handle prepares and caches the statements it runs.
+    ///
+    /// Cached statements are named, and live on the server connection which
+    /// prepared them, so they break behind proxies like PgBouncer which hand
+    /// out a different server connection for each transaction. Disabling
+    /// this runs each statement as the unnamed statement instead, at the cost
+    /// of parsing it every time. Defaults to `true`.
+    pub fn prepared_statements(&mut self, prepared_statements: bool) -> &mut OpenOptions<'a> {
+        self.statements = if prepared_statements {
+            Statements::Cached
+        } else {
+            Statements::Unnamed
+        };
+        self
+    }
+
+    /// Prepares the handle's statements with a `StatementCache` rather than
+    /// the connection's own cache.
+    ///
+    /// The cache must belong to the connection of the transaction the object
+    /// is opened in.
+    pub fn statement_cache<'conn>(
+        &mut self,
+        cache: &'a StatementCache<'conn>,
+    ) -> &mut OpenOptions<'a>
+    where
+        'conn: 'a,
+    {
+        self.statements = Statements::Custom(cache);
+        self
+    }
+
+    /// Uses previously detected server capabilities rather than checking the
+    /// server's catalog when the object is opened.
+    ///
+    /// The capabilities should have been detected on the server the object
+    /// is opened on, though they can be adjusted to force the handle to avoid
+    /// functionality the server supports.
+    pub fn capabilities(&mut self, capabilities: Capabilities) -> &mut OpenOptions<'a> {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// Determines if the handle tracks its position locally.
+    ///
+    /// The handle then knows its position without asking the server, so
+    /// `SeekFrom::Current(0)` is free, and seeks to the current position or
+    /// within buffered data make no round trips. Statements run directly
+    /// against the handle's descriptor (see `LargeObject::fd`) which move the
+    /// position make the tracked position wrong. Defaults to `false`.
+    pub fn track_position(&mut self, track_position: bool) -> &mut OpenOptions<'a> {
+        self.track_position = track_position;
+        self
+    }
+
+    /// Opens the large object with the specified `Oid` in the specified
+    /// `Mode`.
+    pub fn open(
+        &self,
+        trans: &'a Transaction,
+        oid: Oid,
+        mode: Mode,
+    ) -> Result<LargeObject<'a>> {
+        if let Statements::Custom(cache) = self.statements {
+            if !ptr::eq(cache.connection(), trans.connection()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the statement cache belongs to a different connection",
+                ).into());
+            }
+        }
+
+        let known = self
+            .capabilities
+            .or_else(|| capabilities::cached(trans.connection()));
+        if let Some(capabilities) = known {
+            if !capabilities.supports_large_objects() {
+                return Err(io::Error::from(Unsupported::new("large objects")).into());
+            }
+        }
+
+        // check the catalog in the same query rather than making a round trip
+        let sql = match known {
+            Some(_) => "SELECT pg_catalog.lo_open($1, $2), \
+                        pg_catalog.current_setting('block_size')::INT4 / 4"
+                .to_owned(),
+            None => format!(
+                "SELECT pg_catalog.lo_open($1, $2), \
+                 pg_catalog.current_setting('block_size')::INT4 / 4, {}",
+                capabilities::NAMES
+            ),
+        };
+        let rows = check_supported(run_query(
+            trans,
+            self.statements,
+            &sql,
+            &[&oid, &(mode & (Mode::READ | Mode::WRITE)).bits()],
+        ))?;
+        let row = first_row(&rows)?;
+        let fd = column(&row, 0)?;
+        let page_size: i32 = column(&row, 1)?;
+        let capabilities = match known {
+            Some(capabilities) => capabilities,
+            None => {
+                let capabilities = Capabilities::from_names(&column::<Vec<String>>(&row, 2)?);
+                capabilities::remember(trans.connection(), capabilities);
+                capabilities
+            }
+        };
+        let append = mode.contains(Mode::APPEND);
+        let mut lo = LargeObject {
+            trans: trans,
+            oid: oid,
             fd: fd,
-            has_64: has_64,
+            page_size: page_size as usize,
+            capabilities: capabilities,
+            statements: self.statements,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            read_buf: vec![],
+            read_pos: 0,
+            write_buf: vec![],
+            write_buf_size: 0,
+            write_batch_size: 1,
+            chunks: 0,
+            round_trips: 0,
+            cancellation_token: None,
+            rate_limiter: None,
+            drop_policy: DropPolicy::Ignore,
+            track_position: self.track_position,
+            position: 0,
+            saved_statement_timeout: None,
+            writable: mode.contains(Mode::WRITE),
+            append: append,
+            append_pending: false,
+            track_changes: false,
+            change_recorded: false,
             finished: false,
-        })
+        };
+        if append {
+            io::Seek::seek(&mut lo, io::SeekFrom::End(0))?;
+            lo.append_pending = false;
+        }
+        Ok(lo)
     }
 }
 
 /// Represents an open large object.
+///
+/// Errors reported by the server through the `Read`, `Write` and `Seek`
+/// implementations have an `io::ErrorKind` reflecting their SQLSTATE: an
+/// invalid descriptor is `NotFound`, insufficient privileges are
+/// `PermissionDenied`, and cancelled statements are `TimedOut`, for example.
 pub struct LargeObject<'a> {
     trans: &'a Transaction<'a>,
+    oid: Oid,
     fd: i32,
-    has_64: bool,
+    page_size: usize,
+    capabilities: Capabilities,
+    statements: Statements<'a>,
+    chunk_size: usize,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write_buf: Vec<u8>,
+    write_buf_size: usize,
+    write_batch_size: usize,
+    // counts of data transferring calls, for TransferStats
+    chunks: u64,
+    round_trips: u64,
+    cancellation_token: Option<CancellationToken>,
+    rate_limiter: Option<RateLimiter>,
+    drop_policy: DropPolicy,
+    track_position: bool,
+    // the server side position, if tracked
+    position: u64,
+    // the statement timeout in effect before set_statement_timeout
+    saved_statement_timeout: Option<String>,
+    // lo_put ignores the descriptor's mode, so it's checked client side
+    writable: bool,
+    append: bool,
+    // set when the handle may no longer be positioned at the end
+    append_pending: bool,
+    track_changes: bool,
+    change_recorded: bool,
     finished: bool,
 }
 
 impl<'a> fmt::Debug for LargeObject<'a> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("LargeObject")
+            .field("oid", &self.oid)
             .field("fd", &self.fd)
             .field("transaction", &self.trans)
             .finish()
@@ -134,38 +541,456 @@ impl<'a> fmt::Debug for LargeObject<'a> {
 
 impl<'a> Drop for LargeObject<'a> {
     fn drop(&mut self) {
-        let _ = self.finish_inner();
+        let e = match self.finish_inner() {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        match self.drop_policy {
+            DropPolicy::Ignore => {}
+            DropPolicy::Callback(ref f) => f(self.oid, &e),
+            DropPolicy::DebugAssert => {
+                if !thread::panicking() {
+                    debug_assert!(false, "error finishing large object {}: {}", self.oid, e);
+                }
+            }
+        }
+    }
+}
+
+/// What a `LargeObject` does with errors closing it when it's dropped.
+///
+/// Dropping a handle flushes its write buffer and closes it, and neither can
+/// report an error to the caller. `LargeObject::finish` and
+/// `LargeObject::try_finish` should be used where errors matter, but a policy
+/// can catch the places they were missed.
+#[derive(Clone)]
+pub enum DropPolicy {
+    /// Errors are ignored. This is the default.
+    Ignore,
+    /// Errors are passed to a callback, along with the `Oid` of the object.
+    Callback(Arc<dyn Fn(Oid, &postgres::Error) + Send + Sync>),
+    /// Errors cause a panic in debug builds, and are ignored otherwise.
+    DebugAssert,
+}
+
+impl fmt::Debug for DropPolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DropPolicy::Ignore => fmt.write_str("Ignore"),
+            DropPolicy::Callback(_) => fmt.write_str("Callback"),
+            DropPolicy::DebugAssert => fmt.write_str("DebugAssert"),
+        }
     }
 }
 
 impl<'a> LargeObject<'a> {
+    /// Returns the `Oid` of the opened object.
+    pub fn oid(&self) -> Oid {
+        self.oid
+    }
+
     /// Returns the file descriptor of the opened object.
     pub fn fd(&self) -> i32 {
         self.fd
     }
 
+    /// Returns the large object functionality supported by the server.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Returns the size of the pages the server stores large objects in.
+    ///
+    /// This is `LOBLKSIZE`, a quarter of the server's block size, and is
+    /// typically 2048 bytes. Writes which don't cover whole pages require the
+    /// server to read and rewrite the partial pages, so write buffer and
+    /// chunk sizes should be multiples of it.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Determines if modifications made through this handle are recorded in
+    /// the change tracking table.
+    ///
+    /// Defaults to `false`. The table must have been created with
+    /// `track::install`.
+    pub fn set_track_changes(&mut self, track_changes: bool) {
+        self.track_changes = track_changes;
+    }
+
+    /// Returns the maximum number of bytes transferred by a single `loread`
+    /// or `lowrite` call.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Sets the maximum number of bytes transferred by a single `loread` or
+    /// `lowrite` call.
+    ///
+    /// Reads and writes larger than the chunk size are split into multiple
+    /// calls. Writes are split on page boundaries where possible, so a chunk
+    /// size of at least one page is effectively rounded down to a multiple of
+    /// the page size. Defaults to 16 MiB, which keeps the number of round
+    /// trips low without tying up much memory on either side. Values are
+    /// clamped to between 1 byte and 1 GiB - 1, the largest allocation
+    /// Postgres will make for a single call.
+    ///
+    /// The read buffer used by the `BufRead` implementation holds the smaller
+    /// of the chunk size and 8 KiB.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = cmp::max(1, cmp::min(chunk_size, MAX_CHUNK_SIZE));
+    }
+
+    /// Returns the capacity of the write buffer.
+    pub fn write_buffer_size(&self) -> usize {
+        self.write_buf_size
+    }
+
+    /// Sets the capacity of the write buffer.
+    ///
+    /// Writes smaller than the buffer are accumulated and sent to the server
+    /// in a single `lowrite` call once the buffer fills up, the handle is
+    /// flushed, read from, seeked, truncated, or closed. Defaults to 0, which
+    /// disables buffering.
+    ///
+    /// Errors writing out buffered data when the handle is dropped are
+    /// handled by its `DropPolicy`, ignoring them by default, so `flush` or
+    /// `finish` should be called explicitly.
+    pub fn set_write_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        if size < self.write_buf.len() {
+            self.flush_write_buf()?;
+        }
+        self.write_buf_size = size;
+        Ok(())
+    }
+
+    /// Returns the maximum number of chunks sent in a single statement.
+    pub fn write_batch_size(&self) -> usize {
+        self.write_batch_size
+    }
+
+    /// Sets the maximum number of chunks sent in a single statement.
+    ///
+    /// Writes larger than the chunk size are normally sent with one `lowrite`
+    /// call per chunk, each taking a round trip. With a batch size above 1,
+    /// up to that many chunks are instead written by a single statement,
+    /// which can be much faster over high latency links. Defaults to 1, and
+    /// values are clamped to at least 1.
+    pub fn set_write_batch_size(&mut self, batch_size: usize) {
+        self.write_batch_size = cmp::max(batch_size, 1);
+    }
+
+    /// Attaches a cancellation token to the handle.
+    ///
+    /// Once the token is cancelled, reads and writes fail with an error
+    /// before transferring their next chunk.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation_token = Some(token);
+    }
+
+    /// Attaches a rate limiter to the handle.
+    ///
+    /// Reads and writes wait as needed to keep the rate at which data is
+    /// transferred within the limiter's bounds. The limiter may be shared
+    /// with other handles to cap their combined rate.
+    pub fn set_rate_limiter(&mut self, limiter: RateLimiter) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// Sets what happens to errors closing the handle when it's dropped.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.drop_policy = policy;
+    }
+
+    fn throttle(&self, bytes: usize) {
+        if let Some(ref limiter) = self.rate_limiter {
+            limiter.acquire(bytes as u64);
+        }
+    }
+
+    /// Limits the time each statement run in the handle's transaction may
+    /// take, so a stalled server fails the operation rather than hanging it.
+    ///
+    /// The timeout is applied with `SET LOCAL statement_timeout`, and the
+    /// previous setting is restored when the timeout is cleared by passing
+    /// `None` or the handle is finished. Note that the timeout applies to all
+    /// statements in the transaction until then, not just those run by the
+    /// handle.
+    pub fn set_statement_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        match timeout {
+            Some(timeout) => {
+                let previous = set_statement_timeout(
+                    self.trans,
+                    self.statements,
+                    &format_timeout(timeout),
+                )?;
+                if self.saved_statement_timeout.is_none() {
+                    self.saved_statement_timeout = Some(previous);
+                }
+                Ok(())
+            }
+            None => self.restore_statement_timeout(),
+        }
+    }
+
+    fn restore_statement_timeout(&mut self) -> Result<()> {
+        match self.saved_statement_timeout.take() {
+            Some(previous) => {
+                set_statement_timeout(self.trans, self.statements, &previous).map(|_| ())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn query(&self, sql: &str, params: &[&dyn ToSql]) -> Result<Rows> {
+        run_query(self.trans, self.statements, sql, params)
+    }
+
+    fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> Result<()> {
+        match self.statements {
+            Statements::Cached => self.trans.prepare_cached(sql)?.execute(params).map(|_| ()),
+            Statements::Unnamed => self.trans.execute(sql, params).map(|_| ()),
+            Statements::Custom(cache) => cache.query(sql, params).map(|_| ()),
+        }
+    }
+
+    fn check_cancelled(&self) -> io::Result<()> {
+        match self.cancellation_token {
+            Some(ref token) => token.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the size of the object in bytes.
+    ///
+    /// The current position of the handle is left unchanged.
+    pub fn size(&mut self) -> Result<u64> {
+        use std::io::Seek;
+
+        let pos = self.seek(io::SeekFrom::Current(0))?;
+        let size = self.seek(io::SeekFrom::End(0))?;
+        self.seek(io::SeekFrom::Start(pos))?;
+        Ok(size)
+    }
+
+    /// Returns a view of the `len` bytes of the object starting at `offset`.
+    ///
+    /// Reads and seeks through the view are confined to that range. The
+    /// position of the handle is unspecified once the view is dropped.
+    pub fn range<'b>(&'b mut self, offset: u64, len: u64) -> Window<'b, 'a> {
+        Window::new(self, offset, len)
+    }
+
+    fn read_buf_size(&self) -> usize {
+        cmp::min(self.chunk_size, READ_BUF_SIZE)
+    }
+
+    /// Drops any buffered data, moving the server side position back to the
+    /// logical position of the handle.
+    fn discard_read_buf(&mut self) -> io::Result<()> {
+        let remaining = self.read_buf.len() - self.read_pos;
+        self.read_buf.clear();
+        self.read_pos = 0;
+        if remaining > 0 {
+            self.seek_raw(io::SeekFrom::Current(-(remaining as i64)))?;
+        }
+        Ok(())
+    }
+
+    /// Sends any buffered writes to the server.
+    fn flush_write_buf(&mut self) -> io::Result<()> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = mem::replace(&mut self.write_buf, vec![]);
+        let mut pos = 0;
+        let r = loop {
+            if pos == buf.len() {
+                break Ok(());
+            }
+            match self.write_raw(&buf[pos..]) {
+                Ok(0) => {
+                    break Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                Ok(n) => pos += n,
+                Err(e) => break Err(e),
+            }
+        };
+        buf.clear();
+        self.write_buf = buf;
+        r
+    }
+
+    fn record_change(&mut self) -> Result<()> {
+        if !self.track_changes || self.change_recorded {
+            return Ok(());
+        }
+
+        // routed through the handle's statement mode so that tracking works
+        // behind poolers that don't support named statements
+        self.execute(track::RECORD_CHANGE, &[&self.oid])?;
+        self.change_recorded = true;
+        Ok(())
+    }
+
     /// Truncates the object to the specified size.
     ///
     /// If `len` is larger than the size of the object, it will be padded with
-    /// null bytes to the specified size.
+    /// null bytes to the specified size. Fails with an `Unsupported` error on
+    /// servers older than Postgres 8.3, and for sizes over 2GB on servers
+    /// older than 9.3.
     pub fn truncate(&mut self, len: i64) -> Result<()> {
-        if self.has_64 {
-            let stmt = self.trans
-                .prepare_cached("SELECT pg_catalog.lo_truncate64($1, $2)")?;
-            stmt.execute(&[&self.fd, &len]).map(|_| ())
+        self.discard_read_buf()?;
+        self.flush_write_buf()?;
+        self.append_pending = self.append;
+        if !self.capabilities.has_truncate() {
+            return Err(io::Error::from(Unsupported::new("lo_truncate")).into());
+        }
+        self.record_change()?;
+        if self.capabilities.has_64_bit() {
+            self.execute("SELECT pg_catalog.lo_truncate64($1, $2)", &[&self.fd, &len])
         } else {
             let len = if len <= i32::max_value() as i64 {
                 len as i32
             } else {
+                return Err(io::Error::from(Unsupported::new("objects larger than 2GB")).into());
+            };
+            self.execute("SELECT pg_catalog.lo_truncate($1, $2)", &[&self.fd, &len])
+        }
+    }
+
+    /// Extends the object to at least `len` bytes, padding it with null
+    /// bytes.
+    ///
+    /// Unlike `truncate`, this never shrinks the object, so it can be used to
+    /// reserve space before writing ranges of the object in parallel. The
+    /// object is extended with `lo_truncate` where possible, and otherwise by
+    /// writing zeros in large chunks. The position of the handle is left
+    /// unchanged.
+    pub fn allocate(&mut self, len: u64) -> Result<()> {
+        use std::io::Seek;
+
+        let size = self.size()?;
+        if size >= len {
+            return Ok(());
+        }
+
+        if self.capabilities.has_truncate() {
+            if len > i64::max_value() as u64 {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidInput,
-                    "The database does not support objects larger \
-                     than 2GB",
+                    "cannot allocate more than 2^63 bytes",
                 ).into());
-            };
-            let stmt = self.trans
-                .prepare_cached("SELECT pg_catalog.lo_truncate($1, $2)")?;
-            stmt.execute(&[&self.fd, &len]).map(|_| ())
+            }
+            return self.truncate(len as i64);
+        }
+
+        let pos = self.seek(io::SeekFrom::Current(0))?;
+        self.seek(io::SeekFrom::Start(size))?;
+        let mut remaining = len - size;
+        let zeros = vec![0; cmp::min(remaining, MAX_COPY_CHUNK_SIZE as u64) as usize];
+        while remaining > 0 {
+            let n = cmp::min(remaining, zeros.len() as u64) as usize;
+            self.write_all(&zeros[..n])?;
+            remaining -= n as u64;
+        }
+        self.flush_write_buf()?;
+        self.seek(io::SeekFrom::Start(pos))?;
+        Ok(())
+    }
+
+    /// Reads data starting at the specified offset, returning the number of
+    /// bytes read.
+    ///
+    /// Unlike a `seek` followed by a `read`, this takes a single round trip
+    /// per chunk and leaves the position of the handle unchanged. Requires
+    /// Postgres 9.4 or newer.
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.check_lo_get()?;
+        self.flush_write_buf()?;
+        let mut nread = 0;
+        while nread < buf.len() {
+            let cap = cmp::min(buf.len() - nread, self.chunk_size);
+            let pos = (offset + nread as u64) as i64;
+            let rows = self
+                .query(
+                    "SELECT pg_catalog.lo_get($1, $2, $3)",
+                    &[&self.oid, &pos, &(cap as i32)],
+                )
+                .map_err(io_error)?;
+            let row = first_row(&rows)?;
+            let n = (&mut buf[nread..]).write(bytes_column(&row, 0)?)?;
+            self.throttle(n);
+            nread += n;
+            if n < cap {
+                break;
+            }
+        }
+        Ok(nread)
+    }
+
+    /// Like `read_at`, but fails with `UnexpectedEof` if the object ends
+    /// before `buf` is filled.
+    pub fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let n = self.read_at(buf, offset)?;
+        if n < buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Writes all of `buf` starting at the specified offset.
+    ///
+    /// Unlike a `seek` followed by a `write`, this takes a single round trip
+    /// per chunk and leaves the position of the handle unchanged. Requires
+    /// Postgres 9.4 or newer.
+    ///
+    /// Fails with `PermissionDenied` if the handle was not opened for
+    /// writing.
+    pub fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "large object descriptor was not opened for writing",
+            ));
+        }
+        self.check_lo_get()?;
+        self.discard_read_buf()?;
+        self.flush_write_buf()?;
+        let chunk_size = self.write_chunk_size();
+        let mut pos = offset;
+        let mut rest = buf;
+        while !rest.is_empty() {
+            // end each chunk on a page boundary if we can
+            let mut n = chunk_size;
+            if chunk_size >= self.page_size {
+                n -= (pos % self.page_size as u64) as usize;
+            }
+            let n = cmp::min(n, rest.len());
+            self.throttle(n);
+            self.execute(
+                "SELECT pg_catalog.lo_put($1, $2, $3)",
+                &[&self.oid, &(pos as i64), &&rest[..n]],
+            ).map_err(io_error)?;
+            pos += n as u64;
+            rest = &rest[n..];
+        }
+        self.record_change().map_err(io_error)?;
+        Ok(())
+    }
+
+    fn check_lo_get(&self) -> io::Result<()> {
+        if self.capabilities.has_lo_get() {
+            Ok(())
+        } else {
+            Err(Unsupported::new("lo_get and lo_put").into())
         }
     }
 
@@ -174,9 +999,344 @@ impl<'a> LargeObject<'a> {
             return Ok(());
         }
 
+        let flushed = self.flush_write_buf();
+        self.close(flushed)
+    }
+
+    /// Closes the descriptor and restores the statement timeout, even if
+    /// flushing the write buffer failed, returning the first error.
+    fn close(&mut self, flushed: io::Result<()>) -> Result<()> {
         self.finished = true;
-        let stmt = self.trans.prepare_cached("SELECT pg_catalog.lo_close($1)")?;
-        stmt.execute(&[&self.fd]).map(|_| ())
+        let closed = self.execute("SELECT pg_catalog.lo_close($1)", &[&self.fd]);
+        let restored = self.restore_statement_timeout();
+        flushed.map_err(Into::into).and(closed).and(restored)
+    }
+
+    /// Reads up to `len` bytes from the object, passing them to `f` without
+    /// copying them out of the result row.
+    ///
+    /// At most one `loread` call is made, so `len` is capped to the chunk
+    /// size. If data has already been buffered by the `BufRead`
+    /// implementation, `f` is passed that instead. An empty slice indicates
+    /// the end of the object.
+    pub fn read_with<F, T>(&mut self, len: usize, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        if self.read_pos < self.read_buf.len() {
+            let n = cmp::min(len, self.read_buf.len() - self.read_pos);
+            let r = f(&self.read_buf[self.read_pos..self.read_pos + n]);
+            self.read_pos += n;
+            return Ok(r);
+        }
+
+        self.flush_write_buf()?;
+        let len = cmp::min(len, self.chunk_size);
+        self.loread(len, f)
+    }
+
+    /// Reads the remainder of the object into a new `Vec`.
+    ///
+    /// Equivalent to `read_to_end`.
+    pub fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![];
+        io::Read::read_to_end(self, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Copies the remainder of the object into a writer, returning statistics
+    /// about the transfer.
+    ///
+    /// Data is written straight from the result rows, avoiding the small
+    /// intermediate buffer used by `io::copy`. The amount fetched per round
+    /// trip starts at 64 KiB and adapts to the observed latency, between
+    /// 8 KiB and 16 MiB, but never exceeds the chunk size.
+    pub fn copy_to<W>(&mut self, w: &mut W) -> io::Result<TransferStats>
+    where
+        W: ?Sized + Write,
+    {
+        let start = self.start_transfer();
+        let mut total = 0;
+        if self.read_pos < self.read_buf.len() {
+            w.write_all(&self.read_buf[self.read_pos..])?;
+            total += (self.read_buf.len() - self.read_pos) as u64;
+        }
+        self.read_buf.clear();
+        self.read_pos = 0;
+        self.flush_write_buf()?;
+
+        let mut chunk = AdaptiveChunk::new(self.chunk_size);
+        loop {
+            let read_start = Instant::now();
+            let n = self.loread(chunk.size, |data| w.write_all(data).map(|_| data.len()))??;
+            if n == 0 {
+                return Ok(self.transfer_stats(total, start));
+            }
+            // time spent waiting on a rate limiter says nothing about latency
+            if self.rate_limiter.is_none() {
+                chunk.update(n, read_start.elapsed());
+            }
+            total += n as u64;
+        }
+    }
+
+    /// Copies the contents of a reader into the object at its current
+    /// position, returning statistics about the transfer.
+    ///
+    /// Data is read into a buffer which is filled completely before being
+    /// written. As with `copy_to`, its size adapts to the observed latency of
+    /// each `lowrite` call.
+    pub fn copy_from<R>(&mut self, r: &mut R) -> io::Result<TransferStats>
+    where
+        R: ?Sized + io::Read,
+    {
+        let start = self.start_transfer();
+        let mut chunk = AdaptiveChunk::new(self.chunk_size);
+        let mut buf = vec![];
+        let mut total = 0;
+        loop {
+            buf.resize(chunk.size, 0);
+            let len = read_full(r, &mut buf)?;
+            if len == 0 {
+                return Ok(self.transfer_stats(total, start));
+            }
+            let write_start = Instant::now();
+            self.write_all(&buf[..len])?;
+            if self.rate_limiter.is_none() {
+                chunk.update(len, write_start.elapsed());
+            }
+            total += len as u64;
+        }
+    }
+
+    fn loread<F, T>(&mut self, len: usize, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        self.check_cancelled()?;
+        let rows = self
+            .query("SELECT pg_catalog.loread($1, $2)", &[&self.fd, &(len as i32)])
+            .map_err(io_error)?;
+        let row = first_row(&rows)?;
+        let data = bytes_column(&row, 0)?;
+        self.position += data.len() as u64;
+        self.throttle(data.len());
+        self.round_trips += 1;
+        if !data.is_empty() {
+            self.chunks += 1;
+        }
+        Ok(f(data))
+    }
+
+    /// Returns the transfer counters and the current time, to be passed to
+    /// `transfer_stats` once the transfer is complete.
+    fn start_transfer(&self) -> (u64, u64, Instant) {
+        (self.chunks, self.round_trips, Instant::now())
+    }
+
+    fn transfer_stats(&self, bytes: u64, start: (u64, u64, Instant)) -> TransferStats {
+        let (chunks, round_trips, start) = start;
+        TransferStats {
+            bytes: bytes,
+            chunks: self.chunks - chunks,
+            round_trips: self.round_trips - round_trips,
+            elapsed: start.elapsed(),
+        }
+    }
+
+    fn read_raw(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut nread = 0;
+        while nread < buf.len() {
+            let cap = cmp::min(buf.len() - nread, self.chunk_size);
+            let n = {
+                let dst = &mut buf[nread..];
+                self.loread(cap, |data| {
+                    dst[..data.len()].copy_from_slice(data);
+                    data.len()
+                })?
+            };
+            nread += n;
+            // a short read means we've hit the end of the object
+            if n < cap {
+                break;
+            }
+        }
+        Ok(nread)
+    }
+
+    /// Writes data to the server, returning the number of bytes written.
+    ///
+    /// `lowrite` reports how much of each chunk it wrote, and this stops
+    /// after the first chunk it didn't write completely.
+    fn write_raw(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk_size = self.write_chunk_size();
+        let mut written = 0;
+        let mut batch_short = false;
+        if self.write_batch_size > 1 && buf.len() > chunk_size {
+            // unnest produces the chunks in order, so they're written in order
+            let chunks = buf.chunks(chunk_size).collect::<Vec<_>>();
+            for batch in chunks.chunks(self.write_batch_size) {
+                self.check_cancelled()?;
+                self.throttle(batch.iter().map(|c| c.len()).sum());
+                let rows = self
+                    .query(
+                        "SELECT pg_catalog.lowrite($1, c) FROM pg_catalog.unnest($2::BYTEA[]) c",
+                        &[&self.fd, &batch],
+                    )
+                    .map_err(io_error)?;
+                self.chunks += batch.len() as u64;
+                self.round_trips += 1;
+                for (row, chunk) in rows.iter().zip(batch) {
+                    let n: i32 = column(&row, 0)?;
+                    self.position += n as u64;
+                    written += n as usize;
+                    batch_short |= n as usize != chunk.len();
+                }
+                if batch_short {
+                    break;
+                }
+            }
+        } else {
+            for chunk in buf.chunks(chunk_size) {
+                self.check_cancelled()?;
+                self.throttle(chunk.len());
+                let rows = self
+                    .query("SELECT pg_catalog.lowrite($1, $2)", &[&self.fd, &chunk])
+                    .map_err(io_error)?;
+                let n: i32 = first_column(&rows)?;
+                self.position += n as u64;
+                self.chunks += 1;
+                self.round_trips += 1;
+                written += n as usize;
+                if (n as usize) < chunk.len() {
+                    break;
+                }
+            }
+        }
+        self.record_change().map_err(io_error)?;
+        if batch_short {
+            // the chunks after the short one were written anyway, so the
+            // object no longer matches any prefix of the data
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the server wrote a partial chunk in a batch",
+            ));
+        }
+        Ok(written)
+    }
+
+    /// Returns the chunk size rounded down to a whole number of pages, if it
+    /// spans at least one.
+    fn write_chunk_size(&self) -> usize {
+        if self.chunk_size >= self.page_size {
+            self.chunk_size - self.chunk_size % self.page_size
+        } else {
+            self.chunk_size
+        }
+    }
+
+    /// Returns the server side position.
+    fn tell_raw(&mut self) -> io::Result<u64> {
+        if self.track_position {
+            Ok(self.position)
+        } else {
+            self.seek_raw(io::SeekFrom::Current(0))
+        }
+    }
+
+    /// Returns the number of bytes between the server side position and the
+    /// end of the object, in a single round trip.
+    fn remaining_raw(&mut self) -> io::Result<u64> {
+        // the OFFSET 0 fences keep the server from reordering the calls: find
+        // the position, seek to the end, then seek back
+        let sql = if self.capabilities.has_64_bit() {
+            "SELECT s.size - pg_catalog.lo_lseek64($1, s.pos, 0)
+             FROM (SELECT p.pos, pg_catalog.lo_lseek64($1, 0, 2) AS size
+                   FROM (SELECT pg_catalog.lo_tell64($1) AS pos OFFSET 0) p
+                   OFFSET 0) s"
+        } else {
+            "SELECT (s.size - pg_catalog.lo_lseek($1, s.pos, 0))::INT8
+             FROM (SELECT p.pos, pg_catalog.lo_lseek($1, 0, 2) AS size
+                   FROM (SELECT pg_catalog.lo_tell($1) AS pos OFFSET 0) p
+                   OFFSET 0) s"
+        };
+        let rows = self.query(sql, &[&self.fd]).map_err(io_error)?;
+        let remaining: i64 = first_column(&rows)?;
+        Ok(cmp::max(remaining, 0) as u64)
+    }
+
+    /// Seeks using the tracked position, returning `None` if that can't be
+    /// done without asking the server.
+    fn seek_tracked(&mut self, pos: io::SeekFrom) -> io::Result<Option<u64>> {
+        let unread = (self.read_buf.len() - self.read_pos) as u64;
+        let current = self.position + self.write_buf.len() as u64 - unread;
+        let target = match pos {
+            io::SeekFrom::Start(pos) => pos,
+            io::SeekFrom::Current(offset) => seek_position(current, offset)?,
+            io::SeekFrom::End(_) => return Ok(None),
+        };
+
+        if target == current {
+            return Ok(Some(target));
+        }
+        // move around within the read buffer
+        let buf_start = self.position - self.read_buf.len() as u64;
+        if self.write_buf.is_empty() && target >= buf_start && target <= self.position {
+            self.read_pos = (target - buf_start) as usize;
+            return Ok(Some(target));
+        }
+        self.read_buf.clear();
+        self.read_pos = 0;
+        self.flush_write_buf()?;
+        if target == self.position {
+            return Ok(Some(target));
+        }
+        self.seek_raw(io::SeekFrom::Start(target)).map(Some)
+    }
+
+    fn seek_raw(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (kind, pos) = match pos {
+            io::SeekFrom::Start(pos) => {
+                let pos = if pos <= i64::max_value as u64 {
+                    pos as i64
+                } else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "cannot seek more than 2^63 bytes",
+                    ));
+                };
+                (0, pos)
+            }
+            io::SeekFrom::Current(pos) => (1, pos),
+            io::SeekFrom::End(pos) => (2, pos),
+        };
+
+        if self.capabilities.has_64_bit() {
+            let rows = self
+                .query(
+                    "SELECT pg_catalog.lo_lseek64($1, $2, $3)",
+                    &[&self.fd, &pos, &kind],
+                )
+                .map_err(io_error)?;
+            let pos: i64 = first_column(&rows)?;
+            self.position = pos as u64;
+            Ok(pos as u64)
+        } else {
+            let pos = if pos <= i32::max_value() as i64 {
+                pos as i32
+            } else {
+                return Err(Unsupported::new("objects larger than 2GB").into());
+            };
+            let rows = self
+                .query(
+                    "SELECT pg_catalog.lo_lseek($1, $2, $3)",
+                    &[&self.fd, &pos, &kind],
+                )
+                .map_err(io_error)?;
+            let pos: i32 = first_column(&rows)?;
+            self.position = pos as u64;
+            Ok(pos as u64)
+        }
     }
 
     /// Consumes the `LargeObject`, cleaning up server side state.
@@ -186,80 +1346,519 @@ impl<'a> LargeObject<'a> {
     pub fn finish(mut self) -> Result<()> {
         self.finish_inner()
     }
+
+    /// Like `finish`, but reports how much buffered data may have been lost
+    /// if it fails.
+    pub fn try_finish(mut self) -> result::Result<(), FinishError> {
+        if self.finished {
+            return Ok(());
+        }
+
+        let buffered = self.write_buf.len();
+        let flushed = self.flush_write_buf();
+        let unwritten = if flushed.is_err() { buffered } else { 0 };
+        self.close(flushed).map_err(|e| FinishError {
+            error: e,
+            unwritten: unwritten,
+        })
+    }
+}
+
+/// The error returned by `LargeObject::try_finish`.
+#[derive(Debug)]
+pub struct FinishError {
+    error: postgres::Error,
+    unwritten: usize,
+}
+
+impl fmt::Display for FinishError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.error, fmt)?;
+        if self.unwritten > 0 {
+            write!(fmt, " ({} buffered bytes may not have been written)", self.unwritten)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for FinishError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<FinishError> for postgres::Error {
+    fn from(e: FinishError) -> postgres::Error {
+        e.error
+    }
+}
+
+impl FinishError {
+    /// Returns the underlying error.
+    pub fn error(&self) -> &postgres::Error {
+        &self.error
+    }
+
+    /// Consumes the `FinishError`, returning the underlying error.
+    pub fn into_error(self) -> postgres::Error {
+        self.error
+    }
+
+    /// Returns the number of buffered bytes which were being written when
+    /// the error occurred.
+    ///
+    /// Some of them may have reached the object, but none can be relied on
+    /// to have. If this is 0, all data written to the handle was sent to the
+    /// server before the error.
+    pub fn unwritten(&self) -> usize {
+        self.unwritten
+    }
+}
+
+/// Statistics about a transfer of data to or from a large object.
+#[derive(Debug, Clone, Default)]
+pub struct TransferStats {
+    /// The number of bytes transferred.
+    pub bytes: u64,
+    /// The number of chunks of data sent or received.
+    pub chunks: u64,
+    /// The number of statements executed to transfer the data.
+    pub round_trips: u64,
+    /// The time taken by the transfer.
+    pub elapsed: Duration,
+}
+
+impl TransferStats {
+    /// Returns the effective transfer rate in bytes per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1e9;
+        if secs == 0. {
+            0.
+        } else {
+            self.bytes as f64 / secs
+        }
+    }
+
+    /// Returns the effective transfer rate in megabytes (10^6 bytes) per
+    /// second.
+    pub fn megabytes_per_second(&self) -> f64 {
+        self.bytes_per_second() / 1e6
+    }
+
+    /// Adds the counts and time of another transfer to these stats.
+    pub fn add(&mut self, other: &TransferStats) {
+        self.bytes += other.bytes;
+        self.chunks += other.chunks;
+        self.round_trips += other.round_trips;
+        self.elapsed += other.elapsed;
+    }
 }
 
 impl<'a> io::Read for LargeObject<'a> {
-    fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
-        let stmt = self.trans
-            .prepare_cached("SELECT pg_catalog.loread($1, $2)")?;
-        let cap = cmp::min(buf.len(), i32::MAX as usize) as i32;
-        let rows = stmt.query(&[&self.fd, &cap])?;
-        buf.write(rows.get(0).get_bytes(0).unwrap())
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.flush_write_buf()?;
+
+        // large reads bypass the buffer entirely if it's empty
+        if self.read_pos >= self.read_buf.len() && buf.len() >= self.read_buf_size() {
+            self.read_buf.clear();
+            self.read_pos = 0;
+            return self.read_raw(buf);
+        }
+
+        let n = {
+            let available = self.fill_buf()?;
+            let n = cmp::min(available.len(), buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let start = buf.len();
+        buf.extend_from_slice(&self.read_buf[self.read_pos..]);
+        self.read_buf.clear();
+        self.read_pos = 0;
+        self.flush_write_buf()?;
+
+        // size the output up front rather than growing it as we go
+        let remaining = self.remaining_raw()? as usize;
+        buf.reserve(remaining);
+
+        let mut nread = 0;
+        while nread < remaining {
+            let cap = cmp::min(remaining - nread, self.chunk_size);
+            let n = self.loread(cap, |data| {
+                buf.extend_from_slice(data);
+                data.len()
+            })?;
+            if n == 0 {
+                break;
+            }
+            nread += n;
+        }
+        Ok(buf.len() - start)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut]) -> io::Result<usize> {
+        // read into a single buffer so the whole request is one loread call
+        let len = bufs.iter().map(|b| b.len()).sum();
+        let mut buf = vec![0; len];
+        let n = io::Read::read(self, &mut buf)?;
+
+        let mut data = &buf[..n];
+        for dst in bufs {
+            if data.is_empty() {
+                break;
+            }
+            let m = cmp::min(dst.len(), data.len());
+            dst[..m].copy_from_slice(&data[..m]);
+            data = &data[m..];
+        }
+        Ok(n)
+    }
+}
+
+impl<'a> io::BufRead for LargeObject<'a> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.read_pos >= self.read_buf.len() {
+            self.flush_write_buf()?;
+            let mut buf = mem::replace(&mut self.read_buf, vec![]);
+            buf.resize(self.read_buf_size(), 0);
+            let n = match self.read_raw(&mut buf) {
+                Ok(n) => n,
+                Err(e) => {
+                    buf.clear();
+                    self.read_buf = buf;
+                    self.read_pos = 0;
+                    return Err(e);
+                }
+            };
+            buf.truncate(n);
+            self.read_buf = buf;
+            self.read_pos = 0;
+        }
+
+        Ok(&self.read_buf[self.read_pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.read_pos = cmp::min(self.read_pos + amt, self.read_buf.len());
     }
 }
 
 impl<'a> io::Write for LargeObject<'a> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let stmt = self.trans
-            .prepare_cached("SELECT pg_catalog.lowrite($1, $2)")?;
-        let cap = cmp::min(buf.len(), i32::MAX as usize);
-        stmt.execute(&[&self.fd, &&buf[..cap]])?;
-        Ok(cap)
+        if self.append_pending {
+            io::Seek::seek(self, io::SeekFrom::End(0))?;
+            self.append_pending = false;
+        }
+        self.discard_read_buf()?;
+        if self.write_buf.len() + buf.len() > self.write_buf_size {
+            self.flush_write_buf()?;
+        }
+        if buf.len() >= self.write_buf_size {
+            // write_all retries whatever the server didn't take
+            self.write_raw(buf)
+        } else {
+            self.write_buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let buf = bufs.iter().fold(vec![], |mut buf, b| {
+            buf.extend_from_slice(b);
+            buf
+        });
+        self.write(&buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+        self.flush_write_buf()
+    }
+}
+
+impl<'a> io::Seek for LargeObject<'a> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.append_pending = self.append;
+        if self.track_position {
+            if let Some(pos) = self.seek_tracked(pos)? {
+                return Ok(pos);
+            }
+        }
+
+        // the server side position is ahead of ours by the buffered data
+        let pos = match pos {
+            io::SeekFrom::Current(pos) => {
+                io::SeekFrom::Current(pos - (self.read_buf.len() - self.read_pos) as i64)
+            }
+            pos => pos,
+        };
+        self.read_buf.clear();
+        self.read_pos = 0;
+        self.flush_write_buf()?;
+        self.seek_raw(pos)
+    }
+}
+
+const DEFAULT_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+const MAX_CHUNK_SIZE: usize = (1 << 30) - 1;
+const READ_BUF_SIZE: usize = 8 * 1024;
+
+/// Tracks the transfer size used by the copy helpers, aiming for round trips
+/// which are long enough to amortize their latency but short enough to keep
+/// memory use and stalls reasonable.
+struct AdaptiveChunk {
+    size: usize,
+    min: usize,
+    max: usize,
+}
+
+impl AdaptiveChunk {
+    fn new(chunk_size: usize) -> AdaptiveChunk {
+        let max = cmp::min(MAX_COPY_CHUNK_SIZE, chunk_size);
+        let min = cmp::min(MIN_COPY_CHUNK_SIZE, max);
+        AdaptiveChunk {
+            size: cmp::max(min, cmp::min(INITIAL_COPY_CHUNK_SIZE, max)),
+            min: min,
+            max: max,
+        }
+    }
+
+    fn update(&mut self, transferred: usize, elapsed: Duration) {
+        // only full transfers say anything about how fast a larger one would be
+        if transferred == self.size && elapsed < FAST_ROUND_TRIP {
+            self.size = cmp::min(self.size * 2, self.max);
+        } else if elapsed > SLOW_ROUND_TRIP {
+            self.size = cmp::max(self.size / 2, self.min);
+        }
+    }
+}
+
+const MIN_COPY_CHUNK_SIZE: usize = 8 * 1024;
+const INITIAL_COPY_CHUNK_SIZE: usize = 64 * 1024;
+const MAX_COPY_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+const FAST_ROUND_TRIP: Duration = Duration::from_millis(50);
+const SLOW_ROUND_TRIP: Duration = Duration::from_millis(500);
+
+fn quote_identifier(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+fn quote_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Sets the statement timeout for the remainder of the transaction,
+/// returning the previous setting.
+fn set_statement_timeout(
+    trans: &Transaction,
+    statements: Statements,
+    timeout: &str,
+) -> Result<String> {
+    let rows = run_query(
+        trans,
+        statements,
+        "SELECT pg_catalog.current_setting('statement_timeout'), \
+         pg_catalog.set_config('statement_timeout', $1, true)",
+        &[&timeout],
+    )?;
+    first_column(&rows)
+}
+
+/// How a handle prepares the statements it runs.
+#[derive(Debug, Clone, Copy)]
+enum Statements<'a> {
+    /// With the connection's statement cache.
+    Cached,
+    /// As the unnamed statement.
+    Unnamed,
+    /// With a cache provided by the user.
+    Custom(&'a (dyn StatementSource + 'a)),
+}
+
+/// A source of prepared statements for a single connection.
+trait StatementSource: fmt::Debug {
+    fn connection(&self) -> &Connection;
+
+    fn query(&self, sql: &str, params: &[&dyn ToSql]) -> Result<Rows>;
+}
+
+fn run_query(
+    trans: &Transaction,
+    statements: Statements,
+    sql: &str,
+    params: &[&dyn ToSql],
+) -> Result<Rows> {
+    match statements {
+        Statements::Cached => trans.prepare_cached(sql)?.query(params),
+        Statements::Unnamed => trans.query(sql, params),
+        Statements::Custom(cache) => cache.query(sql, params),
+    }
+}
+
+/// Returns the first row of a result, failing rather than panicking if the
+/// server unexpectedly returned none.
+fn first_row(rows: &Rows) -> io::Result<Row> {
+    rows.iter()
+        .next()
+        .ok_or_else(|| unexpected_response("no rows were returned"))
+}
+
+/// Returns a column of a row, failing rather than panicking if it's missing
+/// or of an unexpected type.
+fn column<T: FromSql>(row: &Row, idx: usize) -> Result<T> {
+    match row.get_opt(idx) {
+        Some(value) => value,
+        None => Err(unexpected_response("a column is missing").into()),
+    }
+}
+
+/// Returns the first column of the first row of a result.
+fn first_column<T: FromSql>(rows: &Rows) -> Result<T> {
+    column(&first_row(rows)?, 0)
+}
+
+/// Returns a `BYTEA` column of a row without copying it.
+fn bytes_column<'a>(row: &'a Row, idx: usize) -> io::Result<&'a [u8]> {
+    row.get_bytes(idx)
+        .ok_or_else(|| unexpected_response("a BYTEA column is missing or NULL"))
+}
+
+fn unexpected_response(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response from the server: {}", msg),
+    )
+}
+
+/// Converts an error into an `io::Error` with a kind reflecting its
+/// SQLSTATE, so that generic I/O code reacts to it sensibly.
+///
+/// The original error is wrapped rather than discarded, so `error::Error`
+/// can still recover it.
+fn io_error(e: postgres::Error) -> io::Error {
+    let kind = match e.code() {
+        Some(code) => error_kind(code),
+        None => return io::Error::from(e),
+    };
+    io::Error::new(kind, e)
+}
+
+/// Reads into `buf` until it is full or the reader reaches its end,
+/// returning the number of bytes read.
+fn read_full<R>(r: &mut R, buf: &mut [u8]) -> io::Result<usize>
+where
+    R: ?Sized + io::Read,
+{
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Applies a seek offset to a position, failing rather than going negative
+/// or overflowing.
+fn seek_position(base: u64, offset: i64) -> io::Result<u64> {
+    let pos = if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    };
+    match pos {
+        Some(pos) => Ok(pos),
+        None if offset < 0 => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative position",
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek past the largest position",
+        )),
+    }
+}
+
+/// Appends a range to a list of ranges in ascending order, merging it into
+/// the last one if they touch.
+fn push_range(ranges: &mut Vec<ops::Range<u64>>, range: ops::Range<u64>) {
+    if let Some(last) = ranges.last_mut() {
+        if last.end == range.start {
+            last.end = range.end;
+            return;
+        }
     }
+    ranges.push(range);
 }
 
-impl<'a> io::Seek for LargeObject<'a> {
-    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        let (kind, pos) = match pos {
-            io::SeekFrom::Start(pos) => {
-                let pos = if pos <= i64::max_value as u64 {
-                    pos as i64
-                } else {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "cannot seek more than 2^63 bytes",
-                    ));
-                };
-                (0, pos)
-            }
-            io::SeekFrom::Current(pos) => (1, pos),
-            io::SeekFrom::End(pos) => (2, pos),
-        };
+fn error_kind(code: &SqlState) -> io::ErrorKind {
+    if *code == UNDEFINED_OBJECT {
+        io::ErrorKind::NotFound
+    } else if *code == INSUFFICIENT_PRIVILEGE {
+        io::ErrorKind::PermissionDenied
+    } else if *code == QUERY_CANCELED || *code == LOCK_NOT_AVAILABLE {
+        io::ErrorKind::TimedOut
+    } else if *code == INVALID_PARAMETER_VALUE || *code == PROGRAM_LIMIT_EXCEEDED {
+        io::ErrorKind::InvalidInput
+    } else if *code == ADMIN_SHUTDOWN || *code == CRASH_SHUTDOWN {
+        io::ErrorKind::ConnectionAborted
+    } else {
+        io::ErrorKind::Other
+    }
+}
 
-        if self.has_64 {
-            let stmt = self.trans
-                .prepare_cached("SELECT pg_catalog.lo_lseek64($1, $2, $3)")?;
-            let rows = stmt.query(&[&self.fd, &pos, &kind])?;
-            let pos: i64 = rows.iter().next().unwrap().get(0);
-            Ok(pos as u64)
-        } else {
-            let pos = if pos <= i32::max_value() as i64 {
-                pos as i32
-            } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "cannot seek more than 2^31 bytes",
-                ));
-            };
-            let stmt = self.trans
-                .prepare_cached("SELECT pg_catalog.lo_lseek($1, $2, $3)")?;
-            let rows = stmt.query(&[&self.fd, &pos, &kind])?;
-            let pos: i32 = rows.iter().next().unwrap().get(0);
-            Ok(pos as u64)
+/// Replaces the error raised by a server without the large object functions
+/// with an `Unsupported` error.
+fn check_supported<T>(r: Result<T>) -> Result<T> {
+    match r {
+        Err(ref e) if e.code() == Some(&UNDEFINED_FUNCTION) => {
+            Err(io::Error::from(Unsupported::new("large objects")).into())
         }
+        r => r,
     }
 }
 
-fn parse_version(version: &str) -> (i32, i32) {
-    let version = version.split(' ').next().unwrap();
-    let mut version = version.split('.');
-    let major: i32 = version.next().unwrap().parse().unwrap();
-    let minor: i32 = version.next().unwrap().parse().unwrap();
-    (major, minor)
+/// Formats a timeout as a `statement_timeout` value in milliseconds.
+///
+/// A zero timeout would disable the limit entirely, so it's rounded up.
+fn format_timeout(timeout: Duration) -> String {
+    let ms = timeout.as_secs() * 1000 + u64::from(timeout.subsec_nanos()) / 1_000_000;
+    format!("{}", if ms == 0 { 1 } else { ms })
+}
+
+/// Parses the major and minor version numbers out of a `server_version`.
+///
+/// Besides the plain `9.6.8` and `10.3` forms, this accepts versions with no
+/// minor number like `10`, pre-releases like `14beta1`, and anything trailing
+/// after the numbers, like the ` (Debian 12.3-1)` added by distribution
+/// packages or the suffixes added by hosted forks. A missing minor version is
+/// treated as 0.
+fn parse_version(version: &str) -> io::Result<(i32, i32)> {
+    fn number(s: &str) -> (Option<i32>, &str) {
+        let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        (s[..end].parse().ok(), &s[end..])
+    }
+
+    let (major, rest) = number(version.trim());
+    let minor = if rest.starts_with('.') {
+        number(&rest[1..]).0
+    } else {
+        Some(0)
+    };
+    match (major, minor) {
+        (Some(major), Some(minor)) => Ok((major, minor)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unrecognized server version {:?}", version),
+        )),
+    }
 }
 
 #[cfg(test)]
@@ -378,12 +1977,479 @@ mod test {
         assert_eq!(buf, b"hello\0\0\0\0\0");
     }
 
+    #[test]
+    fn test_chunk_size() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.set_chunk_size(4);
+        assert_eq!(14, lo.write(b"hello world!!!").unwrap());
+
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0; 20];
+        assert_eq!(14, lo.read(&mut buf).unwrap());
+        assert_eq!(&buf[..14], b"hello world!!!");
+    }
+
+    #[test]
+    fn test_buf_read() {
+        use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.write_all(b"hello\nworld!!!\n").unwrap();
+        lo.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut line = String::new();
+        lo.read_line(&mut line).unwrap();
+        assert_eq!(line, "hello\n");
+        assert_eq!(6, lo.seek(SeekFrom::Current(0)).unwrap());
+
+        let mut buf = [0; 5];
+        lo.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        lo.write_all(b"???").unwrap();
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello\nworld???\n");
+    }
+
+    #[test]
+    fn test_write_batch() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.set_chunk_size(4);
+        lo.set_write_batch_size(3);
+        let data = (0..30).collect::<Vec<u8>>();
+        lo.write_all(&data).unwrap();
+        assert_eq!(30, lo.seek(SeekFrom::Current(0)).unwrap());
+
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_write_buffer() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.set_write_buffer_size(8).unwrap();
+        for _ in 0..10 {
+            lo.write_all(b"abc").unwrap();
+        }
+        lo.write_all(b"0123456789").unwrap();
+        assert_eq!(40, lo.seek(SeekFrom::Current(0)).unwrap());
+
+        lo.write_all(b"xyz").unwrap();
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        let mut expected = b"abc".repeat(10);
+        expected.extend_from_slice(b"0123456789xyz");
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_vectored() {
+        use std::io::{IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        let bufs = [IoSlice::new(b"hello "), IoSlice::new(b""), IoSlice::new(b"world")];
+        assert_eq!(11, lo.write_vectored(&bufs).unwrap());
+
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        let mut a = [0; 4];
+        let mut b = [0; 10];
+        let n = {
+            let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+            lo.read_vectored(&mut bufs).unwrap()
+        };
+        assert_eq!(n, 11);
+        assert_eq!(&a, b"hell");
+        assert_eq!(&b[..7], b"o world");
+    }
+
+    #[test]
+    fn test_positioned() {
+        use std::io::{self, Seek, SeekFrom, Write};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.write_all(b"hello world").unwrap();
+        lo.seek(SeekFrom::Start(2)).unwrap();
+
+        lo.write_all_at(b"W", 6).unwrap();
+        let mut buf = [0; 5];
+        lo.read_exact_at(&mut buf, 6).unwrap();
+        assert_eq!(&buf, b"World");
+        assert_eq!(2, lo.seek(SeekFrom::Current(0)).unwrap());
+
+        let mut buf = [0; 10];
+        assert_eq!(3, lo.read_at(&mut buf, 8).unwrap());
+        assert!(lo.read_exact_at(&mut buf, 8).is_err());
+        lo.finish().unwrap();
+
+        let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        let e = lo.write_all_at(b"w", 6).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        lo.read_exact_at(&mut buf[..5], 6).unwrap();
+        assert_eq!(&buf[..5], b"World");
+    }
+
+    #[test]
+    fn test_page_aligned_writes() {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        let page_size = lo.page_size();
+        assert!(page_size > 0);
+        lo.set_chunk_size(page_size * 2 + 100);
+
+        let data = (0..page_size * 5).map(|i| i as u8).collect::<Vec<_>>();
+        lo.write_all_at(&data, 100).unwrap();
+        lo.seek(SeekFrom::Start(100)).unwrap();
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_read_with() {
+        use std::io::{BufRead, Seek, SeekFrom, Write};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.write_all(b"hello world").unwrap();
+        lo.seek(SeekFrom::Start(0)).unwrap();
+
+        let hello = lo.read_with(5, |b| b.to_vec()).unwrap();
+        assert_eq!(hello, b"hello");
+        lo.fill_buf().unwrap();
+        let n = lo.read_with(100, |b| b.len()).unwrap();
+        assert_eq!(n, 6);
+        assert!(lo.read_with(100, |b| b.is_empty()).unwrap());
+    }
+
+    #[test]
+    fn test_read_all() {
+        use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.set_chunk_size(3);
+        lo.write_all(b"hello world").unwrap();
+        lo.seek(SeekFrom::Start(1)).unwrap();
+
+        lo.fill_buf().unwrap();
+        lo.consume(1);
+        assert_eq!(lo.read_all().unwrap(), b"llo world");
+
+        lo.seek(SeekFrom::Start(6)).unwrap();
+        let mut out = b"hello ".to_vec();
+        assert_eq!(5, lo.read_to_end(&mut out).unwrap());
+        assert_eq!(out, b"hello world");
+    }
+
+    #[test]
+    fn test_copy() {
+        use std::io::{Seek, SeekFrom};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        let data = (0..3_000_000).map(|i| i as u8).collect::<Vec<_>>();
+        let stats = lo.copy_from(&mut &data[..]).unwrap();
+        assert_eq!(3_000_000, stats.bytes);
+        assert_eq!(stats.chunks, stats.round_trips);
+
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        let mut out = vec![];
+        let stats = lo.copy_to(&mut out).unwrap();
+        assert_eq!(3_000_000, stats.bytes);
+        assert_eq!(stats.chunks + 1, stats.round_trips);
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_statement_timeout() {
+        use std::time::Duration;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let show = || -> String {
+            trans.query("SHOW statement_timeout", &[]).unwrap().get(0).get(0)
+        };
+        let original = show();
+
+        let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        lo.set_statement_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(show(), "2s");
+        lo.set_statement_timeout(Some(Duration::from_millis(500))).unwrap();
+        assert_eq!(show(), "500ms");
+        lo.finish().unwrap();
+        assert_eq!(show(), original);
+    }
+
+    #[test]
+    fn test_unprepared() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        use OpenOptions;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        ::track::install(&trans).unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = OpenOptions::new()
+            .prepared_statements(false)
+            .open(&trans, oid, Mode::ReadWrite)
+            .unwrap();
+        lo.set_track_changes(true);
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.seek(SeekFrom::Start(6)).unwrap();
+        let mut out = String::new();
+        lo.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "world!!!");
+        lo.truncate(5).unwrap();
+        lo.finish().unwrap();
+
+        let prepared: i64 = trans
+            .query("SELECT count(*) FROM pg_catalog.pg_prepared_statements", &[])
+            .unwrap()
+            .get(0)
+            .get(0);
+        assert_eq!(prepared, 0);
+    }
+
+    #[test]
+    fn test_error_kind() {
+        use std::io::{self, Read};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        trans.execute("SELECT pg_catalog.lo_close($1)", &[&lo.fd()]).unwrap();
+        let e = lo.read(&mut [0; 10]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_drop_policy() {
+        use std::io::Write;
+        use std::sync::{Arc, Mutex};
+
+        use DropPolicy;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        let errors2 = errors.clone();
+        lo.set_drop_policy(DropPolicy::Callback(Arc::new(move |oid, _: &::postgres::Error| {
+            errors2.lock().unwrap().push(oid)
+        })));
+        lo.set_write_buffer_size(100).unwrap();
+        lo.write_all(b"hello").unwrap();
+        trans.execute("SELECT pg_catalog.lo_close($1)", &[&lo.fd()]).unwrap();
+        drop(lo);
+        assert_eq!(*errors.lock().unwrap(), [oid]);
+    }
+
+    #[test]
+    fn test_try_finish() {
+        use std::io::Write;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.set_write_buffer_size(100).unwrap();
+        lo.write_all(b"hello").unwrap();
+        trans.execute("SELECT pg_catalog.lo_close($1)", &[&lo.fd()]).unwrap();
+        let e = lo.try_finish().unwrap_err();
+        assert_eq!(e.unwritten(), 5);
+    }
+
+    #[test]
+    fn test_finish_closes_after_failed_flush() {
+        use std::io::Write;
+        use std::time::Duration;
+
+        use cancel::CancellationToken;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        trans.execute("SET LOCAL statement_timeout = '1min'", &[]).unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.set_statement_timeout(Some(Duration::from_secs(5))).unwrap();
+        lo.set_write_buffer_size(100).unwrap();
+        lo.write_all(b"hello").unwrap();
+        let token = CancellationToken::new();
+        lo.set_cancellation_token(token.clone());
+        token.cancel();
+        let fd = lo.fd();
+        assert!(lo.finish().is_err());
+
+        let rows = trans.query("SHOW statement_timeout", &[]).unwrap();
+        assert_eq!(rows.get(0).get::<_, String>(0), "1min");
+        assert!(trans.execute("SELECT pg_catalog.lo_close($1)", &[&fd]).is_err());
+    }
+
+    #[test]
+    fn test_track_position() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        use OpenOptions;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = OpenOptions::new()
+            .track_position(true)
+            .open(&trans, oid, Mode::ReadWrite)
+            .unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        assert_eq!(lo.seek(SeekFrom::Current(0)).unwrap(), 14);
+        assert_eq!(lo.seek(SeekFrom::Current(-8)).unwrap(), 6);
+        let mut buf = [0; 5];
+        lo.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        assert_eq!(lo.seek(SeekFrom::Current(0)).unwrap(), 11);
+        assert_eq!(lo.seek(SeekFrom::End(-3)).unwrap(), 11);
+        assert_eq!(lo.seek(SeekFrom::Start(0)).unwrap(), 0);
+        let mut out = String::new();
+        lo.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello world!!!");
+        assert_eq!(lo.seek(SeekFrom::Current(0)).unwrap(), 14);
+        assert_eq!(lo.size().unwrap(), 14);
+    }
+
+    #[test]
+    fn test_mode() {
+        assert_eq!(Mode::READ | Mode::WRITE, Mode::ReadWrite);
+        assert!(Mode::ReadWrite.contains(Mode::READ));
+        assert!(!Mode::Read.contains(Mode::WRITE));
+        assert!((Mode::Read & Mode::Write).is_empty());
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mode = Mode::READ | Mode::WRITE;
+        trans.open_large_object(oid, mode).unwrap();
+        trans.open_large_object(oid, mode).unwrap();
+    }
+
+    #[test]
+    fn test_append() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello").unwrap();
+        lo.finish().unwrap();
+
+        let mut lo = trans.open_large_object(oid, Mode::READ | Mode::APPEND).unwrap();
+        assert_eq!(lo.seek(SeekFrom::Current(0)).unwrap(), 5);
+        lo.write_all(b" world").unwrap();
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0; 5];
+        lo.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        lo.write_all(b"!!!").unwrap();
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        let mut out = String::new();
+        lo.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello world!!!");
+    }
+
+    #[test]
+    fn test_allocate() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        use capabilities::Capabilities;
+        use OpenOptions;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.write_all(b"hello").unwrap();
+        lo.allocate(100).unwrap();
+        assert_eq!(lo.size().unwrap(), 100);
+        lo.allocate(10).unwrap();
+        assert_eq!(lo.size().unwrap(), 100);
+        assert_eq!(lo.seek(SeekFrom::Current(0)).unwrap(), 5);
+        lo.finish().unwrap();
+
+        let caps = Capabilities::from_server_version("8.2").unwrap();
+        let mut lo = OpenOptions::new()
+            .capabilities(caps)
+            .open(&trans, oid, Mode::ReadWrite)
+            .unwrap();
+        lo.allocate(200).unwrap();
+        assert_eq!(lo.seek(SeekFrom::Current(0)).unwrap(), 0);
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), 200);
+        assert_eq!(&out[..5], b"hello");
+        assert!(out[5..].iter().all(|&b| b == 0));
+    }
+
     #[test]
     fn test_parse_version() {
-        let version = parse_version("10.3 (Debian 10.3-1.pgdg90+1)");
+        let version = parse_version("10.3 (Debian 10.3-1.pgdg90+1)").unwrap();
         assert_eq!(version, (10, 3));
 
-        let version = parse_version("9.5");
+        let version = parse_version("9.5").unwrap();
         assert_eq!(version, (9, 5));
+
+        let version = parse_version("9.6.8").unwrap();
+        assert_eq!(version, (9, 6));
+
+        let version = parse_version("10").unwrap();
+        assert_eq!(version, (10, 0));
+
+        let version = parse_version("14beta1").unwrap();
+        assert_eq!(version, (14, 0));
+
+        let version = parse_version("11.9-aurora").unwrap();
+        assert_eq!(version, (11, 9));
+
+        assert!(parse_version("").is_err());
+        assert!(parse_version("PostgreSQL").is_err());
     }
 }
@@ -0,0 +1,146 @@
+//! Support for the `lo` type provided by the contrib `lo` extension.
+//!
+//! The extension defines `lo` as a domain over `oid`, which is commonly used
+//! for large object columns by JDBC and Hibernate based applications.
+use postgres::{GenericConnection, Result};
+use postgres::types::{FromSql, IsNull, Kind, Oid, ToSql, Type, OID};
+use std::error::Error;
+use std::result;
+
+use {quote_identifier, quote_literal};
+
+/// A reference to a large object, stored in a column of the `lo` type.
+///
+/// It can also be used with plain `oid` columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Lo(pub Oid);
+
+impl Lo {
+    /// Returns the `Oid` of the referenced large object.
+    pub fn oid(&self) -> Oid {
+        self.0
+    }
+}
+
+impl From<Oid> for Lo {
+    fn from(oid: Oid) -> Lo {
+        Lo(oid)
+    }
+}
+
+fn accepts_lo(ty: &Type) -> bool {
+    if *ty == OID {
+        return true;
+    }
+
+    match *ty.kind() {
+        Kind::Domain(ref base) => ty.name() == "lo" && *base == OID,
+        _ => false,
+    }
+}
+
+impl FromSql for Lo {
+    fn from_sql(_: &Type, raw: &[u8]) -> result::Result<Lo, Box<dyn Error + Sync + Send>> {
+        Oid::from_sql(&OID, raw).map(Lo)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        accepts_lo(ty)
+    }
+}
+
+impl ToSql for Lo {
+    fn to_sql(&self, _: &Type, out: &mut Vec<u8>) -> result::Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.0.to_sql(&OID, out)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        accepts_lo(ty)
+    }
+
+    to_sql_checked!();
+}
+
+/// Installs the `lo` extension in the current database if it is not
+/// already installed.
+pub fn install_extension<C: GenericConnection>(conn: &C) -> Result<()> {
+    conn.batch_execute("CREATE EXTENSION IF NOT EXISTS lo")
+}
+
+fn lo_manage_trigger_name(column: &str) -> String {
+    quote_identifier(&format!("lo_manage_{}", column))
+}
+
+/// Installs a trigger which deletes the large objects referenced by a
+/// column when their rows are updated to reference a different object or
+/// deleted.
+///
+/// The trigger uses the `lo_manage` function of the `lo` extension, which is
+/// installed if necessary. It is named `lo_manage_` followed by the column
+/// name. Names are quoted, so they must match the case of the database
+/// objects exactly.
+pub fn install_lo_manage_trigger<C>(conn: &C, table: &str, column: &str) -> Result<()>
+where
+    C: GenericConnection,
+{
+    install_extension(conn)?;
+    conn.batch_execute(&format!(
+        "CREATE TRIGGER {} BEFORE UPDATE OR DELETE ON {} \
+         FOR EACH ROW EXECUTE PROCEDURE lo_manage({})",
+        lo_manage_trigger_name(column),
+        quote_identifier(table),
+        quote_literal(column)
+    ))
+}
+
+/// Removes a trigger installed by `install_lo_manage_trigger`, if it exists.
+pub fn remove_lo_manage_trigger<C>(conn: &C, table: &str, column: &str) -> Result<()>
+where
+    C: GenericConnection,
+{
+    conn.batch_execute(&format!(
+        "DROP TRIGGER IF EXISTS {} ON {}",
+        lo_manage_trigger_name(column),
+        quote_identifier(table)
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+
+    use LargeObjectExt;
+    use lo::{install_extension, install_lo_manage_trigger, remove_lo_manage_trigger, Lo};
+
+    #[test]
+    fn test_lo_round_trip() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        install_extension(&trans).unwrap();
+
+        let rows = trans.query("SELECT $1::lo, $1::lo::oid", &[&Lo(1234)]).unwrap();
+        let row = rows.get(0);
+        assert_eq!(row.get::<_, Lo>(0), Lo(1234));
+        assert_eq!(row.get::<_, Lo>(1), Lo(1234));
+    }
+
+    #[test]
+    fn test_lo_manage_trigger() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        install_extension(&trans).unwrap();
+        trans.batch_execute("CREATE TEMPORARY TABLE lo_manage_test (data lo)").unwrap();
+        install_lo_manage_trigger(&trans, "lo_manage_test", "data").unwrap();
+
+        let oid = trans.create_large_object().unwrap();
+        trans.execute("INSERT INTO lo_manage_test VALUES ($1)", &[&Lo(oid)]).unwrap();
+        trans.execute("DELETE FROM lo_manage_test", &[]).unwrap();
+        assert!(!trans.list_large_objects().unwrap().contains(&oid));
+
+        remove_lo_manage_trigger(&trans, "lo_manage_test", "data").unwrap();
+        let oid = trans.create_large_object().unwrap();
+        trans.execute("INSERT INTO lo_manage_test VALUES ($1)", &[&Lo(oid)]).unwrap();
+        trans.execute("DELETE FROM lo_manage_test", &[]).unwrap();
+        assert!(trans.list_large_objects().unwrap().contains(&oid));
+    }
+}
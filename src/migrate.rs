@@ -0,0 +1,429 @@
+//! Migration of data between `bytea` columns and large objects.
+use postgres::{Connection, Result};
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::io::Write;
+
+use {bytes_column, first_row, quote_identifier, LargeObjectExt, LargeObjectTransactionExt, Mode};
+use copy::CHUNK_SIZE;
+use progress::Progress;
+
+/// A migration which moves the contents of a `bytea` column into large
+/// objects.
+///
+/// Each non-null `bytea` value is streamed into a new large object, and the
+/// object's `Oid` is written to a separate column of the same row. The
+/// `bytea` column itself is left untouched so that it can be dropped once
+/// the migration has been verified. Rows which already have an `Oid` are
+/// skipped, so an interrupted migration can simply be run again.
+#[derive(Debug, Clone)]
+pub struct ByteaToLargeObject {
+    table: String,
+    bytea_column: String,
+    oid_column: String,
+    batch_size: i64,
+}
+
+impl ByteaToLargeObject {
+    /// Creates a new migration of the `bytea_column` column of `table`,
+    /// storing `Oid`s in `oid_column`.
+    ///
+    /// Names are quoted, so they must match the case of the database
+    /// objects exactly.
+    pub fn new(table: &str, bytea_column: &str, oid_column: &str) -> ByteaToLargeObject {
+        ByteaToLargeObject {
+            table: table.to_owned(),
+            bytea_column: bytea_column.to_owned(),
+            oid_column: oid_column.to_owned(),
+            batch_size: 100,
+        }
+    }
+
+    /// Sets the number of rows migrated in each transaction.
+    ///
+    /// Defaults to 100.
+    pub fn batch_size(&mut self, batch_size: i64) -> &mut ByteaToLargeObject {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Runs the migration, returning the number of rows migrated.
+    ///
+    /// The `Oid` column is added to the table if it does not already exist.
+    pub fn run(&self, conn: &Connection) -> Result<u64> {
+        self.run_with_progress(conn, |_| {})
+    }
+
+    /// Like `run`, but reports the total number of bytes migrated so far to
+    /// `progress` after each chunk.
+    ///
+    /// Bytes migrated in a batch which is later rolled back are included.
+    pub fn run_with_progress<P>(&self, conn: &Connection, mut progress: P) -> Result<u64>
+    where
+        P: Progress,
+    {
+        let table = quote_identifier(&self.table);
+        let bytea_column = quote_identifier(&self.bytea_column);
+        let oid_column = quote_identifier(&self.oid_column);
+
+        let stmt = conn.prepare(
+            "SELECT 1 FROM pg_catalog.pg_attribute
+             WHERE attrelid = $1::TEXT::REGCLASS AND attname = $2 AND NOT attisdropped",
+        )?;
+        if stmt.query(&[&table, &self.oid_column])?.is_empty() {
+            conn.batch_execute(&format!(
+                "ALTER TABLE {} ADD COLUMN {} OID",
+                table,
+                oid_column
+            ))?;
+        }
+
+        let queries = Queries {
+            select: format!(
+                "SELECT ctid::TEXT, pg_catalog.octet_length({1}) FROM {0}
+                 WHERE {2} IS NULL AND {1} IS NOT NULL
+                 LIMIT $1 FOR UPDATE",
+                table,
+                bytea_column,
+                oid_column
+            ),
+            read: format!(
+                "SELECT pg_catalog.substring({}, $2, $3) FROM {} WHERE ctid = $1::TEXT::TID",
+                bytea_column,
+                table
+            ),
+            update: format!(
+                "UPDATE {} SET {} = $1 WHERE ctid = $2::TEXT::TID",
+                table,
+                oid_column
+            ),
+        };
+
+        let mut migrated = 0;
+        let mut bytes = 0;
+        loop {
+            let trans = conn.transaction()?;
+            let n = self.migrate_batch(&trans, &queries, &mut bytes, &mut progress)?;
+            if n == 0 {
+                return Ok(migrated);
+            }
+            trans.commit()?;
+            migrated += n;
+        }
+    }
+
+    fn migrate_batch<P>(
+        &self,
+        trans: &Transaction,
+        queries: &Queries,
+        bytes: &mut u64,
+        progress: &mut P,
+    ) -> Result<u64>
+    where
+        P: Progress,
+    {
+        let rows = trans.prepare_cached(&queries.select)?.query(&[&self.batch_size])?;
+        let read = trans.prepare_cached(&queries.read)?;
+        let update = trans.prepare_cached(&queries.update)?;
+
+        for row in &rows {
+            let ctid: String = row.get(0);
+            let len: i32 = row.get(1);
+
+            let oid = trans.create_large_object()?;
+            let mut lo = trans.open_large_object(oid, Mode::Write)?;
+            let chunk_size = CHUNK_SIZE as i32;
+            // substring offsets are 1-based
+            let mut offset = 1;
+            while offset <= len {
+                let chunk = read.query(&[&ctid, &offset, &chunk_size])?;
+                let row = first_row(&chunk)?;
+                let data = bytes_column(&row, 0)?;
+                lo.write_all(data)?;
+                *bytes += data.len() as u64;
+                progress.progress(*bytes);
+                offset += chunk_size;
+            }
+            lo.finish()?;
+
+            update.execute(&[&oid, &ctid])?;
+        }
+
+        Ok(rows.len() as u64)
+    }
+}
+
+struct Queries {
+    select: String,
+    read: String,
+    update: String,
+}
+
+/// A migration which moves large objects referenced by an `Oid` column into
+/// a `bytea` column.
+///
+/// The data is copied server side with `lo_get`, which requires Postgres 9.4
+/// or newer. Objects larger than the configured maximum size are skipped and
+/// reported so that they can be handled separately. Rows which already have
+/// a `bytea` value are skipped, so an interrupted migration can simply be run
+/// again.
+#[derive(Debug, Clone)]
+pub struct LargeObjectToBytea {
+    table: String,
+    oid_column: String,
+    bytea_column: String,
+    batch_size: i64,
+    max_size: u64,
+    unlink: bool,
+}
+
+/// A summary of the work performed by `LargeObjectToBytea::run`.
+#[derive(Debug, Clone, Default)]
+pub struct LargeObjectToByteaSummary {
+    /// The number of rows migrated.
+    pub migrated: u64,
+    /// The `Oid`s of objects which were skipped because they were larger than
+    /// the maximum size.
+    pub skipped: Vec<Oid>,
+    /// The total size in bytes of the objects migrated.
+    pub bytes: u64,
+}
+
+impl LargeObjectToBytea {
+    /// Creates a new migration of the objects referenced by the `oid_column`
+    /// column of `table`, storing their contents in `bytea_column`.
+    ///
+    /// Names are quoted, so they must match the case of the database
+    /// objects exactly.
+    pub fn new(table: &str, oid_column: &str, bytea_column: &str) -> LargeObjectToBytea {
+        LargeObjectToBytea {
+            table: table.to_owned(),
+            oid_column: oid_column.to_owned(),
+            bytea_column: bytea_column.to_owned(),
+            batch_size: 100,
+            max_size: 1 << 30,
+            unlink: false,
+        }
+    }
+
+    /// Sets the number of distinct objects migrated in each transaction.
+    ///
+    /// Defaults to 100.
+    pub fn batch_size(&mut self, batch_size: i64) -> &mut LargeObjectToBytea {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Sets the size in bytes of the largest object which will be migrated.
+    ///
+    /// Defaults to 1 GiB, the largest value Postgres can store in a field.
+    pub fn max_size(&mut self, max_size: u64) -> &mut LargeObjectToBytea {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Determines if objects are deleted once they have been migrated.
+    ///
+    /// Defaults to `false`.
+    pub fn unlink(&mut self, unlink: bool) -> &mut LargeObjectToBytea {
+        self.unlink = unlink;
+        self
+    }
+
+    /// Runs the migration.
+    ///
+    /// The `bytea` column must already exist.
+    pub fn run(&self, conn: &Connection) -> Result<LargeObjectToByteaSummary> {
+        self.run_with_progress(conn, |_| {})
+    }
+
+    /// Like `run`, but reports the total size of the objects migrated so far
+    /// to `progress` after each object.
+    ///
+    /// Objects migrated in a batch which is later rolled back are included.
+    pub fn run_with_progress<P>(
+        &self,
+        conn: &Connection,
+        mut progress: P,
+    ) -> Result<LargeObjectToByteaSummary>
+    where
+        P: Progress,
+    {
+        let table = quote_identifier(&self.table);
+        let oid_column = quote_identifier(&self.oid_column);
+        let bytea_column = quote_identifier(&self.bytea_column);
+
+        let select = format!(
+            "SELECT ctid::TEXT, {1} FROM {0}
+             WHERE {1} IN (
+                 SELECT DISTINCT {1} FROM {0}
+                 WHERE {1} > $1 AND {2} IS NULL
+                 ORDER BY 1 LIMIT $2
+             ) AND {2} IS NULL
+             ORDER BY {1}
+             FOR UPDATE",
+            table,
+            oid_column,
+            bytea_column
+        );
+        let update = format!(
+            "UPDATE {} SET {} = pg_catalog.lo_get($1) WHERE ctid = $2::TEXT::TID",
+            table,
+            bytea_column
+        );
+
+        let mut summary = LargeObjectToByteaSummary::default();
+        let mut after = 0;
+        loop {
+            let trans = conn.transaction()?;
+            let last = self.migrate_batch(
+                &trans,
+                &select,
+                &update,
+                after,
+                &mut summary,
+                &mut progress,
+            )?;
+            after = match last {
+                Some(last) => last,
+                None => return Ok(summary),
+            };
+            trans.commit()?;
+        }
+    }
+
+    fn migrate_batch<P>(
+        &self,
+        trans: &Transaction,
+        select: &str,
+        update: &str,
+        after: Oid,
+        summary: &mut LargeObjectToByteaSummary,
+        progress: &mut P,
+    ) -> Result<Option<Oid>>
+    where
+        P: Progress,
+    {
+        let rows = trans.prepare_cached(select)?.query(&[&after, &self.batch_size])?;
+        let update = trans.prepare_cached(update)?;
+
+        let mut last = None;
+        let mut fits = false;
+        let mut migrated = vec![];
+        for row in &rows {
+            let ctid: String = row.get(0);
+            let oid: Oid = row.get(1);
+
+            // rows are sorted by Oid, so only check each object's size once
+            if last != Some(oid) {
+                last = Some(oid);
+                let size = trans.open_large_object(oid, Mode::Read)?.size()?;
+                fits = size <= self.max_size;
+                if fits {
+                    summary.bytes += size;
+                    progress.progress(summary.bytes);
+                    migrated.push(oid);
+                } else {
+                    summary.skipped.push(oid);
+                }
+            }
+
+            if fits {
+                update.execute(&[&oid, &ctid])?;
+                summary.migrated += 1;
+            }
+        }
+
+        if self.unlink {
+            for oid in migrated {
+                trans.delete_large_object(oid)?;
+            }
+        }
+
+        Ok(last)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use postgres::types::Oid;
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use migrate::{ByteaToLargeObject, LargeObjectToBytea};
+
+    fn read_all(conn: &Connection, oid: Oid) -> Vec<u8> {
+        use std::io::Read;
+
+        let trans = conn.transaction().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_bytea_to_large_object() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        conn.batch_execute(
+            "CREATE TEMPORARY TABLE bytea_to_lo (id INT PRIMARY KEY, data BYTEA);
+             INSERT INTO bytea_to_lo VALUES (1, 'hello world!!!'), (2, NULL), (3, '')",
+        ).unwrap();
+
+        let migrated = ByteaToLargeObject::new("bytea_to_lo", "data", "data_oid")
+            .batch_size(1)
+            .run(&conn)
+            .unwrap();
+        assert_eq!(migrated, 2);
+
+        let rows = conn.query("SELECT data_oid FROM bytea_to_lo ORDER BY id", &[]).unwrap();
+        let oids = rows.iter().map(|r| r.get(0)).collect::<Vec<Option<Oid>>>();
+        assert_eq!(read_all(&conn, oids[0].unwrap()), b"hello world!!!");
+        assert_eq!(oids[1], None);
+        assert_eq!(read_all(&conn, oids[2].unwrap()), b"");
+
+        conn.delete_large_object(oids[0].unwrap()).unwrap();
+        conn.delete_large_object(oids[2].unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_large_object_to_bytea() {
+        use std::io::Write;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let small = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(small, Mode::Write).unwrap();
+        lo.write_all(b"hello").unwrap();
+        lo.finish().unwrap();
+        let large = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(large, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.finish().unwrap();
+        trans.commit().unwrap();
+
+        conn.batch_execute(
+            "CREATE TEMPORARY TABLE lo_to_bytea (id INT PRIMARY KEY, data_oid OID, data BYTEA)",
+        ).unwrap();
+        conn.execute(
+            "INSERT INTO lo_to_bytea VALUES (1, $1, NULL), (2, $2, NULL), (3, $1, NULL)",
+            &[&small, &large],
+        ).unwrap();
+
+        let summary = LargeObjectToBytea::new("lo_to_bytea", "data_oid", "data")
+            .batch_size(1)
+            .max_size(10)
+            .unlink(true)
+            .run(&conn)
+            .unwrap();
+        assert_eq!(summary.migrated, 2);
+        assert_eq!(summary.skipped, [large]);
+
+        let rows = conn.query("SELECT data FROM lo_to_bytea ORDER BY id", &[]).unwrap();
+        let data = rows.iter().map(|r| r.get(0)).collect::<Vec<Option<Vec<u8>>>>();
+        assert_eq!(data, [Some(b"hello".to_vec()), None, Some(b"hello".to_vec())]);
+        assert!(!conn.list_large_objects().unwrap().contains(&small));
+
+        conn.delete_large_object(large).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+//! Transfers of single large objects over multiple connections.
+//!
+//! A single connection can only have one `loread` in flight at a time, which
+//! caps throughput well below what the server can deliver. A
+//! `ParallelTransfer` splits an object into fixed size pieces which are
+//! fetched concurrently with `lo_get` on separate connections, and
+//! reassembled in order. Uploads work the same way in reverse, writing
+//! disjoint pieces concurrently with `lo_put`. This requires Postgres 9.4 or
+//! newer.
+//!
+//! The worker transactions of a download all import a snapshot exported by
+//! the coordinating transaction, so they see a consistent version of the
+//! object even if it is modified concurrently.
+use postgres::{GenericConnection, Result};
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use {bytes_column, first_column, first_row, quote_literal, read_full, LargeObjectExt,
+     LargeObjectTransactionExt, Mode};
+
+/// The default number of connections used by a transfer.
+pub const DEFAULT_JOBS: usize = 4;
+
+/// The default size of the pieces an object is split into.
+pub const DEFAULT_PIECE_SIZE: usize = 1024 * 1024;
+
+/// A builder for parallel transfers of large objects.
+#[derive(Debug, Clone)]
+pub struct ParallelTransfer {
+    jobs: usize,
+    piece_size: usize,
+}
+
+impl Default for ParallelTransfer {
+    fn default() -> ParallelTransfer {
+        ParallelTransfer {
+            jobs: DEFAULT_JOBS,
+            piece_size: DEFAULT_PIECE_SIZE,
+        }
+    }
+}
+
+impl ParallelTransfer {
+    /// Creates a new transfer using `DEFAULT_JOBS` connections and pieces of
+    /// `DEFAULT_PIECE_SIZE` bytes.
+    pub fn new() -> ParallelTransfer {
+        ParallelTransfer::default()
+    }
+
+    /// Sets the number of worker connections.
+    pub fn jobs(&mut self, jobs: usize) -> &mut ParallelTransfer {
+        self.jobs = cmp::max(jobs, 1);
+        self
+    }
+
+    /// Sets the size of the pieces the object is split into.
+    pub fn piece_size(&mut self, piece_size: usize) -> &mut ParallelTransfer {
+        self.piece_size = cmp::max(1, cmp::min(piece_size, i32::max_value() as usize));
+        self
+    }
+
+    /// Downloads the large object with the specified `Oid` into a writer,
+    /// returning its size.
+    ///
+    /// `connect` is called once to open the coordinating connection, and
+    /// once from each worker thread. It should return a fresh connection
+    /// each time rather than a `Transaction`, since the workers' transactions
+    /// must be top level in order to import the snapshot.
+    ///
+    /// To bound memory use, workers will not fetch pieces more than twice the
+    /// number of jobs ahead of the last piece written.
+    pub fn download<F, C, W>(&self, connect: F, oid: Oid, w: &mut W) -> Result<u64>
+    where
+        F: Fn() -> Result<C> + Sync,
+        C: GenericConnection,
+        W: ?Sized + Write,
+    {
+        let conn = connect()?;
+        let trans = conn.transaction()?;
+        trans.batch_execute("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")?;
+        let snapshot: String =
+            first_column(&trans.query("SELECT pg_catalog.pg_export_snapshot()", &[])?)?;
+        let size = trans.open_large_object(oid, Mode::Read)?.size()?;
+
+        let piece_size = self.piece_size as u64;
+        let pieces = (size + piece_size - 1) / piece_size;
+        if pieces == 0 {
+            return Ok(0);
+        }
+        let jobs = cmp::min(self.jobs as u64, pieces) as usize;
+
+        let state = State {
+            next: AtomicU64::new(0),
+            written: Mutex::new(0),
+            cond: Condvar::new(),
+            window: 2 * jobs as u64,
+            aborted: AtomicBool::new(false),
+        };
+        let piece = Piece {
+            oid: oid,
+            size: size,
+            piece_size: piece_size,
+            pieces: pieces,
+        };
+
+        thread::scope(|s| {
+            let (tx, rx) = mpsc::sync_channel(jobs);
+            for _ in 0..jobs {
+                let tx = tx.clone();
+                let (connect, snapshot, state, piece) = (&connect, &snapshot, &state, &piece);
+                s.spawn(move || {
+                    if let Err(e) = fetch_pieces(connect, snapshot, state, piece, &tx) {
+                        let _ = tx.send(Err(e));
+                    }
+                });
+            }
+            drop(tx);
+
+            let r = write_pieces(&state, pieces, &rx, w);
+            if r.is_err() {
+                state.abort();
+            }
+            r
+        })?;
+
+        trans.commit()?;
+        Ok(size)
+    }
+
+    /// Uploads the contents of a reader into a new large object, returning
+    /// its `Oid`.
+    ///
+    /// The reader is read sequentially on the calling thread, and its pieces
+    /// are handed off to workers which each write them with their own
+    /// connection. `connect` is called once to open the coordinating
+    /// connection, and once from each worker thread.
+    ///
+    /// The object must be visible to the workers, so it is created and
+    /// committed up front, and each worker commits its own writes. If the
+    /// upload fails, the object is deleted, but concurrent readers may
+    /// observe it partially written while the upload is in progress. The
+    /// piece size is rounded down to a multiple of the server's page size so
+    /// that no two workers write to the same page.
+    pub fn upload<F, C, R>(&self, connect: F, r: &mut R) -> Result<Oid>
+    where
+        F: Fn() -> Result<C> + Sync,
+        C: GenericConnection,
+        R: ?Sized + Read,
+    {
+        let conn = connect()?;
+        let page_size: i32 = first_column(
+            &conn.query("SELECT pg_catalog.current_setting('block_size')::INT4 / 4", &[])?,
+        )?;
+        let page_size = page_size as usize;
+        let piece_size = cmp::max(self.piece_size - self.piece_size % page_size, page_size);
+        let oid = conn.create_large_object()?;
+
+        let uploaded = thread::scope(|s| {
+            let (tx, rx) = mpsc::sync_channel(self.jobs);
+            // only the workers hold the receiver, so sends fail once they
+            // have all exited
+            let rx = Arc::new(Mutex::new(rx));
+            let workers = (0..self.jobs)
+                .map(|_| {
+                    let rx = rx.clone();
+                    let connect = &connect;
+                    s.spawn(move || put_pieces(connect, oid, &rx))
+                })
+                .collect::<Vec<_>>();
+            drop(rx);
+
+            let mut result = read_pieces(r, piece_size, &tx);
+            drop(tx);
+            for worker in workers {
+                let worker_result = match worker.join() {
+                    Ok(r) => r,
+                    Err(_) => Err(thread_panicked().into()),
+                };
+                // a worker failure explains a failed send, so report it first
+                if let Err(e) = worker_result {
+                    if result.is_ok() || result.as_ref().err().map_or(false, is_disconnected) {
+                        result = Err(e);
+                    }
+                }
+            }
+            result
+        });
+
+        match uploaded {
+            Ok(()) => Ok(oid),
+            Err(e) => {
+                let _ = conn.delete_large_object(oid);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Coordination between the workers and the writing thread.
+struct State {
+    // the index of the next piece to fetch
+    next: AtomicU64,
+    // the number of pieces written so far
+    written: Mutex<u64>,
+    cond: Condvar,
+    window: u64,
+    aborted: AtomicBool,
+}
+
+impl State {
+    /// Blocks until the piece is within the window, returning `false` if the
+    /// transfer has been aborted.
+    fn wait(&self, piece: u64) -> bool {
+        let mut written = self.written.lock().unwrap();
+        while piece >= *written + self.window && !self.aborted.load(Ordering::SeqCst) {
+            written = self.cond.wait(written).unwrap();
+        }
+        !self.aborted.load(Ordering::SeqCst)
+    }
+
+    fn advance(&self, written: u64) {
+        *self.written.lock().unwrap() = written;
+        self.cond.notify_all();
+    }
+
+    fn abort(&self) {
+        let _guard = self.written.lock().unwrap();
+        self.aborted.store(true, Ordering::SeqCst);
+        self.cond.notify_all();
+    }
+}
+
+/// The layout of the object being transferred.
+struct Piece {
+    oid: Oid,
+    size: u64,
+    piece_size: u64,
+    pieces: u64,
+}
+
+impl Piece {
+    fn range(&self, index: u64) -> (i64, i32) {
+        let offset = index * self.piece_size;
+        let len = cmp::min(self.piece_size, self.size - offset);
+        (offset as i64, len as i32)
+    }
+}
+
+fn worker_transaction<'a, C>(conn: &'a C, snapshot: &str) -> Result<Transaction<'a>>
+where
+    C: GenericConnection,
+{
+    let trans = conn.transaction()?;
+    trans.batch_execute(&format!(
+        "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ; SET TRANSACTION SNAPSHOT {}",
+        quote_literal(snapshot)
+    ))?;
+    Ok(trans)
+}
+
+fn fetch_pieces<F, C>(
+    connect: &F,
+    snapshot: &str,
+    state: &State,
+    piece: &Piece,
+    tx: &mpsc::SyncSender<Result<(u64, Vec<u8>)>>,
+) -> Result<()>
+where
+    F: Fn() -> Result<C>,
+    C: GenericConnection,
+{
+    let conn = connect()?;
+    let trans = worker_transaction(&conn, snapshot)?;
+    let stmt = trans.prepare("SELECT pg_catalog.lo_get($1, $2, $3)")?;
+
+    loop {
+        let index = state.next.fetch_add(1, Ordering::SeqCst);
+        if index >= piece.pieces || !state.wait(index) {
+            return Ok(());
+        }
+
+        let (offset, len) = piece.range(index);
+        let rows = stmt.query(&[&piece.oid, &offset, &len])?;
+        let data = bytes_column(&first_row(&rows)?, 0)?.to_vec();
+        // an error here means that the transfer has been aborted
+        if tx.send(Ok((index, data))).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn write_pieces<W>(
+    state: &State,
+    pieces: u64,
+    rx: &mpsc::Receiver<Result<(u64, Vec<u8>)>>,
+    w: &mut W,
+) -> Result<()>
+where
+    W: ?Sized + Write,
+{
+    let mut pending = BTreeMap::new();
+    let mut written = 0;
+    while written < pieces {
+        match rx.recv() {
+            Ok(Ok((index, data))) => {
+                pending.insert(index, data);
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(thread_panicked().into()),
+        }
+
+        while let Some(data) = pending.remove(&written) {
+            w.write_all(&data)?;
+            written += 1;
+        }
+        state.advance(written);
+    }
+    Ok(())
+}
+
+fn read_pieces<R>(r: &mut R, piece_size: usize, tx: &mpsc::SyncSender<(u64, Vec<u8>)>) -> Result<()>
+where
+    R: ?Sized + Read,
+{
+    let mut offset = 0;
+    loop {
+        let mut buf = vec![0; piece_size];
+        let len = read_full(r, &mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        buf.truncate(len);
+
+        if tx.send((offset, buf)).is_err() {
+            return Err(disconnected().into());
+        }
+        offset += len as u64;
+    }
+}
+
+fn put_pieces<F, C>(connect: &F, oid: Oid, rx: &Mutex<mpsc::Receiver<(u64, Vec<u8>)>>) -> Result<()>
+where
+    F: Fn() -> Result<C>,
+    C: GenericConnection,
+{
+    let conn = connect()?;
+    let trans = conn.transaction()?;
+    let stmt = trans.prepare("SELECT pg_catalog.lo_put($1, $2, $3)")?;
+
+    loop {
+        // the lock guard is dropped before the write
+        let piece = rx.lock().unwrap().recv();
+        match piece {
+            Ok((offset, data)) => {
+                stmt.execute(&[&oid, &(offset as i64), &data])?;
+            }
+            Err(_) => break,
+        }
+    }
+    trans.commit()
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "parallel transfer workers exited")
+}
+
+fn is_disconnected(e: &::postgres::Error) -> bool {
+    e.as_io().map_or(false, |e| e.kind() == io::ErrorKind::BrokenPipe)
+}
+
+fn thread_panicked() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "parallel transfer thread panicked")
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::Write;
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use parallel::ParallelTransfer;
+
+    fn connect() -> ::postgres::Result<Connection> {
+        Connection::connect("postgres://postgres@localhost", TlsMode::None)
+    }
+
+    #[test]
+    fn test_download() {
+        let conn = connect().unwrap();
+        let data = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+        let oid = conn.create_large_object().unwrap();
+        {
+            let trans = conn.transaction().unwrap();
+            let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+            lo.write_all(&data).unwrap();
+            lo.finish().unwrap();
+            trans.commit().unwrap();
+        }
+
+        let mut out = vec![];
+        let size = ParallelTransfer::new()
+            .jobs(3)
+            .piece_size(7000)
+            .download(connect, oid, &mut out)
+            .unwrap();
+        assert_eq!(size, 100_000);
+        assert_eq!(out, data);
+
+        conn.delete_large_object(oid).unwrap();
+    }
+
+    #[test]
+    fn test_upload() {
+        use std::io::Read;
+
+        let data = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+        let oid = ParallelTransfer::new()
+            .jobs(3)
+            .piece_size(7000)
+            .upload(connect, &mut &data[..])
+            .unwrap();
+
+        let conn = connect().unwrap();
+        let trans = conn.transaction().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        lo.finish().unwrap();
+        trans.delete_large_object(oid).unwrap();
+        trans.commit().unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+//! Sequential reads with read-ahead.
+//!
+//! Every `loread` call is a round trip to the server, during which a plain
+//! `LargeObject` reader sits idle. A `PrefetchReader` moves its connection
+//! onto a helper thread which reads the next chunk of the object while the
+//! caller is still consuming the previous one.
+use postgres::{GenericConnection, Result};
+use postgres::types::Oid;
+use std::cmp;
+use std::io::{self, BufRead, Read};
+use std::sync::mpsc;
+use std::thread;
+
+use {LargeObjectTransactionExt, Mode};
+
+/// The default number of bytes fetched by each `loread` call.
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// A reader which fetches the next chunk of a large object in the
+/// background.
+///
+/// At most one chunk is in flight at a time, so memory use is bounded by
+/// twice the chunk size. The helper thread holds a transaction open until
+/// the object has been fully read or the reader is dropped.
+#[derive(Debug)]
+pub struct PrefetchReader {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl PrefetchReader {
+    /// Opens the large object with the specified `Oid` for reading.
+    ///
+    /// The connection is moved to a helper thread for the lifetime of the
+    /// reader. This method blocks until the object has been opened so that
+    /// errors opening it can be reported directly.
+    pub fn new<C>(conn: C, oid: Oid, chunk_size: usize) -> Result<PrefetchReader>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        let (opened_tx, opened_rx) = mpsc::channel();
+        // a rendezvous channel, so the thread fetches exactly one chunk ahead
+        let (chunk_tx, chunk_rx) = mpsc::sync_channel(0);
+
+        thread::spawn(move || {
+            let trans = match conn.transaction() {
+                Ok(trans) => trans,
+                Err(e) => {
+                    let _ = opened_tx.send(Err(e));
+                    return;
+                }
+            };
+            let mut lo = match trans.open_large_object(oid, Mode::Read) {
+                Ok(lo) => lo,
+                Err(e) => {
+                    let _ = opened_tx.send(Err(e));
+                    return;
+                }
+            };
+            lo.set_chunk_size(chunk_size);
+            let _ = opened_tx.send(Ok(()));
+
+            loop {
+                let chunk = lo.read_with(chunk_size, |data| data.to_vec());
+                let done = match chunk {
+                    Ok(ref chunk) => chunk.is_empty(),
+                    Err(_) => true,
+                };
+                // an error here means that the reader was dropped
+                if chunk_tx.send(chunk).is_err() || done {
+                    break;
+                }
+            }
+        });
+
+        match opened_rx.recv() {
+            Ok(r) => r?,
+            Err(_) => return Err(thread_panicked().into()),
+        }
+
+        Ok(PrefetchReader {
+            chunks: chunk_rx,
+            buf: vec![],
+            pos: 0,
+            done: false,
+        })
+    }
+}
+
+impl Read for PrefetchReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let available = self.fill_buf()?;
+            let n = cmp::min(available.len(), buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for PrefetchReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.buf.len() && !self.done {
+            match self.chunks.recv() {
+                Ok(Ok(chunk)) => {
+                    self.done = chunk.is_empty();
+                    self.buf = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => {
+                    self.done = true;
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.done = true;
+                    return Err(thread_panicked());
+                }
+            }
+        }
+
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = cmp::min(self.pos + amt, self.buf.len());
+    }
+}
+
+fn thread_panicked() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "large object prefetch thread panicked")
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use prefetch::PrefetchReader;
+
+    #[test]
+    fn test_prefetch() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let oid = conn.create_large_object().unwrap();
+        {
+            let trans = conn.transaction().unwrap();
+            let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+            lo.write_all(b"hello world!!!").unwrap();
+            lo.finish().unwrap();
+            trans.commit().unwrap();
+        }
+
+        let reader = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let mut reader = PrefetchReader::new(reader, oid, 4).unwrap();
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello world!!!");
+
+        conn.delete_large_object(oid).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+//! Progress reporting for long running transfers.
+//!
+//! The copy, backup and migration helpers each have a variant accepting a
+//! `Progress`, which is called with the total number of bytes transferred so
+//! far as the work proceeds. Closures taking a `u64` implement the trait, so
+//! a progress bar can be wired up with something as simple as
+//! `|bytes| bar.set_position(bytes)`.
+use std::io::{self, Read, Write};
+
+/// A receiver of progress updates.
+pub trait Progress {
+    /// Called with the total number of bytes transferred so far.
+    fn progress(&mut self, bytes: u64);
+}
+
+impl<F> Progress for F
+where
+    F: FnMut(u64),
+{
+    fn progress(&mut self, bytes: u64) {
+        self(bytes)
+    }
+}
+
+/// A reader or writer reporting the number of bytes passed through it to a
+/// `Progress`.
+#[derive(Debug)]
+pub struct ProgressStream<T, P> {
+    inner: T,
+    progress: P,
+    total: u64,
+}
+
+impl<T, P> ProgressStream<T, P>
+where
+    P: Progress,
+{
+    /// Wraps a reader or writer, starting the count at `total`.
+    pub fn new(inner: T, progress: P, total: u64) -> ProgressStream<T, P> {
+        ProgressStream {
+            inner: inner,
+            progress: progress,
+            total: total,
+        }
+    }
+
+    /// Returns the total number of bytes counted.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the underlying reader or writer.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn advance(&mut self, n: usize) {
+        if n > 0 {
+            self.total += n as u64;
+            self.progress.progress(self.total);
+        }
+    }
+}
+
+impl<T, P> Read for ProgressStream<T, P>
+where
+    T: Read,
+    P: Progress,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.advance(n);
+        Ok(n)
+    }
+}
+
+impl<T, P> Write for ProgressStream<T, P>
+where
+    T: Write,
+    P: Progress,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.advance(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+
+    use progress::ProgressStream;
+
+    #[test]
+    fn test_progress_stream() {
+        let mut updates = vec![];
+        {
+            let mut reader = ProgressStream::new(&b"hello world"[..], |n| updates.push(n), 10);
+            let mut buf = [0; 5];
+            reader.read_exact(&mut buf).unwrap();
+            let mut writer = ProgressStream::new(io::sink(), |_| {}, 0);
+            io::copy(&mut reader, &mut writer).unwrap();
+            writer.flush().unwrap();
+            assert_eq!(writer.total(), 6);
+            assert_eq!(reader.total(), 21);
+        }
+        assert_eq!(updates, vec![15, 21]);
+    }
+}
@@ -0,0 +1,242 @@
+//! Support for serving HTTP `Range` requests from large objects.
+//!
+//! Only single byte ranges are supported. Requests for multiple ranges are
+//! treated as requests for the entire object, which RFC 7233 permits.
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// An inclusive range of bytes within an object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    /// The offset of the first byte in the range.
+    pub start: u64,
+    /// The offset of the last byte in the range.
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// Returns the number of bytes in the range.
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// The interpretation of a `Range` header against an object of a known size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// The entire object should be returned with a 200 status.
+    ///
+    /// This is the case when no header was provided, or it was malformed or
+    /// requested multiple ranges.
+    Full,
+    /// The specified range should be returned with a 206 status.
+    Partial(ByteRange),
+    /// The range cannot be satisfied, and a 416 status should be returned.
+    Unsatisfiable,
+}
+
+fn parse_u64(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Interprets the value of a `Range` header for an object of the specified
+/// size.
+pub fn parse_range(header: Option<&str>, size: u64) -> RangeRequest {
+    let header = match header {
+        Some(header) => header.trim(),
+        None => return RangeRequest::Full,
+    };
+
+    let spec = match header.find('=') {
+        Some(idx) if header[..idx].trim().eq_ignore_ascii_case("bytes") => header[idx + 1..].trim(),
+        _ => return RangeRequest::Full,
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+
+    let idx = match spec.find('-') {
+        Some(idx) => idx,
+        None => return RangeRequest::Full,
+    };
+    let (first, last) = (spec[..idx].trim(), spec[idx + 1..].trim());
+
+    if first.is_empty() {
+        let suffix = match parse_u64(last) {
+            Some(suffix) => suffix,
+            None => return RangeRequest::Full,
+        };
+        if suffix == 0 || size == 0 {
+            return RangeRequest::Unsatisfiable;
+        }
+        return RangeRequest::Partial(ByteRange {
+            start: size.saturating_sub(suffix),
+            end: size - 1,
+        });
+    }
+
+    let start = match parse_u64(first) {
+        Some(start) => start,
+        None => return RangeRequest::Full,
+    };
+    let end = if last.is_empty() {
+        None
+    } else {
+        match parse_u64(last) {
+            Some(end) if end >= start => Some(end),
+            _ => return RangeRequest::Full,
+        }
+    };
+
+    if start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+    let end = match end {
+        Some(end) if end < size => end,
+        _ => size - 1,
+    };
+    RangeRequest::Partial(ByteRange {
+        start: start,
+        end: end,
+    })
+}
+
+/// A reader limited to the portion of an object selected by a `Range`
+/// header.
+///
+/// It provides the status code and headers needed to respond to the request.
+/// The reader is empty if the range was unsatisfiable.
+#[derive(Debug)]
+pub struct RangeReader<R> {
+    inner: io::Take<R>,
+    request: RangeRequest,
+    total_size: u64,
+}
+
+impl<R> RangeReader<R>
+where
+    R: Read + Seek,
+{
+    /// Interprets a `Range` header against a readable object, positioning it
+    /// at the start of the requested range.
+    pub fn new(mut inner: R, header: Option<&str>) -> io::Result<RangeReader<R>> {
+        let total_size = inner.seek(SeekFrom::End(0))?;
+        let request = parse_range(header, total_size);
+        let (start, len) = match request {
+            RangeRequest::Full => (0, total_size),
+            RangeRequest::Partial(range) => (range.start, range.len()),
+            RangeRequest::Unsatisfiable => (total_size, 0),
+        };
+        inner.seek(SeekFrom::Start(start))?;
+
+        Ok(RangeReader {
+            inner: inner.take(len),
+            request: request,
+            total_size: total_size,
+        })
+    }
+}
+
+impl<R> RangeReader<R> {
+    /// Returns the interpretation of the `Range` header.
+    pub fn request(&self) -> RangeRequest {
+        self.request
+    }
+
+    /// Returns the HTTP status code of the response: 200, 206, or 416.
+    pub fn status(&self) -> u16 {
+        match self.request {
+            RangeRequest::Full => 200,
+            RangeRequest::Partial(_) => 206,
+            RangeRequest::Unsatisfiable => 416,
+        }
+    }
+
+    /// Returns the total size of the object.
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    /// Returns the number of bytes which will be read, for use as the
+    /// `Content-Length` of the response.
+    pub fn content_length(&self) -> u64 {
+        match self.request {
+            RangeRequest::Full => self.total_size,
+            RangeRequest::Partial(range) => range.len(),
+            RangeRequest::Unsatisfiable => 0,
+        }
+    }
+
+    /// Returns the value of the `Content-Range` header of the response, if
+    /// one should be sent.
+    pub fn content_range(&self) -> Option<String> {
+        match self.request {
+            RangeRequest::Full => None,
+            RangeRequest::Partial(range) => {
+                Some(format!("bytes {}-{}/{}", range.start, range.end, self.total_size))
+            }
+            RangeRequest::Unsatisfiable => Some(format!("bytes */{}", self.total_size)),
+        }
+    }
+
+    /// Returns the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+impl<R> Read for RangeReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use range::{parse_range, ByteRange, RangeReader, RangeRequest};
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range(None, 10), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes=0-4"), 10), RangeRequest::Partial(ByteRange { start: 0, end: 4 }));
+        assert_eq!(parse_range(Some("bytes=5-"), 10), RangeRequest::Partial(ByteRange { start: 5, end: 9 }));
+        assert_eq!(parse_range(Some("bytes=5-100"), 10), RangeRequest::Partial(ByteRange { start: 5, end: 9 }));
+        assert_eq!(parse_range(Some("bytes=-3"), 10), RangeRequest::Partial(ByteRange { start: 7, end: 9 }));
+        assert_eq!(parse_range(Some("bytes=-30"), 10), RangeRequest::Partial(ByteRange { start: 0, end: 9 }));
+        assert_eq!(parse_range(Some("bytes=10-"), 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-0"), 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=4-2"), 10), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes=0-1,4-5"), 10), RangeRequest::Full);
+        assert_eq!(parse_range(Some("items=0-1"), 10), RangeRequest::Full);
+    }
+
+    #[test]
+    fn test_range_reader() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+
+        let mut reader = RangeReader::new(&mut lo, Some("bytes=6-10")).unwrap();
+        assert_eq!(reader.status(), 206);
+        assert_eq!(reader.content_length(), 5);
+        assert_eq!(reader.content_range().unwrap(), "bytes 6-10/14");
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"world");
+
+        let reader = RangeReader::new(&mut lo, Some("bytes=20-")).unwrap();
+        assert_eq!(reader.status(), 416);
+        assert_eq!(reader.content_range().unwrap(), "bytes */14");
+    }
+}
@@ -0,0 +1,207 @@
+//! An append-only log of records stored in a large object.
+//!
+//! Each record is stored as its length, a 4 byte big endian integer,
+//! followed by its data. Records are only ever appended, so the offset of a
+//! record, or of the end of the log, remains valid as a checkpoint from which
+//! to resume reading later.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::u32;
+
+use {read_full, LargeObject, Mode, OpenOptions};
+
+/// A record read from a `RecordLog`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// The offset of the record in the log.
+    pub offset: u64,
+    /// The record's data.
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Returns the offset of the next record in the log.
+    pub fn next_offset(&self) -> u64 {
+        self.offset + 4 + self.data.len() as u64
+    }
+}
+
+/// An append-only log of records stored in a large object.
+#[derive(Debug)]
+pub struct RecordLog<'a> {
+    lo: LargeObject<'a>,
+}
+
+impl<'a> RecordLog<'a> {
+    /// Opens the log stored in the object with the specified `Oid`.
+    pub fn open(trans: &'a Transaction, oid: Oid) -> Result<RecordLog<'a>> {
+        // with the position tracked, finding the end after an append is free
+        let lo = OpenOptions::new()
+            .track_position(true)
+            .open(trans, oid, Mode::READ | Mode::APPEND)?;
+        Ok(RecordLog { lo: lo })
+    }
+
+    /// Appends a record to the log, returning its offset.
+    pub fn append(&mut self, data: &[u8]) -> Result<u64> {
+        if data.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "records must be smaller than 4GB",
+            ).into());
+        }
+
+        let mut buf = Vec::with_capacity(4 + data.len());
+        let len = data.len() as u32;
+        let prefix = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        buf.extend_from_slice(&prefix);
+        buf.extend_from_slice(data);
+        self.lo.write_all(&buf)?;
+        let end = self.lo.seek(SeekFrom::Current(0))?;
+        Ok(end - buf.len() as u64)
+    }
+
+    /// Returns the offset of the end of the log, at which the next record
+    /// will be appended.
+    pub fn end(&mut self) -> Result<u64> {
+        self.lo.size()
+    }
+
+    /// Returns an iterator over the records in the log, starting with the
+    /// record at the specified offset.
+    ///
+    /// `offset` must be the offset of a record, or of the end of the log.
+    pub fn records<'b>(&'b mut self, offset: u64) -> Records<'b, 'a> {
+        Records {
+            log: self,
+            offset: offset,
+            done: false,
+        }
+    }
+
+    /// Returns the underlying object.
+    pub fn into_inner(self) -> LargeObject<'a> {
+        self.lo
+    }
+
+    /// Consumes the log, closing the underlying object.
+    pub fn finish(self) -> Result<()> {
+        self.lo.finish()
+    }
+}
+
+/// An iterator over the records in a `RecordLog`.
+#[derive(Debug)]
+pub struct Records<'b, 'a: 'b> {
+    log: &'b mut RecordLog<'a>,
+    offset: u64,
+    done: bool,
+}
+
+impl<'b, 'a> Records<'b, 'a> {
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let offset = self.offset;
+        let lo = &mut self.log.lo;
+        lo.seek(SeekFrom::Start(offset))?;
+
+        let mut len = [0; 4];
+        match read_full(lo, &mut len)? {
+            0 => return Ok(None),
+            4 => {}
+            _ => return Err(truncated(offset)),
+        }
+        let len = (u32::from(len[0]) << 24) | (u32::from(len[1]) << 16)
+            | (u32::from(len[2]) << 8) | u32::from(len[3]);
+
+        // don't trust the prefix with an allocation until it's known to fit
+        let size = lo.size()?;
+        if u64::from(len) > size.saturating_sub(offset + 4) {
+            return Err(truncated(offset));
+        }
+
+        let mut data = vec![0; len as usize];
+        lo.read_exact(&mut data).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                truncated(offset)
+            } else {
+                e
+            }
+        })?;
+        Ok(Some(Record {
+            offset: offset,
+            data: data,
+        }))
+    }
+}
+
+impl<'b, 'a> Iterator for Records<'b, 'a> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        if self.done {
+            return None;
+        }
+        match self.read_record() {
+            Ok(Some(record)) => {
+                self.offset = record.next_offset();
+                Some(Ok(record))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn truncated(offset: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("truncated record at offset {}", offset),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io;
+
+    use LargeObjectExt;
+    use record_log::RecordLog;
+
+    #[test]
+    fn test_record_log() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+
+        let mut log = RecordLog::open(&trans, oid).unwrap();
+        assert_eq!(log.append(b"hello").unwrap(), 0);
+        let checkpoint = log.append(b"").unwrap();
+        assert_eq!(checkpoint, 9);
+        log.finish().unwrap();
+
+        let mut log = RecordLog::open(&trans, oid).unwrap();
+        assert_eq!(log.append(b"world").unwrap(), 13);
+        assert_eq!(log.end().unwrap(), 22);
+
+        let records = log.records(0).map(|r| r.unwrap().data).collect::<Vec<_>>();
+        assert_eq!(records, [&b"hello"[..], b"", b"world"]);
+
+        let records = log.records(checkpoint).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].offset, 13);
+        assert_eq!(records[1].next_offset(), 22);
+        assert_eq!(log.records(22).count(), 0);
+
+        // not the offset of a record, so the length prefix is garbage
+        let e = log.records(1).next().unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,586 @@
+//! Resumable transfers of large objects.
+//!
+//! A `ResumableUpload` writes an object in segments, committing each one in
+//! its own transaction and reporting an `UploadProgress` checkpoint after
+//! it. If the upload is interrupted, it can be resumed from the last
+//! checkpoint rather than starting over.
+//!
+//! Checkpoints record the offset reached along with an MD5 digest of the
+//! data just before it, computed server side, so that a resumed upload can
+//! verify that the object still ends with the data it last wrote.
+//!
+//! Uploads can also resume themselves automatically after a connection
+//! failure with `ResumableUpload::start_with_failover`, which makes long
+//! ingests survive a failover of the primary.
+//!
+//! A `ResumableDownload` works the same way in the other direction. When it
+//! is resumed, it checks that the object is unchanged and that the local
+//! copy ends with the same data as the object does at the checkpoint before
+//! continuing from there.
+use postgres::{GenericConnection, Result};
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+use {bytes_column, first_column, first_row, LargeObjectExt, LargeObjectTransactionExt, Mode};
+use retry::RetryPolicy;
+
+/// The default number of bytes written between checkpoints.
+pub const DEFAULT_CHECKPOINT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The number of bytes before a checkpoint's offset covered by its digest.
+pub const DIGEST_SIZE: u64 = 64 * 1024;
+
+/// The progress of a resumable upload.
+///
+/// It is formatted by its `Display` implementation as a single line which
+/// can be parsed back with `FromStr`, for persisting between runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadProgress {
+    /// The `Oid` of the object being uploaded.
+    pub oid: Oid,
+    /// The number of bytes committed so far.
+    pub offset: u64,
+    /// The MD5 digest of the up to `DIGEST_SIZE` bytes before `offset`.
+    pub digest: String,
+}
+
+impl fmt::Display for UploadProgress {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} {} {}", self.oid, self.offset, self.digest)
+    }
+}
+
+impl FromStr for UploadProgress {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<UploadProgress> {
+        let mut fields = s.trim().split(' ');
+        let oid = fields.next().and_then(|s| s.parse().ok());
+        let offset = fields.next().and_then(|s| s.parse().ok());
+        match (oid, offset, fields.next(), fields.next()) {
+            (Some(oid), Some(offset), Some(digest), None) => Ok(UploadProgress {
+                oid: oid,
+                offset: offset,
+                digest: digest.to_owned(),
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid upload progress {:?}", s),
+            )),
+        }
+    }
+}
+
+/// A builder for resumable uploads.
+#[derive(Debug, Clone)]
+pub struct ResumableUpload {
+    checkpoint_size: u64,
+}
+
+impl Default for ResumableUpload {
+    fn default() -> ResumableUpload {
+        ResumableUpload {
+            checkpoint_size: DEFAULT_CHECKPOINT_SIZE,
+        }
+    }
+}
+
+impl ResumableUpload {
+    /// Creates a new upload checkpointing every `DEFAULT_CHECKPOINT_SIZE`
+    /// bytes.
+    pub fn new() -> ResumableUpload {
+        ResumableUpload::default()
+    }
+
+    /// Sets the number of bytes written between checkpoints.
+    pub fn checkpoint_size(&mut self, checkpoint_size: u64) -> &mut ResumableUpload {
+        self.checkpoint_size = cmp::max(checkpoint_size, 1);
+        self
+    }
+
+    /// Uploads the contents of a reader into a new large object.
+    ///
+    /// The object is created and committed before any data is written.
+    /// `checkpoint` is called with the progress of the upload after each
+    /// segment has been committed, and should persist it somewhere. The
+    /// final progress is returned.
+    ///
+    /// Each segment is written in a transaction of its own, so `conn` must
+    /// not be a `Transaction`.
+    pub fn start<C, R, F>(&self, conn: &C, r: &mut R, mut checkpoint: F) -> Result<UploadProgress>
+    where
+        C: GenericConnection,
+        R: ?Sized + Read,
+        F: FnMut(&UploadProgress) -> io::Result<()>,
+    {
+        let oid = {
+            let trans = conn.transaction()?;
+            let oid = trans.create_large_object()?;
+            trans.commit()?;
+            oid
+        };
+        let progress = UploadProgress {
+            oid: oid,
+            offset: 0,
+            digest: tail_digest(&conn.transaction()?, oid, 0)?,
+        };
+        checkpoint(&progress)?;
+        self.write_segments(conn, progress, r, checkpoint)
+    }
+
+    /// Resumes an interrupted upload from a checkpoint.
+    ///
+    /// The object must still end with the data covered by the checkpoint's
+    /// digest. Anything written after the checkpoint is discarded, and the
+    /// reader is seeked to the checkpoint's offset before continuing.
+    pub fn resume<C, R, F>(
+        &self,
+        conn: &C,
+        progress: UploadProgress,
+        r: &mut R,
+        checkpoint: F,
+    ) -> Result<UploadProgress>
+    where
+        C: GenericConnection,
+        R: ?Sized + Read + Seek,
+        F: FnMut(&UploadProgress) -> io::Result<()>,
+    {
+        {
+            let trans = conn.transaction()?;
+            let mut lo = trans.open_large_object(progress.oid, Mode::Write)?;
+            let size = lo.size()?;
+            if size < progress.offset
+                || tail_digest(&trans, progress.oid, progress.offset)? != progress.digest
+            {
+                return Err(mismatch(progress.oid));
+            }
+            if size > progress.offset {
+                lo.truncate(progress.offset as i64)?;
+            }
+            lo.finish()?;
+            trans.commit()?;
+        }
+
+        r.seek(SeekFrom::Start(progress.offset))?;
+        self.write_segments(conn, progress, r, checkpoint)
+    }
+
+    /// Uploads the contents of a reader into a new large object, resuming
+    /// automatically after transient failures such as a failover of the
+    /// primary.
+    ///
+    /// `connect` is called to open a connection for each attempt, and
+    /// `policy` decides which failures are retried. Before resuming, the
+    /// checkpoints reached so far are checked against the object, newest
+    /// first, and the upload continues from the latest one which is still
+    /// intact. Segments which were committed but lost in the failover, as
+    /// can happen when an asynchronous replica is promoted, are rewritten.
+    /// If the object itself was lost, the upload starts over with a new one.
+    pub fn start_with_failover<F, C, R, G>(
+        &self,
+        connect: F,
+        policy: &RetryPolicy,
+        r: &mut R,
+        mut checkpoint: G,
+    ) -> Result<UploadProgress>
+    where
+        F: Fn() -> Result<C>,
+        C: GenericConnection,
+        R: ?Sized + Read + Seek,
+        G: FnMut(&UploadProgress) -> io::Result<()>,
+    {
+        let mut history: Vec<UploadProgress> = vec![];
+        policy.run(|_| {
+            let conn = connect()?;
+            let durable = durable_checkpoint(&conn, &history)?;
+            let mut record = |progress: &UploadProgress| {
+                history.push(progress.clone());
+                checkpoint(progress)
+            };
+            match durable {
+                Some(progress) => self.resume(&conn, progress, r, &mut record),
+                None => {
+                    r.seek(SeekFrom::Start(0))?;
+                    self.start(&conn, r, &mut record)
+                }
+            }
+        })
+    }
+
+    fn write_segments<C, R, F>(
+        &self,
+        conn: &C,
+        mut progress: UploadProgress,
+        r: &mut R,
+        mut checkpoint: F,
+    ) -> Result<UploadProgress>
+    where
+        C: GenericConnection,
+        R: ?Sized + Read,
+        F: FnMut(&UploadProgress) -> io::Result<()>,
+    {
+        loop {
+            let trans = conn.transaction()?;
+            let mut lo = trans.open_large_object(progress.oid, Mode::Write)?;
+            lo.seek(SeekFrom::Start(progress.offset))?;
+            let len = lo.copy_from(&mut (&mut *r).take(self.checkpoint_size))?.bytes;
+            lo.finish()?;
+            if len == 0 {
+                return Ok(progress);
+            }
+
+            let offset = progress.offset + len;
+            let digest = tail_digest(&trans, progress.oid, offset)?;
+            trans.commit()?;
+
+            progress.offset = offset;
+            progress.digest = digest;
+            checkpoint(&progress)?;
+        }
+    }
+}
+
+/// The progress of a resumable download.
+///
+/// Like `UploadProgress`, it can be persisted as a single line with its
+/// `Display` and `FromStr` implementations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadProgress {
+    /// The `Oid` of the object being downloaded.
+    pub oid: Oid,
+    /// The size of the object.
+    pub size: u64,
+    /// The number of bytes written to the destination so far.
+    pub offset: u64,
+    /// The MD5 digest of the up to `DIGEST_SIZE` bytes before `offset`.
+    pub digest: String,
+}
+
+impl fmt::Display for DownloadProgress {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} {} {} {}", self.oid, self.size, self.offset, self.digest)
+    }
+}
+
+impl FromStr for DownloadProgress {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<DownloadProgress> {
+        let mut fields = s.trim().split(' ');
+        let oid = fields.next().and_then(|s| s.parse().ok());
+        let size = fields.next().and_then(|s| s.parse().ok());
+        let offset = fields.next().and_then(|s| s.parse().ok());
+        match (oid, size, offset, fields.next(), fields.next()) {
+            (Some(oid), Some(size), Some(offset), Some(digest), None) => Ok(DownloadProgress {
+                oid: oid,
+                size: size,
+                offset: offset,
+                digest: digest.to_owned(),
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid download progress {:?}", s),
+            )),
+        }
+    }
+}
+
+/// A builder for resumable downloads.
+#[derive(Debug, Clone)]
+pub struct ResumableDownload {
+    checkpoint_size: u64,
+}
+
+impl Default for ResumableDownload {
+    fn default() -> ResumableDownload {
+        ResumableDownload {
+            checkpoint_size: DEFAULT_CHECKPOINT_SIZE,
+        }
+    }
+}
+
+impl ResumableDownload {
+    /// Creates a new download checkpointing every `DEFAULT_CHECKPOINT_SIZE`
+    /// bytes.
+    pub fn new() -> ResumableDownload {
+        ResumableDownload::default()
+    }
+
+    /// Sets the number of bytes read between checkpoints.
+    pub fn checkpoint_size(&mut self, checkpoint_size: u64) -> &mut ResumableDownload {
+        self.checkpoint_size = cmp::max(checkpoint_size, 1);
+        self
+    }
+
+    /// Downloads the large object with the specified `Oid` into a writer.
+    ///
+    /// `checkpoint` is called with the progress of the download each time
+    /// `checkpoint_size` more bytes have been written and the writer has been
+    /// flushed. The final progress is returned.
+    pub fn start<C, W, F>(&self, conn: &C, oid: Oid, w: &mut W, checkpoint: F) -> Result<DownloadProgress>
+    where
+        C: GenericConnection,
+        W: ?Sized + Write,
+        F: FnMut(&DownloadProgress) -> io::Result<()>,
+    {
+        let trans = conn.transaction()?;
+        let size = trans.open_large_object(oid, Mode::Read)?.size()?;
+        let progress = DownloadProgress {
+            oid: oid,
+            size: size,
+            offset: 0,
+            digest: tail_digest(&trans, oid, 0)?,
+        };
+        self.read_segments(&trans, progress, w, checkpoint)
+    }
+
+    /// Resumes an interrupted download from a checkpoint.
+    ///
+    /// The object must have the same size as when the download started and
+    /// still match the checkpoint's digest, and the destination must end with
+    /// the same data as the object at the checkpoint's offset. The
+    /// destination is then seeked to that offset before continuing.
+    pub fn resume<C, W, F>(
+        &self,
+        conn: &C,
+        progress: DownloadProgress,
+        w: &mut W,
+        checkpoint: F,
+    ) -> Result<DownloadProgress>
+    where
+        C: GenericConnection,
+        W: ?Sized + Read + Write + Seek,
+        F: FnMut(&DownloadProgress) -> io::Result<()>,
+    {
+        let trans = conn.transaction()?;
+        let size = trans.open_large_object(progress.oid, Mode::Read)?.size()?;
+        if size != progress.size
+            || progress.offset > size
+            || tail_digest(&trans, progress.oid, progress.offset)? != progress.digest
+        {
+            return Err(mismatch(progress.oid));
+        }
+
+        let len = cmp::min(progress.offset, DIGEST_SIZE);
+        let stmt = trans.prepare_cached("SELECT pg_catalog.lo_get($1, $2, $3)")?;
+        let rows = stmt.query(&[&progress.oid, &((progress.offset - len) as i64), &(len as i32)])?;
+        let row = first_row(&rows)?;
+        let expected = bytes_column(&row, 0)?;
+        let mut local = vec![0; len as usize];
+        w.seek(SeekFrom::Start(progress.offset - len))?;
+        if w.read_exact(&mut local).is_err() || local != expected {
+            return Err(mismatch(progress.oid));
+        }
+
+        w.seek(SeekFrom::Start(progress.offset))?;
+        self.read_segments(&trans, progress, w, checkpoint)
+    }
+
+    fn read_segments<W, F>(
+        &self,
+        trans: &Transaction,
+        mut progress: DownloadProgress,
+        w: &mut W,
+        mut checkpoint: F,
+    ) -> Result<DownloadProgress>
+    where
+        W: ?Sized + Write,
+        F: FnMut(&DownloadProgress) -> io::Result<()>,
+    {
+        let mut lo = trans.open_large_object(progress.oid, Mode::Read)?;
+        lo.seek(SeekFrom::Start(progress.offset))?;
+        loop {
+            let mut segment = 0;
+            while segment < self.checkpoint_size {
+                let len = cmp::min(self.checkpoint_size - segment, READ_SIZE) as usize;
+                let n = lo.read_with(len, |data| w.write_all(data).map(|_| data.len()))??;
+                if n == 0 {
+                    break;
+                }
+                segment += n as u64;
+            }
+            if segment == 0 {
+                return Ok(progress);
+            }
+
+            w.flush()?;
+            progress.offset += segment;
+            progress.digest = tail_digest(trans, progress.oid, progress.offset)?;
+            checkpoint(&progress)?;
+        }
+    }
+}
+
+const READ_SIZE: u64 = 1024 * 1024;
+
+/// Returns the latest checkpoint whose data is still intact in the object.
+fn durable_checkpoint<C>(conn: &C, history: &[UploadProgress]) -> Result<Option<UploadProgress>>
+where
+    C: GenericConnection,
+{
+    let last = match history.last() {
+        Some(last) => last,
+        None => return Ok(None),
+    };
+
+    let trans = conn.transaction()?;
+    let stmt = trans.prepare_cached(
+        "SELECT EXISTS (SELECT 1 FROM pg_catalog.pg_largeobject_metadata WHERE oid = $1)",
+    )?;
+    let exists: bool = first_column(&stmt.query(&[&last.oid])?)?;
+    if !exists {
+        return Ok(None);
+    }
+
+    let size = trans.open_large_object(last.oid, Mode::Read)?.size()?;
+    for progress in history.iter().rev().filter(|p| p.oid == last.oid) {
+        if progress.offset <= size
+            && tail_digest(&trans, progress.oid, progress.offset)? == progress.digest
+        {
+            return Ok(Some(progress.clone()));
+        }
+    }
+    Ok(None)
+}
+
+fn mismatch(oid: Oid) -> ::postgres::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("large object {} does not match the checkpoint", oid),
+    ).into()
+}
+
+/// Returns the MD5 digest of the up to `DIGEST_SIZE` bytes of the object
+/// before `offset`, computed server side.
+fn tail_digest(trans: &Transaction, oid: Oid, offset: u64) -> Result<String> {
+    let len = cmp::min(offset, DIGEST_SIZE);
+    let stmt = trans.prepare_cached("SELECT pg_catalog.md5(pg_catalog.lo_get($1, $2, $3))")?;
+    let rows = stmt.query(&[&oid, &((offset - len) as i64), &(len as i32)])?;
+    first_column(&rows)
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{self, Cursor, Read, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use resume::{DownloadProgress, ResumableDownload, ResumableUpload, UploadProgress};
+    use retry::RetryPolicy;
+
+    #[test]
+    fn test_resumable_upload() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let data = (0..10_000).map(|i| i as u8).collect::<Vec<_>>();
+
+        // fail after the second checkpoint
+        let mut saved = None;
+        let mut upload = ResumableUpload::new();
+        upload.checkpoint_size(3000);
+        let r = upload.start(&conn, &mut Cursor::new(&data), |progress| {
+            saved = Some(progress.to_string());
+            if progress.offset == 6000 {
+                Err(io::Error::new(io::ErrorKind::Other, "interrupted"))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(r.is_err());
+
+        let progress = saved.unwrap().parse::<UploadProgress>().unwrap();
+        assert_eq!(progress.offset, 6000);
+        let done = upload
+            .resume(&conn, progress.clone(), &mut Cursor::new(&data), |_| Ok(()))
+            .unwrap();
+        assert_eq!(done.offset, 10_000);
+
+        let trans = conn.transaction().unwrap();
+        let mut lo = trans.open_large_object(progress.oid, Mode::Read).unwrap();
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        lo.finish().unwrap();
+        trans.delete_large_object(progress.oid).unwrap();
+        trans.commit().unwrap();
+    }
+
+    #[test]
+    fn test_resumable_download() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let data = (0..10_000).map(|i| i as u8).collect::<Vec<_>>();
+        let oid = conn.create_large_object().unwrap();
+        {
+            let trans = conn.transaction().unwrap();
+            let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+            lo.write_all(&data).unwrap();
+            lo.finish().unwrap();
+            trans.commit().unwrap();
+        }
+
+        let mut saved = None;
+        let mut download = ResumableDownload::new();
+        download.checkpoint_size(3000);
+        let mut out = Cursor::new(vec![]);
+        let r = download.start(&conn, oid, &mut out, |progress| {
+            saved = Some(progress.to_string());
+            if progress.offset == 6000 {
+                Err(io::Error::new(io::ErrorKind::Other, "interrupted"))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(r.is_err());
+
+        let progress = saved.unwrap().parse::<DownloadProgress>().unwrap();
+        assert_eq!(progress.offset, 6000);
+        let done = download.resume(&conn, progress, &mut out, |_| Ok(())).unwrap();
+        assert_eq!(done.offset, 10_000);
+        assert_eq!(out.into_inner(), data);
+
+        conn.delete_large_object(oid).unwrap();
+    }
+
+    #[test]
+    fn test_upload_with_failover() {
+        use std::cell::Cell;
+        use std::time::Duration;
+
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let data = (0..10_000).map(|i| i as u8).collect::<Vec<_>>();
+
+        // drop the connection once, after the second checkpoint
+        let failed = Cell::new(false);
+        let connect = || Connection::connect("postgres://postgres@localhost", TlsMode::None);
+        let mut policy = RetryPolicy::new();
+        policy.initial_backoff(Duration::from_millis(1));
+        let mut upload = ResumableUpload::new();
+        upload.checkpoint_size(3000);
+        let mut offsets = vec![];
+        let done = upload
+            .start_with_failover(connect, &policy, &mut Cursor::new(&data), |progress| {
+                offsets.push(progress.offset);
+                if progress.offset == 6000 && !failed.get() {
+                    failed.set(true);
+                    Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+                } else {
+                    Ok(())
+                }
+            })
+            .unwrap();
+        assert_eq!(offsets, [0, 3000, 6000, 9000, 10_000]);
+        assert_eq!(done.offset, 10_000);
+
+        let trans = conn.transaction().unwrap();
+        let mut lo = trans.open_large_object(done.oid, Mode::Read).unwrap();
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        lo.finish().unwrap();
+        trans.delete_large_object(done.oid).unwrap();
+        trans.commit().unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+//! Retrying operations which fail with transient errors.
+//!
+//! Long running batch jobs are vulnerable to serialization failures,
+//! deadlocks, cancelled statements and dropped connections, none of which
+//! indicate a real problem with the work being done. A `RetryPolicy` retries
+//! operations which fail in those ways, waiting with exponential backoff
+//! between attempts.
+//!
+//! An aborted transaction can't be reused, and a reset connection can't
+//! either, so each attempt runs in a new transaction on a new connection.
+use postgres::error::{ADMIN_SHUTDOWN, CANNOT_CONNECT_NOW, QUERY_CANCELED, SERIALIZATION_FAILURE,
+                      T_R_DEADLOCK_DETECTED};
+use postgres::{Error, GenericConnection, Result};
+use postgres::types::Oid;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::thread;
+use std::time::Duration;
+
+use {LargeObjectTransactionExt, Mode};
+
+/// A policy for retrying operations which fail with transient errors.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a policy making up to 5 attempts, waiting 100 milliseconds
+    /// after the first failure and doubling the wait after each subsequent
+    /// one, up to 10 seconds.
+    pub fn new() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Sets the maximum number of attempts, including the first.
+    pub fn max_attempts(&mut self, max_attempts: u32) -> &mut RetryPolicy {
+        self.max_attempts = cmp::max(max_attempts, 1);
+        self
+    }
+
+    /// Sets the time waited after the first failure.
+    pub fn initial_backoff(&mut self, backoff: Duration) -> &mut RetryPolicy {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    /// Sets the maximum time waited between attempts.
+    pub fn max_backoff(&mut self, backoff: Duration) -> &mut RetryPolicy {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// Runs an operation, retrying it if it fails with a transient error.
+    ///
+    /// `f` is passed the number of the attempt, starting from 0. The error
+    /// from the last attempt is returned if they all fail.
+    pub fn run<F, T>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(u32) -> Result<T>,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            match f(attempt) {
+                Ok(v) => return Ok(v),
+                Err(ref e) if attempt + 1 < self.max_attempts && is_transient(e) => {}
+                Err(e) => return Err(e),
+            }
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, self.max_backoff);
+            attempt += 1;
+        }
+    }
+
+    /// Downloads the large object with the specified `Oid` into a writer,
+    /// returning its size.
+    ///
+    /// `connect` is called to open a connection for each attempt. Retried
+    /// attempts resume from the last byte written rather than starting over,
+    /// after verifying that the object's size has not changed in the
+    /// meantime.
+    pub fn download<F, C, W>(&self, connect: F, oid: Oid, w: &mut W) -> Result<u64>
+    where
+        F: Fn() -> Result<C>,
+        C: GenericConnection,
+        W: ?Sized + Write,
+    {
+        let mut w = CountingWriter { inner: w, count: 0 };
+        let mut expected_size = None;
+        self.run(|_| {
+            let conn = connect()?;
+            let trans = conn.transaction()?;
+            let mut lo = trans.open_large_object(oid, Mode::Read)?;
+            let size = lo.size()?;
+            match expected_size {
+                Some(expected) if expected != size => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("large object {} changed size during download", oid),
+                    ).into())
+                }
+                _ => expected_size = Some(size),
+            }
+
+            lo.seek(SeekFrom::Start(w.count))?;
+            lo.copy_to(&mut w)?;
+            lo.finish()?;
+            trans.commit()
+        })?;
+        Ok(w.count)
+    }
+
+    /// Replaces the contents of the large object with the specified `Oid`
+    /// with the contents of a reader, returning the number of bytes written.
+    ///
+    /// `connect` is called to open a connection for each attempt. Each
+    /// attempt rewinds the reader and rewrites the object from the start in
+    /// a single transaction, so a failed attempt leaves nothing behind.
+    pub fn upload<F, C, R>(&self, connect: F, oid: Oid, r: &mut R) -> Result<u64>
+    where
+        F: Fn() -> Result<C>,
+        C: GenericConnection,
+        R: ?Sized + Read + Seek,
+    {
+        self.run(|_| {
+            let conn = connect()?;
+            let trans = conn.transaction()?;
+            r.seek(SeekFrom::Start(0))?;
+            let mut lo = trans.open_large_object(oid, Mode::Write)?;
+            lo.truncate(0)?;
+            let len = lo.copy_from(r)?.bytes;
+            lo.finish()?;
+            trans.commit()?;
+            Ok(len)
+        })
+    }
+}
+
+/// Determines if an error is likely to be transient, such that retrying the
+/// operation which caused it may succeed.
+///
+/// This covers serialization failures, deadlocks, cancelled statements,
+/// server shutdowns and startups, and connections which have been reset.
+pub fn is_transient(e: &Error) -> bool {
+    if let Some(code) = e.code() {
+        return [
+            SERIALIZATION_FAILURE,
+            T_R_DEADLOCK_DETECTED,
+            QUERY_CANCELED,
+            ADMIN_SHUTDOWN,
+            CANNOT_CONNECT_NOW,
+        ].iter()
+            .any(|c| c == code);
+    }
+
+    let e = match e.as_io() {
+        Some(e) => e,
+        None => return false,
+    };
+    // errors from large object I/O wrap the server's error in an io::Error
+    if let Some(e) = e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        return is_transient(e);
+    }
+
+    match e.kind() {
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::TimedOut => true,
+        _ => false,
+    }
+}
+
+/// Tracks how much has been written, so retries know where to resume.
+struct CountingWriter<'a, W: ?Sized + 'a> {
+    inner: &'a mut W,
+    count: u64,
+}
+
+impl<'a, W> Write for CountingWriter<'a, W>
+where
+    W: ?Sized + Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::cell::Cell;
+    use std::io::{self, Cursor};
+    use std::time::Duration;
+
+    use LargeObjectExt;
+    use retry::{is_transient, RetryPolicy};
+
+    fn connect() -> ::postgres::Result<Connection> {
+        Connection::connect("postgres://postgres@localhost", TlsMode::None)
+    }
+
+    #[test]
+    fn test_run() {
+        let mut policy = RetryPolicy::new();
+        policy.max_attempts(3).initial_backoff(Duration::from_millis(1));
+
+        let reset = || io::Error::new(io::ErrorKind::ConnectionReset, "reset").into();
+        let r = policy.run(|attempt| if attempt < 2 { Err(reset()) } else { Ok(attempt) });
+        assert_eq!(r.unwrap(), 2);
+
+        let attempts = Cell::new(0);
+        let r = policy.run(|_| -> ::postgres::Result<()> {
+            attempts.set(attempts.get() + 1);
+            Err(io::Error::new(io::ErrorKind::InvalidData, "bad").into())
+        });
+        let e = r.unwrap_err();
+        assert!(!is_transient(&e));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn test_wrapped_server_error() {
+        let conn = connect().unwrap();
+        let serialization_failure = || {
+            let e = conn.batch_execute(
+                "DO $$ BEGIN RAISE EXCEPTION 'conflict' USING ERRCODE = '40001'; END $$",
+            ).unwrap_err();
+            ::postgres::Error::from(io::Error::new(io::ErrorKind::Other, e))
+        };
+        assert!(is_transient(&serialization_failure()));
+
+        let mut policy = RetryPolicy::new();
+        policy.max_attempts(3).initial_backoff(Duration::from_millis(1));
+        let attempts = Cell::new(0);
+        let r = policy.run(|_| {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 {
+                Err(serialization_failure())
+            } else {
+                Ok(())
+            }
+        });
+        r.unwrap();
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn test_upload_download() {
+        let conn = connect().unwrap();
+        let oid = conn.create_large_object().unwrap();
+
+        let policy = RetryPolicy::new();
+        let data = b"hello world!!!".to_vec();
+        let len = policy.upload(connect, oid, &mut Cursor::new(&data)).unwrap();
+        assert_eq!(len, 14);
+
+        let mut out = vec![];
+        assert_eq!(policy.download(connect, oid, &mut out).unwrap(), 14);
+        assert_eq!(out, data);
+
+        conn.delete_large_object(oid).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! Transfer of large objects to and from S3-compatible object storage.
+//!
+//! Requires the `with-rusoto` feature.
+//!
+//! Uploads use the multipart API and downloads use ranged requests, so both
+//! can be resumed after an interruption without starting over.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use rusoto_s3::{CompleteMultipartUploadRequest, CompletedMultipartUpload, CompletedPart,
+                CreateMultipartUploadRequest, GetObjectError, GetObjectRequest, ListPartsRequest,
+                S3, UploadPartRequest};
+use std::error;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+
+/// The default size of each part of a multipart upload.
+///
+/// S3 requires every part other than the last to be at least 5 MiB.
+pub const DEFAULT_PART_SIZE: usize = 8 * 1024 * 1024;
+
+fn s3_error<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Uploads a large object to the specified bucket and key, returning the
+/// number of bytes uploaded.
+pub fn export_to_s3<C>(trans: &Transaction, oid: Oid, client: &C, bucket: &str, key: &str) -> Result<u64>
+where
+    C: S3,
+{
+    let upload_id = start_upload(client, bucket, key)?;
+    resume_upload(trans, oid, client, bucket, key, &upload_id, DEFAULT_PART_SIZE)
+}
+
+/// Starts a multipart upload to the specified bucket and key, returning its
+/// upload ID.
+///
+/// The upload ID should be recorded so that `resume_upload` can continue the
+/// upload if it is interrupted.
+pub fn start_upload<C>(client: &C, bucket: &str, key: &str) -> Result<String>
+where
+    C: S3,
+{
+    let request = CreateMultipartUploadRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    let output = client.create_multipart_upload(request).sync().map_err(s3_error)?;
+    match output.upload_id {
+        Some(upload_id) => Ok(upload_id),
+        None => Err(s3_error("response did not contain an upload ID").into()),
+    }
+}
+
+/// Uploads a large object as part of a multipart upload, skipping any parts
+/// which have already been uploaded, and completes the upload.
+///
+/// `part_size` must match the value used by any earlier attempts. The number
+/// of bytes uploaded by this call is returned.
+pub fn resume_upload<C>(
+    trans: &Transaction,
+    oid: Oid,
+    client: &C,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    part_size: usize,
+) -> Result<u64>
+where
+    C: S3,
+{
+    let mut parts = vec![];
+    let mut offset = 0;
+    let mut marker = None;
+    // each response lists at most 1000 parts
+    loop {
+        let request = ListPartsRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            upload_id: upload_id.to_owned(),
+            part_number_marker: marker,
+            ..Default::default()
+        };
+        let output = client.list_parts(request).sync().map_err(s3_error)?;
+
+        for part in output.parts.unwrap_or_default() {
+            offset += part.size.unwrap_or(0) as u64;
+            parts.push(CompletedPart {
+                e_tag: part.e_tag,
+                part_number: part.part_number,
+            });
+        }
+
+        match (output.is_truncated, output.next_part_number_marker) {
+            (Some(true), Some(next)) => marker = Some(next),
+            (Some(true), None) => {
+                return Err(s3_error("truncated part listing did not contain a marker").into())
+            }
+            _ => break,
+        }
+    }
+
+    let mut lo = trans.open_large_object(oid, Mode::Read)?;
+    lo.seek(SeekFrom::Start(offset))?;
+
+    let mut uploaded = 0;
+    let mut buf = vec![];
+    loop {
+        buf.clear();
+        (&mut lo).take(part_size as u64).read_to_end(&mut buf)?;
+        // an empty object still needs a single (empty) part
+        if buf.is_empty() && !parts.is_empty() {
+            break;
+        }
+
+        let part_number = parts.len() as i64 + 1;
+        let len = buf.len();
+        let request = UploadPartRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            upload_id: upload_id.to_owned(),
+            part_number: part_number,
+            body: Some(buf.clone().into()),
+            ..Default::default()
+        };
+        let output = client.upload_part(request).sync().map_err(s3_error)?;
+        parts.push(CompletedPart {
+            e_tag: output.e_tag,
+            part_number: Some(part_number),
+        });
+        uploaded += len as u64;
+
+        if len < part_size {
+            break;
+        }
+    }
+    lo.finish()?;
+
+    let request = CompleteMultipartUploadRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        upload_id: upload_id.to_owned(),
+        multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+        ..Default::default()
+    };
+    client.complete_multipart_upload(request).sync().map_err(s3_error)?;
+
+    Ok(uploaded)
+}
+
+/// Downloads the specified key into a new large object, returning its `Oid`.
+pub fn import_from_s3<C>(trans: &Transaction, client: &C, bucket: &str, key: &str) -> Result<Oid>
+where
+    C: S3,
+{
+    let oid = trans.create_large_object()?;
+    resume_download(trans, oid, client, bucket, key)?;
+    Ok(oid)
+}
+
+/// Continues a download into an existing large object, fetching only the
+/// bytes past its current end.
+///
+/// The number of bytes downloaded by this call is returned.
+pub fn resume_download<C>(trans: &Transaction, oid: Oid, client: &C, bucket: &str, key: &str) -> Result<u64>
+where
+    C: S3,
+{
+    let mut lo = trans.open_large_object(oid, Mode::Write)?;
+    let offset = lo.seek(SeekFrom::End(0))?;
+
+    let request = GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        range: Some(format!("bytes={}-", offset)),
+        ..Default::default()
+    };
+    let output = match client.get_object(request).sync() {
+        Ok(output) => output,
+        // the requested range starts past the end of the key, so there's
+        // nothing left to download
+        Err(GetObjectError::Unknown(ref response))
+            if offset > 0 && response.status.as_u16() == 416 =>
+        {
+            return Ok(0)
+        }
+        Err(e) => return Err(s3_error(e).into()),
+    };
+
+    let len = match output.body {
+        Some(body) => io::copy(&mut body.into_blocking_read(), &mut lo)?,
+        None => 0,
+    };
+    lo.finish()?;
+    Ok(len)
+}
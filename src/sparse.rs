@@ -0,0 +1,132 @@
+//! Sparse-aware copying into large objects.
+//!
+//! Postgres stores large objects in pages, and pages which have never been
+//! written take up no space and read back as zeros. Disk images and similar
+//! files often contain long runs of zeros, which needn't be sent to the
+//! server at all: they can be seeked over, and the object extended with
+//! `lo_truncate` if the data ends in one.
+use postgres::Result;
+use std::cmp;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use {read_full, LargeObject};
+
+/// The default minimum length of a run of zeros which is skipped rather than
+/// written.
+pub const DEFAULT_MIN_RUN: usize = 64 * 1024;
+
+const BUF_SIZE: usize = 1024 * 1024;
+
+/// Statistics about a sparse copy.
+#[derive(Debug, Clone, Default)]
+pub struct SparseStats {
+    /// The number of bytes copied, including skipped zeros.
+    pub bytes: u64,
+    /// The number of zero bytes which were skipped rather than written.
+    pub skipped: u64,
+}
+
+/// Returns the ranges of `buf` consisting of at least `min_len` zero bytes.
+pub fn zero_runs(buf: &[u8], min_len: usize) -> Vec<Range<usize>> {
+    let min_len = cmp::max(min_len, 1);
+    let mut runs = vec![];
+    let mut pos = 0;
+    while pos < buf.len() {
+        let start = match buf[pos..].iter().position(|&b| b == 0) {
+            Some(i) => pos + i,
+            None => break,
+        };
+        let end = buf[start..]
+            .iter()
+            .position(|&b| b != 0)
+            .map_or(buf.len(), |i| start + i);
+        if end - start >= min_len {
+            runs.push(start..end);
+        }
+        pos = end;
+    }
+    runs
+}
+
+/// Copies the contents of a reader into an object starting at the handle's
+/// position, skipping runs of at least `min_run` zeros.
+///
+/// The object is truncated at the handle's position first, since skipped
+/// ranges would otherwise keep their old contents. Runs are only detected
+/// within the blocks the reader is read in, so runs shorter than a megabyte
+/// may not always be skipped.
+pub fn copy_sparse<R>(lo: &mut LargeObject, r: &mut R, min_run: usize) -> Result<SparseStats>
+where
+    R: ?Sized + Read,
+{
+    let start = lo.seek(SeekFrom::Current(0))?;
+    if lo.size()? > start {
+        lo.truncate(start as i64)?;
+    }
+
+    let mut stats = SparseStats::default();
+    let mut buf = vec![0; BUF_SIZE];
+    // whether the handle was left positioned past the end of the object
+    let mut in_hole = false;
+    loop {
+        let len = read_full(r, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+        let buf = &buf[..len];
+
+        let mut pos = 0;
+        for run in zero_runs(buf, min_run) {
+            lo.write_all(&buf[pos..run.start])?;
+            lo.seek(SeekFrom::Current((run.end - run.start) as i64))?;
+            stats.skipped += (run.end - run.start) as u64;
+            pos = run.end;
+        }
+        lo.write_all(&buf[pos..])?;
+        in_hole = pos == len;
+        stats.bytes += len as u64;
+    }
+
+    if in_hole {
+        lo.allocate(start + stats.bytes)?;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use sparse::{copy_sparse, zero_runs};
+
+    #[test]
+    fn test_zero_runs() {
+        assert_eq!(zero_runs(b"a\0\0\0b\0c\0\0", 2), vec![1..4, 7..9]);
+        assert!(zero_runs(b"abc", 1).is_empty());
+        assert_eq!(zero_runs(&[0; 5], 5), vec![0..5]);
+    }
+
+    #[test]
+    fn test_copy_sparse() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+
+        let mut data = vec![0; 100_000];
+        data[..5].copy_from_slice(b"hello");
+        data[50_000..50_005].copy_from_slice(b"world");
+        let stats = copy_sparse(&mut lo, &mut Cursor::new(&data), 1000).unwrap();
+        assert_eq!(stats.bytes, 100_000);
+        assert_eq!(stats.skipped, 99_990);
+
+        assert_eq!(lo.size().unwrap(), 100_000);
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        let mut out = vec![];
+        lo.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+}
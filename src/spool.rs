@@ -0,0 +1,62 @@
+//! Spooling large objects to temporary files.
+//!
+//! Code which makes several passes over an object, or seeks around it
+//! heavily, can download it once and work from a local copy instead.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use tempfile;
+
+use {LargeObject, LargeObjectTransactionExt, Mode};
+
+/// Copies the remainder of an object into an anonymous temporary file.
+///
+/// The returned file is positioned at its start. It is deleted by the
+/// operating system once closed.
+pub fn spool(lo: &mut LargeObject) -> io::Result<File> {
+    let mut file = tempfile::tempfile()?;
+    lo.copy_to(&mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Opens the object with the specified `Oid` and copies it into an anonymous
+/// temporary file.
+///
+/// See `spool` for details.
+pub fn spool_object(trans: &Transaction, oid: Oid) -> Result<File> {
+    let mut lo = trans.open_large_object(oid, Mode::Read)?;
+    let file = spool(&mut lo)?;
+    lo.finish()?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use spool::spool_object;
+
+    #[test]
+    fn test_spool_object() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.finish().unwrap();
+
+        let mut file = spool_object(&trans, oid).unwrap();
+        let mut out = String::new();
+        file.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello world!!!");
+        file.seek(SeekFrom::Start(6)).unwrap();
+        out.clear();
+        file.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "world!!!");
+    }
+}
@@ -0,0 +1,192 @@
+//! Reading large objects on hot standby replicas.
+//!
+//! Replicas only accept read-only transactions, and reject any statement
+//! which would write to the database. A `ReadOnlyLargeObject` wraps a handle
+//! opened in `Mode::Read` and exposes only the operations which are
+//! guaranteed to issue no writes, so blob reads can be offloaded from the
+//! primary without risking errors partway through a request.
+use postgres::{GenericConnection, Result};
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+use {LargeObject, LargeObjectTransactionExt, Mode, TransferStats};
+use cancel::CancellationToken;
+use throttle::RateLimiter;
+
+/// Begins a read-only transaction.
+///
+/// This is not required to read from a replica, where every transaction is
+/// read only, but running reads this way against the primary as well makes
+/// any accidental write fail the same way in testing as it would in
+/// production.
+pub fn read_only_transaction<C>(conn: &C) -> Result<Transaction>
+where
+    C: ?Sized + GenericConnection,
+{
+    let trans = conn.transaction()?;
+    trans.batch_execute("SET TRANSACTION READ ONLY")?;
+    Ok(trans)
+}
+
+/// Opens the large object with the specified `Oid` for reading only.
+///
+/// The handle works inside of read-only transactions, including those on hot
+/// standby replicas.
+pub fn open_read_only<'a>(trans: &'a Transaction, oid: Oid) -> Result<ReadOnlyLargeObject<'a>> {
+    trans
+        .open_large_object(oid, Mode::Read)
+        .map(ReadOnlyLargeObject)
+}
+
+/// A large object handle which issues no write statements.
+#[derive(Debug)]
+pub struct ReadOnlyLargeObject<'a>(LargeObject<'a>);
+
+impl<'a> ReadOnlyLargeObject<'a> {
+    /// Returns the `Oid` of the object.
+    pub fn oid(&self) -> Oid {
+        self.0.oid()
+    }
+
+    /// Returns the size of the object in bytes.
+    pub fn size(&mut self) -> Result<u64> {
+        self.0.size()
+    }
+
+    /// Sets the maximum number of bytes read per round trip.
+    ///
+    /// See `LargeObject::set_chunk_size`.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.0.set_chunk_size(chunk_size)
+    }
+
+    /// Attaches a cancellation token to the handle.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.0.set_cancellation_token(token)
+    }
+
+    /// Attaches a rate limiter to the handle.
+    pub fn set_rate_limiter(&mut self, limiter: RateLimiter) {
+        self.0.set_rate_limiter(limiter)
+    }
+
+    /// Limits the time each statement run in the handle's transaction may
+    /// take.
+    ///
+    /// `SET LOCAL` is permitted in read-only transactions. See
+    /// `LargeObject::set_statement_timeout`.
+    pub fn set_statement_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.0.set_statement_timeout(timeout)
+    }
+
+    /// Reads data starting at the specified offset.
+    ///
+    /// See `LargeObject::read_at`.
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.0.read_at(buf, offset)
+    }
+
+    /// Like `read_at`, but fails with `UnexpectedEof` if the object ends
+    /// before `buf` is filled.
+    pub fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.0.read_exact_at(buf, offset)
+    }
+
+    /// Reads up to `len` bytes from the object, passing them to `f`.
+    ///
+    /// See `LargeObject::read_with`.
+    pub fn read_with<F, T>(&mut self, len: usize, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        self.0.read_with(len, f)
+    }
+
+    /// Reads the remainder of the object into a new buffer.
+    pub fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        self.0.read_all()
+    }
+
+    /// Copies the remainder of the object into a writer.
+    ///
+    /// See `LargeObject::copy_to`.
+    pub fn copy_to<W>(&mut self, w: &mut W) -> io::Result<TransferStats>
+    where
+        W: ?Sized + Write,
+    {
+        self.0.copy_to(w)
+    }
+
+    /// Consumes the handle, closing it.
+    pub fn finish(self) -> Result<()> {
+        self.0.finish()
+    }
+}
+
+impl<'a> Read for ReadOnlyLargeObject<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.0.read_to_end(buf)
+    }
+}
+
+impl<'a> BufRead for ReadOnlyLargeObject<'a> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.0.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0.consume(amt)
+    }
+}
+
+impl<'a> Seek for ReadOnlyLargeObject<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use standby::{open_read_only, read_only_transaction};
+
+    #[test]
+    fn test_read_only() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let oid = conn.create_large_object().unwrap();
+        {
+            let trans = conn.transaction().unwrap();
+            let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+            lo.write_all(b"hello world!!!").unwrap();
+            lo.finish().unwrap();
+            trans.commit().unwrap();
+        }
+
+        {
+            let trans = read_only_transaction(&conn).unwrap();
+            let mut lo = open_read_only(&trans, oid).unwrap();
+            assert_eq!(lo.size().unwrap(), 14);
+            lo.seek(SeekFrom::Start(6)).unwrap();
+            let mut out = String::new();
+            lo.read_to_string(&mut out).unwrap();
+            assert_eq!(out, "world!!!");
+            let mut buf = [0; 5];
+            lo.read_exact_at(&mut buf, 0).unwrap();
+            assert_eq!(&buf, b"hello");
+            lo.finish().unwrap();
+
+            assert!(trans.open_large_object(oid, Mode::Write).is_err());
+        }
+
+        conn.delete_large_object(oid).unwrap();
+    }
+}
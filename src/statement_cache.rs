@@ -0,0 +1,175 @@
+//! Control over the prepared statements used by large object handles.
+//!
+//! By default, handles prepare their statements with `prepare_cached`, which
+//! keeps them on the connection for the rest of its life. A
+//! `StatementCache` can be used instead, by opening objects with
+//! `OpenOptions::statement_cache`. It holds a bounded number of statements,
+//! evicting the least recently used once full, and can be cleared at any
+//! time. Statements are deallocated on the server as they are evicted.
+use postgres::{Connection, Result};
+use postgres::rows::Rows;
+use postgres::stmt::Statement;
+use postgres::types::ToSql;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use StatementSource;
+
+/// A bounded cache of prepared statements for a single connection.
+pub struct StatementCache<'conn> {
+    conn: &'conn Connection,
+    inner: RefCell<Inner<'conn>>,
+}
+
+struct Inner<'conn> {
+    capacity: usize,
+    statements: HashMap<String, Statement<'conn>>,
+    // queries, least recently used first
+    lru: VecDeque<String>,
+}
+
+impl<'conn> Inner<'conn> {
+    fn evict(&mut self, len: usize) {
+        while self.lru.len() > len {
+            if let Some(sql) = self.lru.pop_front() {
+                self.statements.remove(&sql);
+            }
+        }
+    }
+}
+
+impl<'conn> fmt::Debug for StatementCache<'conn> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        fmt.debug_struct("StatementCache")
+            .field("capacity", &inner.capacity)
+            .field("len", &inner.statements.len())
+            .finish()
+    }
+}
+
+impl<'conn> StatementCache<'conn> {
+    /// Creates a cache of up to `capacity` statements prepared on the
+    /// specified connection.
+    ///
+    /// A capacity of 0 disables caching, so each statement is prepared
+    /// immediately before it is run and deallocated immediately after.
+    pub fn new(conn: &'conn Connection, capacity: usize) -> StatementCache<'conn> {
+        StatementCache {
+            conn: conn,
+            inner: RefCell::new(Inner {
+                capacity: capacity,
+                statements: HashMap::new(),
+                lru: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Returns the maximum number of statements held by the cache.
+    pub fn capacity(&self) -> usize {
+        self.inner.borrow().capacity
+    }
+
+    /// Sets the maximum number of statements held by the cache, evicting
+    /// any beyond it.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.borrow_mut();
+        inner.capacity = capacity;
+        inner.evict(capacity);
+    }
+
+    /// Returns the number of statements currently held by the cache.
+    pub fn len(&self) -> usize {
+        self.inner.borrow().statements.len()
+    }
+
+    /// Determines if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Evicts all statements from the cache.
+    pub fn clear(&self) {
+        self.inner.borrow_mut().evict(0);
+    }
+}
+
+impl<'conn> StatementSource for StatementCache<'conn> {
+    fn connection(&self) -> &Connection {
+        self.conn
+    }
+
+    fn query(&self, sql: &str, params: &[&dyn ToSql]) -> Result<Rows> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.capacity == 0 {
+            return self.conn.prepare(sql)?.query(params);
+        }
+
+        if inner.statements.contains_key(sql) {
+            if let Some(i) = inner.lru.iter().position(|s| s == sql) {
+                inner.lru.remove(i);
+            }
+        } else {
+            let stmt = self.conn.prepare(sql)?;
+            let capacity = inner.capacity;
+            inner.evict(capacity - 1);
+            inner.statements.insert(sql.to_owned(), stmt);
+        }
+        inner.lru.push_back(sql.to_owned());
+
+        inner.statements[sql].query(params)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use {LargeObjectExt, Mode, OpenOptions};
+    use statement_cache::StatementCache;
+
+    fn prepared(conn: &Connection) -> i64 {
+        conn.query("SELECT count(*) FROM pg_catalog.pg_prepared_statements", &[])
+            .unwrap()
+            .get(0)
+            .get(0)
+    }
+
+    #[test]
+    fn test_statement_cache() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let cache = StatementCache::new(&conn, 2);
+        {
+            let trans = conn.transaction().unwrap();
+            let oid = trans.create_large_object().unwrap();
+            let mut lo = OpenOptions::new()
+                .statement_cache(&cache)
+                .open(&trans, oid, Mode::ReadWrite)
+                .unwrap();
+            lo.write_all(b"hello world!!!").unwrap();
+            lo.seek(SeekFrom::Start(6)).unwrap();
+            let mut out = String::new();
+            lo.read_to_string(&mut out).unwrap();
+            assert_eq!(out, "world!!!");
+            lo.finish().unwrap();
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(prepared(&conn), 2);
+
+        cache.set_capacity(1);
+        assert_eq!(cache.len(), 1);
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(prepared(&conn), 0);
+
+        let other = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = other.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        assert!(OpenOptions::new()
+            .statement_cache(&cache)
+            .open(&trans, oid, Mode::Read)
+            .is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use postgres::types::Oid;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use Mode;
+use store::{LargeObjectStore, FIRST_OID};
+
+/// A `LargeObjectStore` which keeps each object in a file in a local
+/// directory.
+///
+/// Files are named by the decimal representation of the object's `Oid`, and
+/// other files in the directory are ignored. It is useful in development
+/// environments, and as a source or target for migrations.
+#[derive(Debug, Clone)]
+pub struct FilesystemLargeObjectStore {
+    root: PathBuf,
+}
+
+impl FilesystemLargeObjectStore {
+    /// Creates a new store backed by the specified directory.
+    ///
+    /// The directory is created if it does not already exist.
+    pub fn new<P>(root: P) -> io::Result<FilesystemLargeObjectStore>
+    where
+        P: AsRef<Path>,
+    {
+        let root = root.as_ref().to_owned();
+        fs::create_dir_all(&root)?;
+        Ok(FilesystemLargeObjectStore { root: root })
+    }
+
+    /// Returns the directory backing the store.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, oid: Oid) -> PathBuf {
+        self.root.join(oid.to_string())
+    }
+}
+
+impl LargeObjectStore for FilesystemLargeObjectStore {
+    type Object = FilesystemLargeObject;
+
+    fn create(&self) -> io::Result<Oid> {
+        let mut oid = match self.list()?.last() {
+            Some(&oid) => next_oid(oid)?,
+            None => FIRST_OID,
+        };
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(self.path(oid)) {
+                Ok(_) => return Ok(oid),
+                // someone else got there first
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => oid = next_oid(oid)?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn open(&self, oid: Oid, mode: Mode) -> io::Result<FilesystemLargeObject> {
+        let writable = mode.contains(Mode::WRITE);
+        // O_APPEND moves every write to the end, as APPEND does for the
+        // Postgres implementation
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .append(mode.contains(Mode::APPEND))
+            .open(self.path(oid))?;
+
+        Ok(FilesystemLargeObject {
+            file: file,
+            oid: oid,
+            writable: writable,
+        })
+    }
+
+    fn delete(&self, oid: Oid) -> io::Result<()> {
+        fs::remove_file(self.path(oid))
+    }
+
+    fn list(&self) -> io::Result<Vec<Oid>> {
+        let mut oids = vec![];
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let oid = entry.file_name().to_str().and_then(|s| s.parse::<Oid>().ok());
+            if let Some(oid) = oid {
+                oids.push(oid);
+            }
+        }
+        oids.sort();
+        Ok(oids)
+    }
+}
+
+fn next_oid(oid: Oid) -> io::Result<Oid> {
+    oid.checked_add(1)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no more OIDs are available"))
+}
+
+/// An open object in a `FilesystemLargeObjectStore`.
+#[derive(Debug)]
+pub struct FilesystemLargeObject {
+    file: File,
+    oid: Oid,
+    writable: bool,
+}
+
+impl FilesystemLargeObject {
+    /// Returns the `Oid` of the opened object.
+    pub fn oid(&self) -> Oid {
+        self.oid
+    }
+
+    /// Truncates the object to the specified size.
+    ///
+    /// If `len` is larger than the size of the object, it will be padded with
+    /// null bytes to the specified size.
+    pub fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.check_writable()?;
+        self.file.set_len(len)
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("large object descriptor for {} was not opened for writing", self.oid),
+            ))
+        }
+    }
+}
+
+impl Read for FilesystemLargeObject {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for FilesystemLargeObject {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_writable()?;
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FilesystemLargeObject {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Seek, SeekFrom, Write};
+
+    use Mode;
+    use store::LargeObjectStore;
+    use store::fs::FilesystemLargeObjectStore;
+
+    #[test]
+    fn test_filesystem_store() {
+        let dir = env::temp_dir().join("postgres_large_object_fs_store");
+        let _ = fs::remove_dir_all(&dir);
+        let store = FilesystemLargeObjectStore::new(&dir).unwrap();
+        File::create(dir.join("not_an_object")).unwrap();
+
+        let oid = store.create_with(b"hello world!!!").unwrap();
+        assert_eq!(store.list().unwrap(), [oid]);
+        assert_eq!(store.create().unwrap(), oid + 1);
+
+        let mut lo = store.open(oid, Mode::Write).unwrap();
+        lo.seek(SeekFrom::Start(5)).unwrap();
+        lo.write_all(b"!").unwrap();
+        lo.truncate(6).unwrap();
+        assert_eq!(store.read_all(oid).unwrap(), b"hello!");
+
+        let mut lo = store.open(oid, Mode::Read).unwrap();
+        assert!(lo.write_all(b"hello").is_err());
+
+        let mut lo = store.open(oid, Mode::APPEND).unwrap();
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        lo.write_all(b"?").unwrap();
+        assert_eq!(store.read_all(oid).unwrap(), b"hello!?");
+
+        store.delete(oid).unwrap();
+        assert_eq!(store.list().unwrap(), [oid + 1]);
+
+        File::create(dir.join(u32::max_value().to_string())).unwrap();
+        assert!(store.create().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+use postgres::types::Oid;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use {seek_position, Mode};
+use store::{LargeObjectStore, FIRST_OID};
+
+#[derive(Debug)]
+struct Inner {
+    next_oid: Oid,
+    objects: BTreeMap<Oid, Vec<u8>>,
+}
+
+/// A `LargeObjectStore` which keeps objects in memory.
+///
+/// It is primarily intended for use in tests of code written against the
+/// `LargeObjectStore` trait, and mirrors the behavior of the Postgres
+/// implementation. Clones of a store share the same objects.
+#[derive(Debug, Clone)]
+pub struct MemoryLargeObjectStore(Arc<Mutex<Inner>>);
+
+impl Default for MemoryLargeObjectStore {
+    fn default() -> MemoryLargeObjectStore {
+        MemoryLargeObjectStore::new()
+    }
+}
+
+impl MemoryLargeObjectStore {
+    /// Creates a new, empty store.
+    pub fn new() -> MemoryLargeObjectStore {
+        MemoryLargeObjectStore(Arc::new(Mutex::new(Inner {
+            next_oid: FIRST_OID,
+            objects: BTreeMap::new(),
+        })))
+    }
+}
+
+fn lock(inner: &Mutex<Inner>) -> MutexGuard<Inner> {
+    inner.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn not_found(oid: Oid) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("large object {} does not exist", oid),
+    )
+}
+
+impl LargeObjectStore for MemoryLargeObjectStore {
+    type Object = MemoryLargeObject;
+
+    fn create(&self) -> io::Result<Oid> {
+        let mut inner = lock(&self.0);
+        let oid = inner.next_oid;
+        inner.next_oid += 1;
+        inner.objects.insert(oid, vec![]);
+        Ok(oid)
+    }
+
+    fn open(&self, oid: Oid, mode: Mode) -> io::Result<MemoryLargeObject> {
+        if !lock(&self.0).objects.contains_key(&oid) {
+            return Err(not_found(oid));
+        }
+
+        Ok(MemoryLargeObject {
+            inner: self.0.clone(),
+            oid: oid,
+            pos: 0,
+            writable: mode.contains(Mode::WRITE),
+            append: mode.contains(Mode::APPEND),
+        })
+    }
+
+    fn delete(&self, oid: Oid) -> io::Result<()> {
+        match lock(&self.0).objects.remove(&oid) {
+            Some(_) => Ok(()),
+            None => Err(not_found(oid)),
+        }
+    }
+
+    fn list(&self) -> io::Result<Vec<Oid>> {
+        Ok(lock(&self.0).objects.keys().cloned().collect())
+    }
+}
+
+/// An open object in a `MemoryLargeObjectStore`.
+#[derive(Debug)]
+pub struct MemoryLargeObject {
+    inner: Arc<Mutex<Inner>>,
+    oid: Oid,
+    pos: u64,
+    writable: bool,
+    append: bool,
+}
+
+impl MemoryLargeObject {
+    /// Returns the `Oid` of the opened object.
+    pub fn oid(&self) -> Oid {
+        self.oid
+    }
+
+    /// Truncates the object to the specified size.
+    ///
+    /// If `len` is larger than the size of the object, it will be padded with
+    /// null bytes to the specified size.
+    pub fn truncate(&mut self, len: u64) -> io::Result<()> {
+        let oid = self.oid;
+        self.check_writable()?;
+        let mut inner = lock(&self.inner);
+        let data = inner.objects.get_mut(&oid).ok_or_else(|| not_found(oid))?;
+        data.resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("large object descriptor for {} was not opened for writing", self.oid),
+            ))
+        }
+    }
+}
+
+impl Read for MemoryLargeObject {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let oid = self.oid;
+        let inner = lock(&self.inner);
+        let data = inner.objects.get(&oid).ok_or_else(|| not_found(oid))?;
+
+        let start = cmp::min(self.pos, data.len() as u64) as usize;
+        let len = cmp::min(buf.len(), data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryLargeObject {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let oid = self.oid;
+        self.check_writable()?;
+        let mut inner = lock(&self.inner);
+        let data = inner.objects.get_mut(&oid).ok_or_else(|| not_found(oid))?;
+
+        if self.append {
+            self.pos = data.len() as u64;
+        }
+        let start = self.pos as usize;
+        let end = start + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryLargeObject {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let oid = self.oid;
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => (pos, 0),
+            SeekFrom::Current(pos) => (self.pos, pos),
+            SeekFrom::End(pos) => {
+                let inner = lock(&self.inner);
+                let data = inner.objects.get(&oid).ok_or_else(|| not_found(oid))?;
+                (data.len() as u64, pos)
+            }
+        };
+
+        self.pos = seek_position(base, offset)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use Mode;
+    use store::LargeObjectStore;
+    use store::memory::MemoryLargeObjectStore;
+
+    #[test]
+    fn test_create_list_delete() {
+        let store = MemoryLargeObjectStore::new();
+        let a = store.create().unwrap();
+        let b = store.create().unwrap();
+        assert_eq!(store.list().unwrap(), [a, b]);
+        store.delete(a).unwrap();
+        assert_eq!(store.list().unwrap(), [b]);
+        assert!(store.delete(a).is_err());
+        assert!(store.open(a, Mode::Read).is_err());
+    }
+
+    #[test]
+    fn test_write_seek_read() {
+        let store = MemoryLargeObjectStore::new();
+        let oid = store.create().unwrap();
+        let mut lo = store.open(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+
+        assert_eq!(14, lo.seek(SeekFrom::Current(0)).unwrap());
+        assert_eq!(10, lo.seek(SeekFrom::End(-4)).unwrap());
+        let mut buf = [0];
+        assert_eq!(1, lo.read(&mut buf).unwrap());
+        assert_eq!(b'd', buf[0]);
+
+        lo.seek(SeekFrom::Start(16)).unwrap();
+        lo.write_all(b"!").unwrap();
+        assert_eq!(store.read_all(oid).unwrap(), b"hello world!!!\0\0!");
+
+        lo.truncate(5).unwrap();
+        assert_eq!(store.read_all(oid).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_write_with_read_handle() {
+        let store = MemoryLargeObjectStore::new();
+        let oid = store.create().unwrap();
+        let mut lo = store.open(oid, Mode::Read).unwrap();
+        assert!(lo.write_all(b"hello world!!!").is_err());
+    }
+
+    #[test]
+    fn test_append() {
+        let store = MemoryLargeObjectStore::new();
+        let oid = store.create_with(b"hello").unwrap();
+        let mut lo = store.open(oid, Mode::READ | Mode::APPEND).unwrap();
+        lo.write_all(b" world").unwrap();
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0; 5];
+        lo.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        lo.write_all(b"!!!").unwrap();
+        assert_eq!(store.read_all(oid).unwrap(), b"hello world!!!");
+    }
+}
@@ -0,0 +1,110 @@
+//! A storage abstraction over large objects.
+//!
+//! The `LargeObjectStore` trait covers the basic operations on large objects
+//! so that code can be written against it rather than a Postgres connection
+//! directly. A `Transaction` reference implements the trait using the
+//! server's large object API, `MemoryLargeObjectStore` provides an in-memory
+//! implementation for use in tests, and `FilesystemLargeObjectStore` keeps
+//! objects in a local directory.
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::io::{self, Read, Seek, Write};
+
+use {LargeObject, LargeObjectExt, LargeObjectTransactionExt, Mode};
+
+pub use self::fs::{FilesystemLargeObject, FilesystemLargeObjectStore};
+pub use self::memory::{MemoryLargeObject, MemoryLargeObjectStore};
+
+mod fs;
+mod memory;
+
+// the first Oid Postgres hands out to user objects
+const FIRST_OID: Oid = 16384;
+
+/// A store of large objects.
+pub trait LargeObjectStore {
+    /// The type of an open object.
+    type Object: Read + Write + Seek;
+
+    /// Creates a new, empty object, returning its `Oid`.
+    fn create(&self) -> io::Result<Oid>;
+
+    /// Opens the object with the specified `Oid` in the specified `Mode`.
+    fn open(&self, oid: Oid, mode: Mode) -> io::Result<Self::Object>;
+
+    /// Deletes the object with the specified `Oid`.
+    fn delete(&self, oid: Oid) -> io::Result<()>;
+
+    /// Returns the `Oid`s of all objects in the store, in ascending order.
+    fn list(&self) -> io::Result<Vec<Oid>>;
+
+    /// Reads the entire contents of the object with the specified `Oid`.
+    fn read_all(&self, oid: Oid) -> io::Result<Vec<u8>> {
+        let mut object = self.open(oid, Mode::Read)?;
+        let mut buf = vec![];
+        object.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Creates a new object containing the specified data, returning its
+    /// `Oid`.
+    fn create_with(&self, data: &[u8]) -> io::Result<Oid> {
+        let oid = self.create()?;
+        let mut object = self.open(oid, Mode::Write)?;
+        object.write_all(data)?;
+        Ok(oid)
+    }
+}
+
+impl<'a, 'conn> LargeObjectStore for &'a Transaction<'conn> {
+    type Object = LargeObject<'a>;
+
+    fn create(&self) -> io::Result<Oid> {
+        self.create_large_object().map_err(Into::into)
+    }
+
+    fn open(&self, oid: Oid, mode: Mode) -> io::Result<LargeObject<'a>> {
+        let trans: &'a Transaction<'conn> = *self;
+        trans.open_large_object(oid, mode).map_err(Into::into)
+    }
+
+    fn delete(&self, oid: Oid) -> io::Result<()> {
+        self.delete_large_object(oid).map_err(Into::into)
+    }
+
+    fn list(&self) -> io::Result<Vec<Oid>> {
+        self.list_large_objects().map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Seek, SeekFrom, Write};
+
+    use Mode;
+    use store::LargeObjectStore;
+
+    fn exercise<S: LargeObjectStore>(store: S) {
+        let oid = store.create_with(b"hello world!!!").unwrap();
+        assert!(store.list().unwrap().contains(&oid));
+        assert_eq!(store.read_all(oid).unwrap(), b"hello world!!!");
+
+        {
+            let mut object = store.open(oid, Mode::READ | Mode::APPEND).unwrap();
+            object.seek(SeekFrom::Start(0)).unwrap();
+            object.write_all(b"?").unwrap();
+        }
+        assert_eq!(store.read_all(oid).unwrap(), b"hello world!!!?");
+
+        store.delete(oid).unwrap();
+        assert!(!store.list().unwrap().contains(&oid));
+    }
+
+    #[test]
+    fn test_transaction_store() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        exercise(&trans);
+    }
+}
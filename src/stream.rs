@@ -0,0 +1,562 @@
+//! Asynchronous streaming of large objects.
+//!
+//! Requires the `with-futures` feature.
+//!
+//! rust-postgres connections are blocking, so a `LargeObjectStream` moves its
+//! connection onto a dedicated thread which holds the transaction open and
+//! reads the object in chunks, handing them to the stream through a bounded
+//! channel. The transaction is closed when the object has been fully read or
+//! the stream is dropped. A `LargeObjectSink` works the same way in the other
+//! direction.
+//!
+//! Both can report their progress as `ProgressEvent`s sent over an unbounded
+//! channel, so that the status of a transfer can be shown live without
+//! polling.
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use futures::executor;
+use futures::{Sink, SinkExt, Stream};
+use postgres::{GenericConnection, Result};
+use postgres::types::Oid;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::sync::mpsc as std_mpsc;
+use std::task::{Context, Poll};
+use std::thread;
+
+use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+
+/// The default number of bytes read from the object for each item of a
+/// stream.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The stage a transfer has reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// The object has been opened.
+    Opened,
+    /// A chunk of data has been transferred.
+    Transferring,
+    /// All data has been written and the transaction is being committed.
+    Committing,
+    /// The transfer has completed successfully.
+    Finished,
+    /// The transfer has failed or been abandoned.
+    Failed,
+}
+
+/// An update on the progress of a transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressEvent {
+    /// The `Oid` of the object being transferred.
+    pub oid: Oid,
+    /// The stage the transfer has reached.
+    pub phase: Phase,
+    /// The number of bytes transferred so far.
+    pub bytes: u64,
+    /// The size of the object, if known.
+    pub size: Option<u64>,
+}
+
+/// The sending half of a channel of progress events.
+pub type ProgressSender = mpsc::UnboundedSender<ProgressEvent>;
+
+struct Reporter {
+    events: Option<ProgressSender>,
+    oid: Oid,
+    bytes: u64,
+    size: Option<u64>,
+}
+
+impl Reporter {
+    fn report(&self, phase: Phase) {
+        if let Some(ref events) = self.events {
+            // the receiver going away shouldn't interrupt the transfer
+            let _ = events.unbounded_send(ProgressEvent {
+                oid: self.oid,
+                phase: phase,
+                bytes: self.bytes,
+                size: self.size,
+            });
+        }
+    }
+}
+
+/// A `Stream` of the contents of a large object.
+#[derive(Debug)]
+pub struct LargeObjectStream {
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    remaining: u64,
+}
+
+impl LargeObjectStream {
+    /// Opens the large object with the specified `Oid` for streaming.
+    ///
+    /// The connection is moved to a background thread for the lifetime of
+    /// the stream. This method blocks until the object has been opened so
+    /// that errors opening it can be reported directly. A `chunk_size` of 0
+    /// is rejected with an `InvalidInput` error.
+    pub fn new<C>(conn: C, oid: Oid, chunk_size: usize) -> Result<LargeObjectStream>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        LargeObjectStream::spawn(conn, oid, chunk_size, None)
+    }
+
+    /// Like `new`, but sends progress events to `events` as chunks are read
+    /// from the database.
+    pub fn with_progress<C>(
+        conn: C,
+        oid: Oid,
+        chunk_size: usize,
+        events: ProgressSender,
+    ) -> Result<LargeObjectStream>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        LargeObjectStream::spawn(conn, oid, chunk_size, Some(events))
+    }
+
+    fn spawn<C>(
+        conn: C,
+        oid: Oid,
+        chunk_size: usize,
+        events: Option<ProgressSender>,
+    ) -> Result<LargeObjectStream>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        // an empty buffer would end the stream short of its reported length
+        if chunk_size == 0 {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "chunk size must be nonzero").into(),
+            );
+        }
+
+        let (size_tx, size_rx) = std_mpsc::channel();
+        let (mut chunk_tx, chunk_rx) = mpsc::channel(1);
+
+        thread::spawn(move || {
+            let mut reporter = Reporter {
+                events: events,
+                oid: oid,
+                bytes: 0,
+                size: None,
+            };
+            let trans = match conn.transaction() {
+                Ok(trans) => trans,
+                Err(e) => {
+                    reporter.report(Phase::Failed);
+                    let _ = size_tx.send(Err(e));
+                    return;
+                }
+            };
+            let mut lo = match trans.open_large_object(oid, Mode::Read) {
+                Ok(lo) => lo,
+                Err(e) => {
+                    reporter.report(Phase::Failed);
+                    let _ = size_tx.send(Err(e));
+                    return;
+                }
+            };
+            let size = lo.size();
+            reporter.size = size.as_ref().ok().cloned();
+            let failed = size.is_err();
+            if size_tx.send(size).is_err() || failed {
+                reporter.report(Phase::Failed);
+                return;
+            }
+            reporter.report(Phase::Opened);
+
+            let mut buf = vec![0; chunk_size];
+            loop {
+                let chunk = match lo.read(&mut buf) {
+                    Ok(0) => {
+                        reporter.report(Phase::Finished);
+                        break;
+                    }
+                    Ok(n) => Ok(Bytes::copy_from_slice(&buf[..n])),
+                    Err(e) => Err(e),
+                };
+                let failed = chunk.is_err();
+                let n = chunk.as_ref().map(|c| c.len()).unwrap_or(0);
+                // an error here means that the stream was dropped
+                if executor::block_on(chunk_tx.send(chunk)).is_err() || failed {
+                    reporter.report(Phase::Failed);
+                    break;
+                }
+                reporter.bytes += n as u64;
+                reporter.report(Phase::Transferring);
+            }
+        });
+
+        let size = match size_rx.recv() {
+            Ok(size) => size?,
+            Err(_) => return Err(thread_panicked().into()),
+        };
+
+        Ok(LargeObjectStream {
+            chunks: chunk_rx,
+            remaining: size,
+        })
+    }
+
+    /// Returns the number of bytes of the object which have not yet been
+    /// yielded by the stream.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+impl Stream for LargeObjectStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Bytes>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.chunks).poll_next(cx);
+        if let Poll::Ready(Some(Ok(ref chunk))) = poll {
+            this.remaining = this.remaining.saturating_sub(chunk.len() as u64);
+        }
+        poll
+    }
+}
+
+fn thread_panicked() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "large object stream thread panicked")
+}
+
+/// A `Sink` writing to a large object.
+///
+/// Chunks are written as they are received. The transaction is committed
+/// when the sink is closed, and rolled back if it is dropped without being
+/// closed or if writing fails.
+#[derive(Debug)]
+pub struct LargeObjectSink {
+    oid: Oid,
+    chunks: mpsc::Sender<Option<Bytes>>,
+    result: oneshot::Receiver<io::Result<()>>,
+    closing: bool,
+}
+
+impl LargeObjectSink {
+    /// Opens the large object with the specified `Oid` for writing.
+    ///
+    /// The connection is moved to a background thread for the lifetime of
+    /// the sink. This method blocks until the object has been opened so that
+    /// errors opening it can be reported directly.
+    pub fn new<C>(conn: C, oid: Oid) -> Result<LargeObjectSink>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        LargeObjectSink::spawn(conn, Some(oid), None)
+    }
+
+    /// Like `new`, but sends progress events to `events` as chunks are
+    /// written to the database.
+    pub fn with_progress<C>(conn: C, oid: Oid, events: ProgressSender) -> Result<LargeObjectSink>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        LargeObjectSink::spawn(conn, Some(oid), Some(events))
+    }
+
+    /// Creates a new large object and opens it for writing.
+    ///
+    /// The object is created in the sink's transaction, so it will not exist
+    /// unless the sink is successfully closed.
+    pub fn create<C>(conn: C) -> Result<LargeObjectSink>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        LargeObjectSink::spawn(conn, None, None)
+    }
+
+    /// Like `create`, but sends progress events to `events` as chunks are
+    /// written to the database.
+    pub fn create_with_progress<C>(conn: C, events: ProgressSender) -> Result<LargeObjectSink>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        LargeObjectSink::spawn(conn, None, Some(events))
+    }
+
+    fn spawn<C>(
+        conn: C,
+        oid: Option<Oid>,
+        events: Option<ProgressSender>,
+    ) -> Result<LargeObjectSink>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        let (opened_tx, opened_rx) = std_mpsc::channel();
+        let (chunk_tx, chunk_rx) = mpsc::channel(1);
+        let (result_tx, result_rx) = oneshot::channel();
+
+        thread::spawn(move || {
+            let mut reporter = Reporter {
+                events: events,
+                oid: oid.unwrap_or(0),
+                bytes: 0,
+                size: None,
+            };
+            let result = write_chunks(conn, oid, opened_tx, chunk_rx, &mut reporter);
+            match result {
+                Ok(true) => reporter.report(Phase::Finished),
+                _ => reporter.report(Phase::Failed),
+            }
+            let result = result.map(|_| ());
+            let _ = result_tx.send(result.map_err(io::Error::from));
+        });
+
+        let oid = match opened_rx.recv() {
+            Ok(oid) => oid?,
+            Err(_) => return Err(thread_panicked().into()),
+        };
+
+        Ok(LargeObjectSink {
+            oid: oid,
+            chunks: chunk_tx,
+            result: result_rx,
+            closing: false,
+        })
+    }
+
+    /// Returns the `Oid` of the object being written.
+    pub fn oid(&self) -> Oid {
+        self.oid
+    }
+
+    fn poll_result(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.result).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(thread_panicked())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    // the channel only disconnects once the thread has failed, so report its
+    // error
+    fn poll_failure(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.poll_result(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "large object sink is closed",
+            ))),
+            poll => poll,
+        }
+    }
+}
+
+// returns whether the transaction was committed
+fn write_chunks<C>(
+    conn: C,
+    oid: Option<Oid>,
+    opened: std_mpsc::Sender<Result<Oid>>,
+    chunks: mpsc::Receiver<Option<Bytes>>,
+    reporter: &mut Reporter,
+) -> Result<bool>
+where
+    C: GenericConnection,
+{
+    let trans = match conn.transaction() {
+        Ok(trans) => trans,
+        Err(e) => {
+            let _ = opened.send(Err(e));
+            return Ok(false);
+        }
+    };
+    let oid = match oid {
+        Some(oid) => oid,
+        None => match trans.create_large_object() {
+            Ok(oid) => oid,
+            Err(e) => {
+                let _ = opened.send(Err(e));
+                return Ok(false);
+            }
+        },
+    };
+    {
+        let mut lo = match trans.open_large_object(oid, Mode::Write) {
+            Ok(lo) => lo,
+            Err(e) => {
+                let _ = opened.send(Err(e));
+                return Ok(false);
+            }
+        };
+        let _ = opened.send(Ok(oid));
+        reporter.oid = oid;
+        reporter.report(Phase::Opened);
+
+        let mut finished = false;
+        for chunk in executor::block_on_stream(chunks) {
+            match chunk {
+                Some(chunk) => {
+                    lo.write_all(&chunk)?;
+                    reporter.bytes += chunk.len() as u64;
+                    reporter.report(Phase::Transferring);
+                }
+                None => {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+        if !finished {
+            // the sink was dropped without being closed
+            return Ok(false);
+        }
+        lo.finish()?;
+    }
+    reporter.size = Some(reporter.bytes);
+    reporter.report(Phase::Committing);
+    trans.commit()?;
+    Ok(true)
+}
+
+impl Sink<Bytes> for LargeObjectSink {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.chunks.poll_ready(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
+            Poll::Ready(Err(_)) => this.poll_failure(cx),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> io::Result<()> {
+        self.get_mut()
+            .chunks
+            .start_send(Some(item))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.chunks).poll_flush(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
+            Poll::Ready(Err(_)) => this.poll_failure(cx),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closing {
+            match this.chunks.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(_)) => return this.poll_failure(cx),
+                Poll::Pending => return Poll::Pending,
+            }
+            if this.chunks.start_send(None).is_err() {
+                return this.poll_failure(cx);
+            }
+            this.closing = true;
+        }
+        this.poll_result(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use futures::channel::mpsc;
+    use futures::{executor, SinkExt, StreamExt};
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use stream::{LargeObjectSink, LargeObjectStream, Phase};
+
+    #[test]
+    fn test_stream() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let oid = conn.create_large_object().unwrap();
+        {
+            let trans = conn.transaction().unwrap();
+            let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+            lo.write_all(b"hello world!!!").unwrap();
+            lo.finish().unwrap();
+            trans.commit().unwrap();
+        }
+
+        let reader = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let stream = LargeObjectStream::new(reader, oid, 4).unwrap();
+        assert_eq!(14, stream.remaining());
+        let mut data = vec![];
+        for chunk in executor::block_on_stream(stream) {
+            let chunk = chunk.unwrap();
+            assert!(chunk.len() <= 4);
+            data.extend_from_slice(&chunk);
+        }
+        assert_eq!(data, b"hello world!!!");
+
+        let reader = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        assert!(LargeObjectStream::new(reader, oid, 0).is_err());
+
+        conn.delete_large_object(oid).unwrap();
+    }
+
+    #[test]
+    fn test_sink() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let oid = conn.create_large_object().unwrap();
+
+        let writer = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let mut sink = LargeObjectSink::new(writer, oid).unwrap();
+        executor::block_on(sink.send(Bytes::from_static(b"hello "))).unwrap();
+        executor::block_on(sink.send(Bytes::from_static(b"world!!!"))).unwrap();
+        executor::block_on(sink.close()).unwrap();
+
+        let trans = conn.transaction().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        let mut data = vec![];
+        lo.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello world!!!");
+        lo.finish().unwrap();
+        trans.delete_large_object(oid).unwrap();
+        trans.commit().unwrap();
+    }
+
+    #[test]
+    fn test_progress() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let (tx, rx) = mpsc::unbounded();
+
+        let writer = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let mut sink = LargeObjectSink::create_with_progress(writer, tx.clone()).unwrap();
+        let oid = sink.oid();
+        executor::block_on(sink.send(Bytes::from_static(b"hello "))).unwrap();
+        executor::block_on(sink.send(Bytes::from_static(b"world!!!"))).unwrap();
+        executor::block_on(sink.close()).unwrap();
+
+        let reader = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let stream = LargeObjectStream::with_progress(reader, oid, 8, tx).unwrap();
+        executor::block_on_stream(stream).count();
+
+        let events = executor::block_on(rx.collect::<Vec<_>>());
+        let phases = events.iter().map(|e| e.phase).collect::<Vec<_>>();
+        assert_eq!(
+            phases,
+            [
+                Phase::Opened,
+                Phase::Transferring,
+                Phase::Transferring,
+                Phase::Committing,
+                Phase::Finished,
+                Phase::Opened,
+                Phase::Transferring,
+                Phase::Transferring,
+                Phase::Finished,
+            ]
+        );
+        assert!(events.iter().all(|e| e.oid == oid));
+        assert_eq!(events[2].bytes, 14);
+        assert_eq!(events[5].size, Some(14));
+        assert_eq!(events[7].bytes, 14);
+
+        conn.delete_large_object(oid).unwrap();
+    }
+}
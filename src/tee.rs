@@ -0,0 +1,90 @@
+//! Copying data to a second destination as it is read.
+//!
+//! A `TeeReader` wraps a reader, such as a `LargeObject`, and writes every
+//! byte read through it to a second writer. A download can then fill a local
+//! cache file or feed a hash as it streams to the caller, rather than needing
+//! a second pass over the object.
+use std::io::{self, Read, Write};
+
+/// A reader which writes the bytes read through it to a second writer.
+#[derive(Debug)]
+pub struct TeeReader<R, W> {
+    inner: R,
+    sink: W,
+}
+
+impl<R, W> TeeReader<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    /// Wraps a reader, copying everything read from it into `sink`.
+    pub fn new(inner: R, sink: W) -> TeeReader<R, W> {
+        TeeReader {
+            inner: inner,
+            sink: sink,
+        }
+    }
+
+    /// Returns a reference to the writer receiving the copied bytes.
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    /// Returns a mutable reference to the writer receiving the copied bytes.
+    pub fn sink_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+
+    /// Flushes the sink and returns the underlying reader and writer.
+    pub fn into_parts(mut self) -> io::Result<(R, W)> {
+        self.sink.flush()?;
+        Ok((self.inner, self.sink))
+    }
+}
+
+impl<R, W> Read for TeeReader<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        // the caller never sees bytes that didn't reach the sink
+        self.sink.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Write};
+
+    use {LargeObjectTransactionExt, Mode};
+    use tee::TeeReader;
+
+    #[test]
+    fn test_tee_reader() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+        lo.finish().unwrap();
+
+        let lo = trans.open_large_object(oid, Mode::Read).unwrap();
+        let mut reader = TeeReader::new(lo, vec![]);
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(reader.sink(), b"hello");
+
+        let mut out = vec![];
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b" world!!!");
+        let (lo, copy) = reader.into_parts().unwrap();
+        assert_eq!(copy, b"hello world!!!");
+        lo.finish().unwrap();
+    }
+}
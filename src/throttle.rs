@@ -0,0 +1,181 @@
+//! Bandwidth throttling for large object transfers.
+//!
+//! A `RateLimiter` is a token bucket which caps the rate at which bytes are
+//! transferred. Handles with a limiter attached (see
+//! `LargeObject::set_rate_limiter`) wait as needed before each chunk they
+//! transfer, and any reader or writer can be limited by wrapping it in a
+//! `Throttled`. Clones of a limiter share its bucket, so a single limit can
+//! be applied across many handles and threads at once.
+use std::cmp;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket rate limiter.
+#[derive(Clone)]
+pub struct RateLimiter(Arc<Mutex<Bucket>>);
+
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let bucket = self.0.lock().unwrap();
+        fmt.debug_struct("RateLimiter")
+            .field("bytes_per_second", &bucket.rate)
+            .field("burst", &bucket.burst)
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `bytes_per_second` bytes per second on
+    /// average, with bursts of up to one second's worth.
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter::with_burst(bytes_per_second, bytes_per_second)
+    }
+
+    /// Creates a limiter allowing `bytes_per_second` bytes per second on
+    /// average, with bursts of up to `burst` bytes.
+    pub fn with_burst(bytes_per_second: u64, burst: u64) -> RateLimiter {
+        let rate = cmp::max(bytes_per_second, 1) as f64;
+        let burst = cmp::max(burst, 1) as f64;
+        RateLimiter(Arc::new(Mutex::new(Bucket {
+            rate: rate,
+            burst: burst,
+            tokens: burst,
+            last: Instant::now(),
+        })))
+    }
+
+    /// Waits until `bytes` bytes may be transferred.
+    ///
+    /// Requests larger than the burst size are allowed through, after
+    /// which callers wait until the bucket has refilled, so large chunks
+    /// are still limited to the average rate over time.
+    pub fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.0.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now - bucket.last;
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+            bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.burst);
+            bucket.last = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0. {
+                return;
+            }
+            -bucket.tokens / bucket.rate
+        };
+        thread::sleep(Duration::new(
+            wait as u64,
+            (wait.fract() * 1e9) as u32,
+        ));
+    }
+}
+
+/// A reader or writer whose throughput is capped by a `RateLimiter`.
+#[derive(Debug)]
+pub struct Throttled<T> {
+    inner: T,
+    limiter: RateLimiter,
+}
+
+impl<T> Throttled<T> {
+    /// Wraps a reader or writer.
+    pub fn new(inner: T, limiter: RateLimiter) -> Throttled<T> {
+        Throttled {
+            inner: inner,
+            limiter: limiter,
+        }
+    }
+
+    /// Returns a shared reference to the underlying reader or writer.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying reader or writer.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the underlying reader or writer.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> Read for Throttled<T>
+where
+    T: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.limiter.acquire(n as u64);
+        Ok(n)
+    }
+}
+
+impl<T> BufRead for Throttled<T>
+where
+    T: BufRead,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.limiter.acquire(amt as u64);
+        self.inner.consume(amt);
+    }
+}
+
+impl<T> Write for Throttled<T>
+where
+    T: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.limiter.acquire(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{self, Seek, SeekFrom, Write};
+    use std::time::{Duration, Instant};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use throttle::{RateLimiter, Throttled};
+
+    #[test]
+    fn test_throttle() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.set_chunk_size(1000);
+
+        let limiter = RateLimiter::new(10_000);
+        lo.set_rate_limiter(limiter.clone());
+        let start = Instant::now();
+        lo.write_all(&[0; 15_000]).unwrap();
+        lo.seek(SeekFrom::Start(0)).unwrap();
+        io::copy(&mut lo, &mut Throttled::new(io::sink(), limiter)).unwrap();
+        // 10,000 bytes of burst, then 20,000 bytes at 10,000 bytes/s
+        assert!(start.elapsed() >= Duration::from_millis(1900));
+    }
+}
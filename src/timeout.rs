@@ -0,0 +1,66 @@
+//! Statement timeouts for large object operations.
+//!
+//! Timeouts are applied with `set_config('statement_timeout', ..., true)`,
+//! the function form of `SET LOCAL`, so they never outlive the transaction.
+//! The previous setting is restored once the operation completes, so other
+//! statements in the transaction are unaffected.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use std::time::Duration;
+
+use {format_timeout, set_statement_timeout, Statements};
+
+/// Runs an operation with a statement timeout applied to each statement it
+/// executes in the transaction.
+///
+/// The previous timeout is restored afterwards. If the operation fails, the
+/// transaction may be aborted, in which case restoring it is skipped. The
+/// settings are changed with unnamed statements, so this can be used behind
+/// poolers that don't support prepared statements.
+pub fn with_statement_timeout<F, T>(trans: &Transaction, timeout: Duration, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let previous = set_statement_timeout(trans, Statements::Unnamed, &format_timeout(timeout))?;
+    let r = f();
+    match r {
+        Ok(v) => {
+            set_statement_timeout(trans, Statements::Unnamed, &previous)?;
+            Ok(v)
+        }
+        Err(e) => {
+            let _ = set_statement_timeout(trans, Statements::Unnamed, &previous);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::time::Duration;
+
+    use timeout::with_statement_timeout;
+
+    #[test]
+    fn test_with_statement_timeout() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        trans.batch_execute("SET LOCAL statement_timeout = '5min'").unwrap();
+
+        let r = with_statement_timeout(&trans, Duration::from_millis(1500), || {
+            let timeout: String = trans.query("SHOW statement_timeout", &[])?.get(0).get(0);
+            Ok(timeout)
+        });
+        assert_eq!(r.unwrap(), "1500ms");
+
+        let timeout: String = trans.query("SHOW statement_timeout", &[]).unwrap().get(0).get(0);
+        assert_eq!(timeout, "5min");
+
+        let nested = trans.transaction().unwrap();
+        let r = with_statement_timeout(&nested, Duration::from_millis(10), || {
+            nested.execute("SELECT pg_catalog.pg_sleep(1)", &[])
+        });
+        assert!(r.is_err());
+    }
+}
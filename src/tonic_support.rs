@@ -0,0 +1,86 @@
+//! Adapters for gRPC services built with tonic.
+//!
+//! Requires the `with-tonic` feature.
+//!
+//! File transfer services typically stream messages each holding a chunk of
+//! bytes. The adapters here convert between such streams and large objects,
+//! with a function mapping between chunks and the service's message type.
+use bytes::Bytes;
+use futures::{FutureExt, SinkExt, Stream, StreamExt};
+use postgres::types::Oid;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::Status;
+
+use stream::{LargeObjectSink, LargeObjectStream};
+
+fn status(e: io::Error) -> Status {
+    let message = e.to_string();
+    match e.kind() {
+        io::ErrorKind::NotFound => Status::not_found(message),
+        io::ErrorKind::PermissionDenied => Status::permission_denied(message),
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => Status::invalid_argument(message),
+        _ => Status::internal(message),
+    }
+}
+
+/// A stream of response messages, each holding a chunk of a large object.
+///
+/// It can be used directly as the response stream of a server streaming
+/// method.
+#[derive(Debug)]
+pub struct ChunkStream<F> {
+    stream: LargeObjectStream,
+    f: F,
+}
+
+/// Converts a stream of a large object's contents into a stream of messages.
+///
+/// `f` builds a message from each chunk.
+pub fn chunk_stream<T, F>(stream: LargeObjectStream, f: F) -> ChunkStream<F>
+where
+    F: FnMut(Bytes) -> T,
+{
+    ChunkStream { stream: stream, f: f }
+}
+
+impl<T, F> Stream for ChunkStream<F>
+where
+    F: FnMut(Bytes) -> T + Unpin,
+{
+    type Item = Result<T, Status>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<T, Status>>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.stream).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => Poll::Ready(Some(Ok((this.f)(chunk)))),
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(status(e)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Writes a stream of request messages into a large object, returning its
+/// `Oid`.
+///
+/// `f` extracts the chunk of data from each message. The sink is closed,
+/// committing its transaction, once the request stream ends. If the stream
+/// or the write fails, the sink is dropped and nothing is committed.
+pub fn write_stream<S, T, F>(
+    sink: LargeObjectSink,
+    requests: S,
+    mut f: F,
+) -> impl Future<Output = Result<Oid, Status>>
+where
+    S: Stream<Item = Result<T, Status>>,
+    F: FnMut(T) -> Bytes,
+{
+    let oid = sink.oid();
+    requests
+        .map(move |request| request.map(|r| f(r)))
+        .forward(sink.sink_map_err(status))
+        .map(move |r| r.map(|()| oid))
+}
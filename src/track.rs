@@ -0,0 +1,49 @@
+//! Change tracking for large objects.
+//!
+//! Postgres does not record when a large object was last modified, so this
+//! module maintains a small side table, `large_object_changes`, which is
+//! updated whenever a `LargeObject` with change tracking enabled is written
+//! to or truncated. The table is consulted by `export::export_incremental`.
+//! Recording changes requires Postgres 9.5 or newer.
+use postgres::{GenericConnection, Result};
+use postgres::types::Oid;
+use std::time::SystemTime;
+
+// a single upsert can't race with another writer recording the same object
+pub(crate) const RECORD_CHANGE: &str =
+    "INSERT INTO large_object_changes (oid, modified) VALUES ($1, pg_catalog.now())
+     ON CONFLICT (oid) DO UPDATE SET modified = EXCLUDED.modified";
+
+/// Creates the change tracking table if it does not already exist.
+pub fn install<C: GenericConnection>(conn: &C) -> Result<()> {
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS large_object_changes (
+             oid OID PRIMARY KEY,
+             modified TIMESTAMP WITH TIME ZONE NOT NULL
+         )",
+    )
+}
+
+/// Records that the large object with the specified `Oid` has been modified
+/// in the current transaction.
+pub fn record_change<C: GenericConnection>(conn: &C, oid: Oid) -> Result<()> {
+    let stmt = conn.prepare_cached(RECORD_CHANGE)?;
+    stmt.execute(&[&oid]).map(|_| ())
+}
+
+/// Returns the `Oid`s of all existing large objects recorded as modified at
+/// or after `since`, in ascending order.
+///
+/// Modification times are the start times of the modifying transactions, so
+/// `since` should be taken from the start of the previous export rather than
+/// its end.
+pub fn changed_since<C: GenericConnection>(conn: &C, since: SystemTime) -> Result<Vec<Oid>> {
+    let stmt = conn.prepare_cached(
+        "SELECT c.oid FROM large_object_changes c
+         JOIN pg_catalog.pg_largeobject_metadata m ON m.oid = c.oid
+         WHERE c.modified >= $1
+         ORDER BY c.oid",
+    )?;
+    let rows = stmt.query(&[&since])?;
+    Ok(rows.iter().map(|r| r.get(0)).collect())
+}
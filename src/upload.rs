@@ -0,0 +1,420 @@
+//! Uploads of request bodies into large objects.
+//!
+//! An `Upload` streams either a raw request body or a single field of a
+//! `multipart/form-data` body into a new large object, enforcing a size
+//! limit and reporting progress as it goes. The object is created inside of
+//! a savepoint, so nothing is left behind if the upload fails, even if the
+//! enclosing transaction is committed.
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use {LargeObject, LargeObjectExt, LargeObjectTransactionExt, Mode};
+
+const READ_SIZE: usize = 64 * 1024;
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// Information about an uploaded object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadedObject {
+    /// The `Oid` of the new object.
+    pub oid: Oid,
+    /// The number of bytes written to the object.
+    pub size: u64,
+    /// The file name provided by the client, for multipart uploads.
+    pub file_name: Option<String>,
+    /// The content type provided by the client, for multipart uploads.
+    pub content_type: Option<String>,
+}
+
+/// A builder for uploads into new large objects.
+#[derive(Debug, Clone, Default)]
+pub struct Upload {
+    max_size: Option<u64>,
+}
+
+impl Upload {
+    /// Creates a new upload with no size limit.
+    pub fn new() -> Upload {
+        Upload::default()
+    }
+
+    /// Sets the maximum number of bytes which will be accepted.
+    ///
+    /// Uploads exceeding the limit fail with an error of kind
+    /// `io::ErrorKind::InvalidData`.
+    pub fn max_size(&mut self, max_size: u64) -> &mut Upload {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Streams a raw body into a new large object.
+    ///
+    /// `progress` is called with the total number of bytes written so far
+    /// after each write.
+    pub fn from_reader<R, F>(&self, trans: &Transaction, mut body: R, progress: F) -> Result<UploadedObject>
+    where
+        R: Read,
+        F: FnMut(u64),
+    {
+        self.upload(trans, progress, |sink| {
+            let mut buf = vec![0; READ_SIZE];
+            loop {
+                let n = match body.read(&mut buf) {
+                    Ok(0) => return Ok((None, None)),
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                sink.write(&buf[..n])?;
+            }
+        })
+    }
+
+    /// Streams the contents of the field named `field` of a
+    /// `multipart/form-data` body into a new large object.
+    ///
+    /// `content_type` is the value of the request's `Content-Type` header,
+    /// from which the multipart boundary is taken. Other fields are skipped.
+    /// `progress` is called with the total number of bytes written so far
+    /// after each write.
+    pub fn from_multipart<R, F>(
+        &self,
+        trans: &Transaction,
+        body: R,
+        content_type: &str,
+        field: &str,
+        progress: F,
+    ) -> Result<UploadedObject>
+    where
+        R: Read,
+        F: FnMut(u64),
+    {
+        let boundary = match boundary(content_type) {
+            Some(boundary) => boundary,
+            None => return Err(invalid_data("missing multipart boundary").into()),
+        };
+        let mut body = Multipart::new(body, &boundary);
+
+        self.upload(trans, progress, |sink| {
+            while let Some(headers) = body.next_part()? {
+                if headers.name.as_ref().map(|s| &**s) == Some(field) {
+                    body.read_part(|buf| sink.write(buf))?;
+                    return Ok((headers.file_name, headers.content_type));
+                }
+                body.read_part(|_| Ok(()))?;
+            }
+            Err(invalid_data(&format!("multipart body has no field named `{}`", field)))
+        })
+    }
+
+    fn upload<F, G>(&self, trans: &Transaction, progress: F, body: G) -> Result<UploadedObject>
+    where
+        F: FnMut(u64),
+        G: FnOnce(&mut Sink<F>) -> io::Result<(Option<String>, Option<String>)>,
+    {
+        let nested = trans.transaction()?;
+        let oid = nested.create_large_object()?;
+        let (size, (file_name, content_type)) = {
+            let mut sink = Sink {
+                lo: nested.open_large_object(oid, Mode::Write)?,
+                size: 0,
+                max_size: self.max_size,
+                progress: progress,
+            };
+            let info = body(&mut sink)?;
+            let size = sink.size;
+            sink.lo.finish()?;
+            (size, info)
+        };
+        nested.commit()?;
+
+        Ok(UploadedObject {
+            oid: oid,
+            size: size,
+            file_name: file_name,
+            content_type: content_type,
+        })
+    }
+}
+
+struct Sink<'a, F> {
+    lo: LargeObject<'a>,
+    size: u64,
+    max_size: Option<u64>,
+    progress: F,
+}
+
+impl<'a, F> Sink<'a, F>
+where
+    F: FnMut(u64),
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let size = self.size + buf.len() as u64;
+        if let Some(max_size) = self.max_size {
+            if size > max_size {
+                return Err(invalid_data(&format!(
+                    "upload exceeds the maximum size of {} bytes",
+                    max_size
+                )));
+            }
+        }
+
+        self.lo.write_all(buf)?;
+        self.size = size;
+        (self.progress)(size);
+        Ok(())
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Returns the value of a parameter of a header such as `Content-Type` or
+/// `Content-Disposition`, with surrounding quotes removed.
+fn parameter(header: &str, name: &str) -> Option<String> {
+    header.split(';').skip(1).filter_map(|param| {
+        let idx = param.find('=')?;
+        if !param[..idx].trim().eq_ignore_ascii_case(name) {
+            return None;
+        }
+        let value = param[idx + 1..].trim();
+        if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            Some(value[1..value.len() - 1].to_owned())
+        } else {
+            Some(value.to_owned())
+        }
+    }).next()
+}
+
+fn boundary(content_type: &str) -> Option<String> {
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    parameter(content_type, "boundary").and_then(|b| if b.is_empty() { None } else { Some(b) })
+}
+
+struct PartHeaders {
+    name: Option<String>,
+    file_name: Option<String>,
+    content_type: Option<String>,
+}
+
+struct Multipart<R> {
+    inner: R,
+    buf: Vec<u8>,
+    delimiter: Vec<u8>,
+    started: bool,
+    eof: bool,
+}
+
+impl<R> Multipart<R>
+where
+    R: Read,
+{
+    fn new(inner: R, boundary: &str) -> Multipart<R> {
+        Multipart {
+            inner: inner,
+            // the first boundary isn't preceded by a line break, so add one
+            buf: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            started: false,
+            eof: false,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+
+        let len = self.buf.len();
+        self.buf.resize(len + READ_SIZE, 0);
+        loop {
+            match self.inner.read(&mut self.buf[len..]) {
+                Ok(n) => {
+                    self.buf.truncate(len + n);
+                    self.eof = n == 0;
+                    return Ok(n != 0);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn fill_to(&mut self, len: usize) -> io::Result<()> {
+        while self.buf.len() < len {
+            if !self.fill()? {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "unexpected end of multipart body",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes data to `f` until the next delimiter, and consumes it.
+    fn read_part<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        loop {
+            if let Some(idx) = find(&self.buf, &self.delimiter) {
+                f(&self.buf[..idx])?;
+                self.buf.drain(..idx + self.delimiter.len());
+                return Ok(());
+            }
+
+            // hold back enough bytes to match a delimiter split across reads
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                let end = self.buf.len() - keep;
+                f(&self.buf[..end])?;
+                self.buf.drain(..end);
+            }
+
+            let len = self.buf.len() + 1;
+            self.fill_to(len)?;
+        }
+    }
+
+    /// Advances to the next part, returning its headers, or `None` after the
+    /// final delimiter.
+    ///
+    /// The part's data must be consumed with `read_part` before advancing
+    /// again.
+    fn next_part(&mut self) -> io::Result<Option<PartHeaders>> {
+        if !self.started {
+            // discard the preamble
+            self.read_part(|_| Ok(()))?;
+            self.started = true;
+        }
+
+        self.fill_to(2)?;
+        if self.buf.starts_with(b"--") {
+            return Ok(None);
+        }
+
+        // the line break ending the delimiter is also the start of the
+        // sequence ending the headers if there are none
+        let end = loop {
+            if let Some(idx) = find(&self.buf, b"\r\n\r\n") {
+                break idx;
+            }
+            if self.buf.len() > MAX_HEADER_SIZE {
+                return Err(invalid_data("multipart part headers are too large"));
+            }
+            let len = self.buf.len() + 1;
+            self.fill_to(len)?;
+        };
+
+        let mut headers = PartHeaders {
+            name: None,
+            file_name: None,
+            content_type: None,
+        };
+        {
+            let raw = String::from_utf8_lossy(&self.buf[cmp::min(2, end)..end]);
+            for line in raw.split("\r\n") {
+                let idx = match line.find(':') {
+                    Some(idx) => idx,
+                    None => continue,
+                };
+                let (name, value) = (line[..idx].trim(), line[idx + 1..].trim());
+                if name.eq_ignore_ascii_case("content-disposition") {
+                    headers.name = parameter(value, "name");
+                    headers.file_name = parameter(value, "filename");
+                } else if name.eq_ignore_ascii_case("content-type") {
+                    headers.content_type = Some(value.to_owned());
+                }
+            }
+        }
+        self.buf.drain(..end + 4);
+        Ok(Some(headers))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::Read;
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use upload::Upload;
+
+    #[test]
+    fn test_from_reader() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let before = trans.list_large_objects().unwrap();
+
+        let mut progress = vec![];
+        let upload = Upload::new()
+            .max_size(14)
+            .from_reader(&trans, &b"hello world!!!"[..], |n| progress.push(n))
+            .unwrap();
+        assert_eq!(upload.size, 14);
+        assert_eq!(progress.last(), Some(&14));
+
+        let mut buf = vec![];
+        let mut lo = trans.open_large_object(upload.oid, Mode::Read).unwrap();
+        lo.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello world!!!");
+        trans.delete_large_object(upload.oid).unwrap();
+
+        let result = Upload::new()
+            .max_size(13)
+            .from_reader(&trans, &b"hello world!!!"[..], |_| {});
+        assert!(result.is_err());
+        assert_eq!(trans.list_large_objects().unwrap(), before);
+    }
+
+    #[test]
+    fn test_from_multipart() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+
+        let body = b"preamble\r\n\
+                     --XyZ\r\n\
+                     Content-Disposition: form-data; name=\"title\"\r\n\
+                     \r\n\
+                     greeting\r\n\
+                     --XyZ\r\n\
+                     Content-Disposition: form-data; name=\"file\"; filename=\"hello.txt\"\r\n\
+                     Content-Type: text/plain\r\n\
+                     \r\n\
+                     hello\r\nworld!!!\r\n\
+                     --XyZ--\r\n";
+        let upload = Upload::new()
+            .from_multipart(&trans, &body[..], "multipart/form-data; boundary=XyZ", "file", |_| {})
+            .unwrap();
+        assert_eq!(upload.file_name, Some("hello.txt".to_owned()));
+        assert_eq!(upload.content_type, Some("text/plain".to_owned()));
+
+        let mut buf = vec![];
+        let mut lo = trans.open_large_object(upload.oid, Mode::Read).unwrap();
+        lo.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello\r\nworld!!!");
+
+        let result = Upload::new()
+            .from_multipart(&trans, &body[..], "multipart/form-data; boundary=XyZ", "missing", |_| {});
+        assert!(result.is_err());
+    }
+}
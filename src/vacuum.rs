@@ -0,0 +1,107 @@
+//! Removal of orphaned large objects.
+//!
+//! Like the `vacuumlo` utility, a large object is considered orphaned if its
+//! `Oid` does not appear in any `oid` or `lo` column of any table in the
+//! database. The change tracking table maintained by the `track` module is
+//! not considered to reference objects.
+use postgres::{GenericConnection, Result};
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+
+use {quote_identifier, LargeObjectExt, LargeObjectTransactionExt, Mode};
+
+/// A large object found to be orphaned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Orphan {
+    /// The `Oid` of the object.
+    pub oid: Oid,
+    /// The size of the object in bytes.
+    pub size: u64,
+}
+
+/// Returns the orphaned large objects in the database, in ascending order
+/// of `Oid`.
+pub fn find_orphans(trans: &Transaction) -> Result<Vec<Orphan>> {
+    let columns = reference_columns(trans)?;
+
+    let mut query = "SELECT m.oid FROM pg_catalog.pg_largeobject_metadata m".to_owned();
+    for (i, &(ref schema, ref table, ref column)) in columns.iter().enumerate() {
+        query.push_str(if i == 0 { " WHERE " } else { " AND " });
+        query.push_str(&format!(
+            "NOT EXISTS (SELECT 1 FROM {}.{} WHERE {}::pg_catalog.oid = m.oid)",
+            quote_identifier(schema),
+            quote_identifier(table),
+            quote_identifier(column)
+        ));
+    }
+    query.push_str(" ORDER BY m.oid");
+
+    let rows = trans.query(&query, &[])?;
+    let mut orphans = vec![];
+    for row in &rows {
+        let oid = row.get(0);
+        let size = trans.open_large_object(oid, Mode::Read)?.size()?;
+        orphans.push(Orphan {
+            oid: oid,
+            size: size,
+        });
+    }
+    Ok(orphans)
+}
+
+/// Deletes the orphaned large objects in the database, returning them.
+pub fn delete_orphans(trans: &Transaction) -> Result<Vec<Orphan>> {
+    let orphans = find_orphans(trans)?;
+    for orphan in &orphans {
+        trans.delete_large_object(orphan.oid)?;
+    }
+    Ok(orphans)
+}
+
+fn reference_columns<C: GenericConnection>(conn: &C) -> Result<Vec<(String, String, String)>> {
+    let rows = conn.query(
+        "SELECT n.nspname, c.relname, a.attname
+         FROM pg_catalog.pg_class c
+         JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+         JOIN pg_catalog.pg_attribute a ON a.attrelid = c.oid
+         JOIN pg_catalog.pg_type t ON t.oid = a.atttypid
+         WHERE c.relkind IN ('r', 'm')
+             AND a.attnum > 0
+             AND NOT a.attisdropped
+             AND t.typname IN ('oid', 'lo')
+             AND n.nspname NOT IN ('pg_catalog', 'information_schema')
+             AND n.nspname NOT LIKE 'pg\\_toast%'
+             AND (n.nspname NOT LIKE 'pg\\_temp\\_%' OR n.oid = pg_catalog.pg_my_temp_schema())
+             AND NOT (c.relname = 'large_object_changes' AND a.attname = 'oid')",
+        &[],
+    )?;
+    Ok(rows.iter().map(|r| (r.get(0), r.get(1), r.get(2))).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+
+    use LargeObjectExt;
+    use vacuum::{delete_orphans, find_orphans};
+
+    #[test]
+    fn test_orphans() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        trans.batch_execute("CREATE TEMPORARY TABLE vacuum_test (data OID)").unwrap();
+
+        let referenced = trans.create_large_object().unwrap();
+        let orphaned = trans.create_large_object().unwrap();
+        trans.execute("INSERT INTO vacuum_test VALUES ($1)", &[&referenced]).unwrap();
+
+        let orphans = find_orphans(&trans).unwrap().into_iter().map(|o| o.oid).collect::<Vec<_>>();
+        assert!(orphans.contains(&orphaned));
+        assert!(!orphans.contains(&referenced));
+
+        delete_orphans(&trans).unwrap();
+        let oids = trans.list_large_objects().unwrap();
+        assert!(!oids.contains(&orphaned));
+        assert!(oids.contains(&referenced));
+    }
+}
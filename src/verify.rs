@@ -0,0 +1,233 @@
+//! End-to-end verification of transfers.
+//!
+//! `server_digest` has the server hash an object in place, and
+//! `verify_transfer` compares that digest with one computed by the client
+//! while it uploaded or downloaded the data, typically by wrapping the
+//! transfer in a `checksum::ChecksumStream` with a `ChunkedDigest`.
+//!
+//! Postgres can't hash an object incrementally, so the digest is computed in
+//! two levels: each `copy::CHUNK_SIZE` chunk of the object is hashed, and the
+//! digest is the hash of the concatenated chunk hashes. It therefore differs
+//! from a plain hash of the object's contents.
+//!
+//! MD5 is supported with the `with-md5` feature, and SHA-256 with the
+//! `with-sha2` feature. Requires Postgres 9.4 or newer, or 11 or newer for
+//! SHA-256.
+#[cfg(feature = "with-md5")]
+use md5;
+use postgres::Result;
+use postgres::transaction::Transaction;
+use postgres::types::Oid;
+#[cfg(feature = "with-sha2")]
+use sha2::{self, Digest};
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::mem;
+
+use {first_column, LargeObjectTransactionExt, Mode};
+use checksum::Checksum;
+use copy::CHUNK_SIZE;
+
+/// A hash algorithm used to compute digests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// MD5.
+    #[cfg(feature = "with-md5")]
+    Md5,
+    /// SHA-256.
+    #[cfg(feature = "with-sha2")]
+    Sha256,
+}
+
+enum Hasher {
+    #[cfg(feature = "with-md5")]
+    Md5(md5::Context),
+    #[cfg(feature = "with-sha2")]
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Hasher {
+        match algorithm {
+            #[cfg(feature = "with-md5")]
+            Algorithm::Md5 => Hasher::Md5(md5::Context::new()),
+            #[cfg(feature = "with-sha2")]
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match *self {
+            #[cfg(feature = "with-md5")]
+            Hasher::Md5(ref mut h) => h.consume(data),
+            #[cfg(feature = "with-sha2")]
+            Hasher::Sha256(ref mut h) => Digest::update(h, data),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            #[cfg(feature = "with-md5")]
+            Hasher::Md5(h) => h.compute().0.to_vec(),
+            #[cfg(feature = "with-sha2")]
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+/// A `Checksum` computing the same digest as `server_digest`, returned as a
+/// lowercase hex string.
+pub struct ChunkedDigest {
+    algorithm: Algorithm,
+    chunk: Hasher,
+    // the number of bytes hashed into `chunk`
+    chunk_len: usize,
+    digest: Hasher,
+}
+
+impl fmt::Debug for ChunkedDigest {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ChunkedDigest")
+            .field("algorithm", &self.algorithm)
+            .field("chunk_len", &self.chunk_len)
+            .finish()
+    }
+}
+
+impl ChunkedDigest {
+    /// Creates a digest using the specified algorithm.
+    pub fn new(algorithm: Algorithm) -> ChunkedDigest {
+        ChunkedDigest {
+            algorithm: algorithm,
+            chunk: Hasher::new(algorithm),
+            chunk_len: 0,
+            digest: Hasher::new(algorithm),
+        }
+    }
+
+    fn finish_chunk(&mut self) {
+        let chunk = mem::replace(&mut self.chunk, Hasher::new(self.algorithm));
+        self.digest.update(&chunk.finish());
+        self.chunk_len = 0;
+    }
+}
+
+impl Checksum for ChunkedDigest {
+    type Output = String;
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = cmp::min(data.len(), CHUNK_SIZE - self.chunk_len);
+            self.chunk.update(&data[..n]);
+            self.chunk_len += n;
+            data = &data[n..];
+            if self.chunk_len == CHUNK_SIZE {
+                self.finish_chunk();
+            }
+        }
+    }
+
+    fn finish(mut self) -> String {
+        if self.chunk_len > 0 {
+            self.finish_chunk();
+        }
+        self.digest
+            .finish()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Computes the digest of the object with the specified `Oid` server side,
+/// returning it as a lowercase hex string.
+pub fn server_digest(trans: &Transaction, oid: Oid, algorithm: Algorithm) -> Result<String> {
+    let size = trans.open_large_object(oid, Mode::Read)?.size()? as i64;
+    let query = match algorithm {
+        #[cfg(feature = "with-md5")]
+        Algorithm::Md5 => {
+            "SELECT pg_catalog.md5(COALESCE(pg_catalog.string_agg(
+                 pg_catalog.decode(pg_catalog.md5(pg_catalog.lo_get($1, off, $3)), 'hex'),
+                 ''::BYTEA ORDER BY off), ''::BYTEA))
+             FROM pg_catalog.generate_series(0, $2::INT8 - 1, $4::INT8) off"
+        }
+        #[cfg(feature = "with-sha2")]
+        Algorithm::Sha256 => {
+            "SELECT pg_catalog.encode(pg_catalog.sha256(COALESCE(pg_catalog.string_agg(
+                 pg_catalog.sha256(pg_catalog.lo_get($1, off, $3)),
+                 ''::BYTEA ORDER BY off), ''::BYTEA)), 'hex')
+             FROM pg_catalog.generate_series(0, $2::INT8 - 1, $4::INT8) off"
+        }
+    };
+    let stmt = trans.prepare_cached(query)?;
+    let len = CHUNK_SIZE as i32;
+    let rows = stmt.query(&[&oid, &size, &len, &(len as i64)])?;
+    first_column(&rows)
+}
+
+/// Checks that the object with the specified `Oid` has the digest computed
+/// by the client, as returned by a `ChunkedDigest`.
+///
+/// An error with the kind `InvalidData` is returned if the digests differ.
+pub fn verify_transfer(
+    trans: &Transaction,
+    oid: Oid,
+    algorithm: Algorithm,
+    digest: &str,
+) -> Result<()> {
+    let expected = server_digest(trans, oid, algorithm)?;
+    if expected.eq_ignore_ascii_case(digest) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "large object {} has digest {} but {} was transferred",
+                oid, expected, digest
+            ),
+        ).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{self, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+    use checksum::ChecksumStream;
+    use verify::{server_digest, verify_transfer, Algorithm, ChunkedDigest};
+
+    fn check(algorithm: Algorithm) {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+
+        let empty = ChecksumStream::new(io::sink(), ChunkedDigest::new(algorithm)).finish().1;
+        assert_eq!(server_digest(&trans, oid, algorithm).unwrap(), empty);
+
+        let data = (0..600_000).map(|i| i as u8).collect::<Vec<_>>();
+        let lo = trans.open_large_object(oid, Mode::Write).unwrap();
+        let mut writer = ChecksumStream::new(lo, ChunkedDigest::new(algorithm));
+        writer.write_all(&data).unwrap();
+        let (lo, digest) = writer.finish();
+        lo.finish().unwrap();
+
+        verify_transfer(&trans, oid, algorithm, &digest).unwrap();
+        let e = verify_transfer(&trans, oid, algorithm, &empty).unwrap_err();
+        assert_eq!(e.as_io().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[cfg(feature = "with-md5")]
+    fn test_md5() {
+        check(Algorithm::Md5);
+    }
+
+    #[test]
+    #[cfg(feature = "with-sha2")]
+    fn test_sha256() {
+        check(Algorithm::Sha256);
+    }
+}
@@ -0,0 +1,110 @@
+//! Filters and replies for warp.
+//!
+//! Requires the `with-warp` feature.
+use bytes::Buf;
+use futures::{FutureExt, Stream, StreamExt, TryFutureExt};
+use postgres::{GenericConnection, Result};
+use postgres::types::Oid;
+use std::future::Future;
+use std::io;
+use warp::http::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use warp::hyper::Body;
+use warp::reject::{self, Reject, Rejection};
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use stream::{LargeObjectSink, LargeObjectStream, DEFAULT_CHUNK_SIZE};
+
+/// The rejection produced when a request body cannot be written to a large
+/// object.
+#[derive(Debug)]
+pub struct UploadRejection(pub io::Error);
+
+impl Reject for UploadRejection {}
+
+fn rejection<E>(e: E) -> Rejection
+where
+    E: Into<io::Error>,
+{
+    reject::custom(UploadRejection(e.into()))
+}
+
+/// Returns a filter which streams the request body into a new large object,
+/// extracting its `Oid`.
+///
+/// `connect` is called on a blocking thread for each request to obtain a
+/// connection, which is held for the duration of the upload. The object is
+/// only created if the entire body is written successfully. Failures are
+/// reported as `UploadRejection`s.
+pub fn upload<F, C>(connect: F) -> impl Filter<Extract = (Oid,), Error = Rejection> + Clone
+where
+    F: Fn() -> Result<C> + Clone + Send + Sync + 'static,
+    C: GenericConnection + Send + 'static,
+{
+    warp::body::stream().and_then(move |body| write_body(connect.clone(), body))
+}
+
+fn write_body<F, C, S, B>(connect: F, body: S) -> impl Future<Output = ::std::result::Result<Oid, Rejection>>
+where
+    F: FnOnce() -> Result<C> + Send + 'static,
+    C: GenericConnection + Send + 'static,
+    S: Stream<Item = ::std::result::Result<B, warp::Error>> + Send + 'static,
+    B: Buf,
+{
+    tokio::task::spawn_blocking(move || connect().and_then(LargeObjectSink::create))
+        .map(|sink| match sink {
+            Ok(Ok(sink)) => Ok(sink),
+            Ok(Err(e)) => Err(rejection(e)),
+            Err(e) => Err(rejection(e)),
+        })
+        .and_then(|sink| {
+            let oid = sink.oid();
+            body.map(|chunk| {
+                chunk
+                    .map(|mut buf| buf.copy_to_bytes(buf.remaining()))
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            }).forward(sink)
+                .map(move |r| r.map(|()| oid).map_err(rejection))
+        })
+}
+
+/// A warp `Reply` streaming the contents of a large object.
+///
+/// The response has a `Content-Length` of the object's size and a
+/// `Content-Type` of `application/octet-stream`.
+#[derive(Debug)]
+pub struct LargeObjectReply {
+    stream: LargeObjectStream,
+}
+
+impl LargeObjectReply {
+    /// Opens the large object with the specified `Oid` for a reply.
+    ///
+    /// See `LargeObjectStream::new` for details. This blocks while the object
+    /// is opened, so it should be called from a blocking context.
+    pub fn new<C>(conn: C, oid: Oid) -> Result<LargeObjectReply>
+    where
+        C: GenericConnection + Send + 'static,
+    {
+        let stream = LargeObjectStream::new(conn, oid, DEFAULT_CHUNK_SIZE)?;
+        Ok(LargeObjectReply::from_stream(stream))
+    }
+
+    /// Creates a reply from an existing stream.
+    pub fn from_stream(stream: LargeObjectStream) -> LargeObjectReply {
+        LargeObjectReply { stream: stream }
+    }
+}
+
+impl Reply for LargeObjectReply {
+    fn into_response(self) -> Response {
+        let size = self.stream.remaining();
+        let mut response = Response::new(Body::wrap_stream(self.stream));
+        {
+            let headers = response.headers_mut();
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+        }
+        response
+    }
+}
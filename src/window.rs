@@ -0,0 +1,130 @@
+//! Views of part of a large object.
+//!
+//! `LargeObject::range` returns a `Window` confined to a range of bytes of an
+//! object, such as a single attachment stored within a larger archive. Code
+//! handed a window can read and seek within it, but can't reach the rest of
+//! the object.
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use {seek_position, LargeObject};
+
+/// A view of a range of bytes of a large object.
+///
+/// Positions are relative to the start of the range, and reads stop at its
+/// end. Seeking relative to the end seeks relative to the end of the range,
+/// even if the object itself ends earlier.
+#[derive(Debug)]
+pub struct Window<'b, 'a: 'b> {
+    lo: &'b mut LargeObject<'a>,
+    offset: u64,
+    len: u64,
+    pos: u64,
+    // whether the handle's position corresponds to `pos`
+    positioned: bool,
+}
+
+impl<'b, 'a: 'b> Window<'b, 'a> {
+    pub(crate) fn new(lo: &'b mut LargeObject<'a>, offset: u64, len: u64) -> Window<'b, 'a> {
+        Window {
+            lo: lo,
+            offset: offset,
+            len: len,
+            pos: 0,
+            positioned: false,
+        }
+    }
+
+    /// Returns the offset of the start of the range within the object.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the length of the range.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Determines if the range is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<'b, 'a: 'b> Read for Window<'b, 'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+
+        if !self.positioned {
+            self.lo.seek(SeekFrom::Start(self.offset + self.pos))?;
+            self.positioned = true;
+        }
+        let len = cmp::min(buf.len() as u64, self.len - self.pos) as usize;
+        let n = self.lo.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'b, 'a: 'b> Seek for Window<'b, 'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => (pos, 0),
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (self.len, offset),
+        };
+
+        let pos = seek_position(base, offset)?;
+        if pos != self.pos {
+            self.pos = pos;
+            self.positioned = false;
+        }
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::{Connection, TlsMode};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use {LargeObjectExt, LargeObjectTransactionExt, Mode};
+
+    #[test]
+    fn test_window() {
+        let conn = Connection::connect("postgres://postgres@localhost", TlsMode::None).unwrap();
+        let trans = conn.transaction().unwrap();
+        let oid = trans.create_large_object().unwrap();
+        let mut lo = trans.open_large_object(oid, Mode::ReadWrite).unwrap();
+        lo.write_all(b"hello world!!!").unwrap();
+
+        {
+            let mut window = lo.range(6, 5);
+            assert_eq!(window.len(), 5);
+            let mut out = String::new();
+            window.read_to_string(&mut out).unwrap();
+            assert_eq!(out, "world");
+
+            assert_eq!(window.seek(SeekFrom::End(-2)).unwrap(), 3);
+            out.clear();
+            window.read_to_string(&mut out).unwrap();
+            assert_eq!(out, "ld");
+            assert!(window.seek(SeekFrom::Current(-6)).is_err());
+
+            window.seek(SeekFrom::Start(10)).unwrap();
+            out.clear();
+            window.read_to_string(&mut out).unwrap();
+            assert!(out.is_empty());
+
+            window.seek(SeekFrom::Start(u64::max_value())).unwrap();
+            assert!(window.seek(SeekFrom::Current(1)).is_err());
+        }
+
+        let mut out = String::new();
+        lo.range(11, 10).read_to_string(&mut out).unwrap();
+        assert_eq!(out, "!!!");
+        lo.finish().unwrap();
+    }
+}